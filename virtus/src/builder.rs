@@ -1,16 +1,59 @@
+use crate::config::{Config, PoolConfig, TlsConfig};
+use crate::hypervisor::Hypervisor;
+use crate::qemu_img::{QemuImg, QemuImgRunner};
+use crate::reconcile::Reconciler;
+use crate::virtus::{Tls, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_PORT};
 use crate::{error::Error, virtus::Virtus};
-use std::net::Ipv4Addr;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct Builder {
     id: Uuid,
-    bind_address: Ipv4Addr,
+    bind_address: IpAddr,
+    port: u16,
+
+    // Where the other nodes reach us, if not on the bind address and port, e.g. behind NAT or
+    // on several address families
+    advertise_addresses: Vec<IpAddr>,
+    advertise_port: Option<u16>,
+
     data_dir: String,
 
     // If empty, we are the leader of a new cluster
     // Otherwise, we are a follower in an existing cluster
-    peers: Vec<Ipv4Addr>,
+    peers: Vec<IpAddr>,
+
+    // If unset, connect to the local libvirt daemon
+    hypervisor: Option<Arc<dyn Hypervisor>>,
+
+    // If unset, run the qemu-img binary
+    qemu_img: Option<Arc<dyn QemuImgRunner>>,
+
+    // How far the virtual size of a pool's disks may exceed the pool's capacity
+    overcommit: f64,
+
+    // How often to compare the local pools against the cluster state, never if unset
+    reconcile_interval: Option<Duration>,
+
+    // Adopt orphan images and mark disks with missing images as broken
+    adopt_drift: bool,
+
+    // How often to tell the cluster this node is alive
+    heartbeat_interval: Duration,
+
+    // Where /proc, /sys and /dev are found for the host inventory
+    host_root: PathBuf,
+
+    // Pools to create on this node at startup
+    default_pools: Vec<PoolConfig>,
+
+    // If unset, gRPC is served and spoken in plain text
+    tls: Option<TlsConfig>,
 }
 
 impl Default for Builder {
@@ -23,9 +66,21 @@ impl Builder {
     pub fn new() -> Self {
         Self {
             id: Uuid::new_v4(),
-            bind_address: Ipv4Addr::new(127, 0, 0, 1),
+            bind_address: Ipv4Addr::new(127, 0, 0, 1).into(),
+            port: DEFAULT_PORT,
+            advertise_addresses: vec![],
+            advertise_port: None,
             data_dir: "/tmp/virtus".to_string(),
             peers: vec![],
+            hypervisor: None,
+            qemu_img: None,
+            overcommit: 1.0,
+            reconcile_interval: Some(Duration::from_secs(300)),
+            adopt_drift: false,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            host_root: PathBuf::from("/"),
+            default_pools: vec![],
+            tls: None,
         }
     }
 
@@ -33,28 +88,225 @@ impl Builder {
         self.id
     }
 
+    pub fn set_id(mut self, id: Uuid) -> Self {
+        self.id = id;
+        self
+    }
+
     pub fn set_dir(mut self, dir: &str) -> Self {
         self.data_dir = dir.to_string();
         self
     }
 
-    pub fn bind(mut self, address: Ipv4Addr) -> Self {
+    pub fn bind(mut self, address: IpAddr) -> Self {
         self.bind_address = address;
         self
     }
 
-    pub fn join_cluster(mut self, peers: Vec<Ipv4Addr>) -> Self {
+    /// Port the gRPC server listens on. Defaults to 9400.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Addresses the other nodes connect to, tried in order, if it isn't the bind address.
+    /// Required when binding to 0.0.0.0 or ::. One of them has to be IPv4 for skiff.
+    pub fn advertise_addresses(mut self, addresses: Vec<IpAddr>) -> Self {
+        self.advertise_addresses = addresses;
+        self
+    }
+
+    /// Port the other nodes connect to, if it isn't the one we listen on
+    pub fn advertise_port(mut self, port: u16) -> Self {
+        self.advertise_port = Some(port);
+        self
+    }
+
+    pub fn join_cluster(mut self, peers: Vec<IpAddr>) -> Self {
         self.peers = peers;
         self
     }
 
-    pub fn from_config(id: u32, dir: Option<&str>) -> Self {
-        let data_dir = dir.unwrap_or("/tmp/virtus");
+    pub fn hypervisor(mut self, hypervisor: Arc<dyn Hypervisor>) -> Self {
+        self.hypervisor = Some(hypervisor);
+        self
+    }
+
+    pub fn qemu_img(mut self, runner: Arc<dyn QemuImgRunner>) -> Self {
+        self.qemu_img = Some(runner);
+        self
+    }
 
-        todo!()
+    /// Allow disks in a pool to add up to `ratio` times the pool's capacity. Defaults to 1.0,
+    /// i.e. no overcommit.
+    pub fn overcommit(mut self, ratio: f64) -> Self {
+        self.overcommit = ratio;
+        self
+    }
+
+    /// Scan the local pools for drift every `interval`, or only through `GetDrift` if unset.
+    /// Defaults to every 5 minutes.
+    pub fn reconcile_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reconcile_interval = interval;
+        self
+    }
+
+    /// Fix drift instead of only reporting it: orphan images are adopted as disks, and disks
+    /// with missing or mismatched images are marked as broken
+    pub fn adopt_drift(mut self, adopt: bool) -> Self {
+        self.adopt_drift = adopt;
+        self
+    }
+
+    /// Publish a heartbeat every `interval`. Nodes that miss three in a row are reported as
+    /// not ready. Defaults to 10 seconds.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Read the host inventory from `/proc`, `/sys` and `/dev` below `root` instead of `/`,
+    /// e.g. from a fixture directory in tests
+    pub fn host_root(mut self, root: &str) -> Self {
+        self.host_root = PathBuf::from(root);
+        self
+    }
+
+    /// Create `pool` on this node at startup, unless the node already has a pool at that path
+    pub fn default_pool(mut self, pool: PoolConfig) -> Self {
+        self.default_pools.push(pool);
+        self
+    }
+
+    /// Serve gRPC over TLS with the certificate in `tls`, and connect to the other nodes over
+    /// TLS too. The files are read when building.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets up a builder from a daemon configuration, see `Config::load`. The node id is
+    /// read from (or persisted to) the configured id file.
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        config.validate()?;
+
+        let interval = match config.reconcile_interval {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let mut builder = Self::new()
+            .set_id(config.node_id()?)
+            .set_dir(&config.data_dir)
+            .bind(config.bind)
+            .port(config.port)
+            .advertise_addresses(config.advertise_addresses.clone())
+            .join_cluster(config.peers.clone())
+            .overcommit(config.overcommit)
+            .reconcile_interval(interval)
+            .adopt_drift(config.adopt_drift)
+            .heartbeat_interval(Duration::from_secs(config.heartbeat_interval))
+            .host_root(&config.host_root);
+
+        if let Some(port) = config.advertise_port {
+            builder = builder.advertise_port(port);
+        }
+
+        if let Some(tls) = &config.tls {
+            builder = builder.tls(tls.clone());
+        }
+
+        for pool in &config.pools {
+            builder = builder.default_pool(pool.clone());
+        }
+
+        Ok(builder)
     }
 
     pub fn build(self) -> Result<Virtus, Error> {
-        Virtus::new(self.id, self.bind_address, self.data_dir, self.peers)
+        for pool in &self.default_pools {
+            pool.validate()?;
+        }
+
+        if self.heartbeat_interval.is_zero() {
+            return Err(Error::InvalidConfig(
+                "the heartbeat interval must not be 0".to_string(),
+            ));
+        }
+
+        let addresses = match self.advertise_addresses.is_empty() {
+            true => vec![self.bind_address],
+            false => self.advertise_addresses,
+        };
+        if let Some(address) = addresses.iter().find(|address| address.is_unspecified()) {
+            return Err(Error::InvalidConfig(format!(
+                "an advertise address is required when binding to {}",
+                address
+            )));
+        }
+        let bind = SocketAddr::new(self.bind_address, self.port);
+        let port = self.advertise_port.unwrap_or(self.port);
+
+        let tls = match &self.tls {
+            Some(tls) => {
+                tls.validate()?;
+                Some(load_tls(tls)?)
+            }
+            None => None,
+        };
+
+        let hypervisor = match self.hypervisor {
+            Some(hypervisor) => hypervisor,
+            None => default_hypervisor()?,
+        };
+
+        let qemu_img = match self.qemu_img {
+            Some(runner) => QemuImg::new(runner),
+            None => QemuImg::default(),
+        };
+
+        Virtus::new(
+            self.id,
+            addresses,
+            self.data_dir,
+            self.peers,
+            hypervisor,
+            qemu_img,
+            self.overcommit,
+        )
+        .map(|virtus| {
+            virtus
+                .with_reconciler(Reconciler::new(self.reconcile_interval, self.adopt_drift))
+                .with_listener(bind, port)
+                .with_heartbeat_interval(self.heartbeat_interval)
+                .with_host_root(&self.host_root)
+                .with_default_pools(self.default_pools)
+                .with_tls(tls)
+        })
     }
 }
+
+fn load_tls(tls: &TlsConfig) -> Result<Tls, Error> {
+    let read = |path: &str| {
+        fs::read(path).map_err(|e| Error::InvalidConfig(format!("failed to read {}: {}", path, e)))
+    };
+
+    let ca = match &tls.ca {
+        Some(path) => Some(read(path)?),
+        None => None,
+    };
+
+    Ok(Tls::new(&read(&tls.cert)?, &read(&tls.key)?, ca.as_deref()))
+}
+
+#[cfg(feature = "libvirt")]
+fn default_hypervisor() -> Result<Arc<dyn Hypervisor>, Error> {
+    Ok(Arc::new(crate::hypervisor::Libvirt::connect(
+        "qemu:///system",
+    )?))
+}
+
+#[cfg(not(feature = "libvirt"))]
+fn default_hypervisor() -> Result<Arc<dyn Hypervisor>, Error> {
+    Err(Error::NoHypervisor)
+}
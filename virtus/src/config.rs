@@ -0,0 +1,664 @@
+use crate::error::Error;
+use crate::node;
+use crate::pool::BackendKind;
+use crate::virtus::DEFAULT_PORT;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Where the daemon looks for its configuration unless told otherwise
+pub const DEFAULT_PATH: &str = "/etc/virtus/virtus.toml";
+
+/// Prefix of the environment variables overriding the configuration file, e.g.
+/// `VIRTUS_DATA_DIR`
+const ENV_PREFIX: &str = "VIRTUS_";
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Daemon configuration, read from a TOML file.
+///
+/// Every setting but `tls` and `pools` can be overridden from the environment
+/// (`VIRTUS_PORT=9401`) and the command line (`--port 9401`), see `Config::load`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: String,
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Addresses the other nodes connect to, in order, if not `bind`. Required when binding to
+    /// 0.0.0.0 or ::, and one of them must be IPv4.
+    pub advertise_addresses: Vec<IpAddr>,
+    /// Port the other nodes connect to, if not `port`
+    pub advertise_port: Option<u16>,
+    /// IPv4 addresses of existing cluster members. Empty to start a new cluster.
+    pub peers: Vec<IpAddr>,
+    /// Fixed node id. If unset, the id is generated on first start and kept in `id_file`.
+    pub node_id: Option<Uuid>,
+    /// Defaults to `node_id` in the data directory
+    pub id_file: Option<String>,
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: String,
+    pub overcommit: f64,
+    /// Seconds between drift scans of the local pools, 0 to only scan on request
+    pub reconcile_interval: u64,
+    pub adopt_drift: bool,
+    /// Seconds between heartbeats. Nodes that miss three in a row are reported as not ready.
+    pub heartbeat_interval: u64,
+    /// Where `/proc`, `/sys` and `/dev` are found for the host inventory
+    pub host_root: String,
+    /// Serve gRPC over TLS and use it to connect to the other nodes
+    pub tls: Option<TlsConfig>,
+    /// Pools created on this node at startup if it has no pool at that path yet
+    pub pools: Vec<PoolConfig>,
+}
+
+/// PEM files of this node's certificate, shown to clients and to the other nodes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// CA to verify the other nodes against, which then also have to present a certificate.
+    /// Defaults to the system roots, without client certificates.
+    pub ca: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.cert.is_empty() || self.key.is_empty() {
+            return Err(Error::InvalidConfig(
+                "tls cert and key must not be empty".to_string(),
+            ));
+        }
+
+        match &self.ca {
+            Some(ca) if ca.is_empty() => {
+                Err(Error::InvalidConfig("tls ca must not be empty".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    pub path: String,
+    pub name: Option<String>,
+    /// `directory` (the default) or `shared`
+    #[serde(default = "default_backend")]
+    pub backend: String,
+}
+
+fn default_backend() -> String {
+    "directory".to_string()
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.path.is_empty() {
+            return Err(Error::InvalidConfig(
+                "pool path must not be empty".to_string(),
+            ));
+        }
+
+        match self.backend.as_str() {
+            "directory" | "shared" => Ok(()),
+            backend => Err(Error::InvalidConfig(format!(
+                "pool {} has an unknown backend {}",
+                self.path, backend
+            ))),
+        }
+    }
+
+    pub(crate) fn backend(&self) -> BackendKind {
+        match self.backend.as_str() {
+            "shared" => BackendKind::Shared,
+            _ => BackendKind::Directory,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: "/var/lib/virtus".to_string(),
+            bind: Ipv4Addr::LOCALHOST.into(),
+            port: DEFAULT_PORT,
+            advertise_addresses: vec![],
+            advertise_port: None,
+            peers: vec![],
+            node_id: None,
+            id_file: None,
+            log_level: "info".to_string(),
+            overcommit: 1.0,
+            reconcile_interval: 300,
+            adopt_drift: false,
+            heartbeat_interval: 10,
+            host_root: "/".to_string(),
+            tls: None,
+            pools: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        toml::from_str(contents).map_err(|e| Error::InvalidConfig(e.message().to_string()))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Error::InvalidConfig(format!("failed to read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&contents)
+    }
+
+    /// Loads the daemon configuration from the file given by `--config`, `VIRTUS_CONFIG` or
+    /// `DEFAULT_PATH`, then applies the overrides from `env` and `args`, in that order.
+    ///
+    /// A missing default file is not an error, so virtus can run on defaults alone. Unknown
+    /// settings on the command line are, while unknown `VIRTUS_` variables are ignored, as
+    /// other tools may share the prefix.
+    pub fn load(args: &[String], env: &HashMap<String, String>) -> Result<Self, Error> {
+        let args = parse_args(args)?;
+
+        let path = args
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env.get(&format!("{}CONFIG", ENV_PREFIX)).cloned());
+
+        let mut config = match path {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None => match fs::read_to_string(DEFAULT_PATH) {
+                Ok(contents) => Self::parse(&contents)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+                Err(e) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "failed to read {}: {}",
+                        DEFAULT_PATH, e
+                    )))
+                }
+            },
+        };
+
+        // Sorted, so that the outcome doesn't depend on the order of the environment
+        let mut vars: Vec<(&String, &String)> = env
+            .iter()
+            .filter(|(key, _)| key.starts_with(ENV_PREFIX) && *key != "VIRTUS_CONFIG")
+            .collect();
+        vars.sort();
+        for (key, value) in vars {
+            config.apply(&key[ENV_PREFIX.len()..].to_lowercase(), value)?;
+        }
+
+        for (key, value) in args.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides a single setting. Lists like `peers` are comma separated.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match self.apply(key, value)? {
+            true => Ok(()),
+            false => Err(Error::InvalidConfig(format!("unknown setting {}", key))),
+        }
+    }
+
+    /// Overrides a single setting, returning whether `key` is one
+    fn apply(&mut self, key: &str, value: &str) -> Result<bool, Error> {
+        let invalid = || Error::InvalidConfig(format!("invalid value for {}: {}", key, value));
+
+        match key {
+            "data_dir" => self.data_dir = value.to_string(),
+            "bind" => self.bind = value.parse().map_err(|_| invalid())?,
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "advertise_addresses" => {
+                self.advertise_addresses = parse_list(value).map_err(|_| invalid())?
+            }
+            "advertise_port" => self.advertise_port = Some(value.parse().map_err(|_| invalid())?),
+            "peers" => self.peers = parse_list(value).map_err(|_| invalid())?,
+            "node_id" => self.node_id = Some(Uuid::parse_str(value).map_err(|_| invalid())?),
+            "id_file" => self.id_file = Some(value.to_string()),
+            "log_level" => self.log_level = value.to_lowercase(),
+            "overcommit" => self.overcommit = value.parse().map_err(|_| invalid())?,
+            "reconcile_interval" => {
+                self.reconcile_interval = value.parse().map_err(|_| invalid())?
+            }
+            "adopt_drift" => self.adopt_drift = value.parse().map_err(|_| invalid())?,
+            "host_root" => self.host_root = value.to_string(),
+            "heartbeat_interval" => {
+                self.heartbeat_interval = value.parse().map_err(|_| invalid())?
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::InvalidConfig(message));
+
+        if self.data_dir.is_empty() {
+            return invalid("data_dir must not be empty".to_string());
+        }
+
+        if self.port == 0 || self.advertise_port == Some(0) {
+            return invalid("port must not be 0".to_string());
+        }
+
+        let addresses = self.advertise_addresses();
+        if let Some(address) = addresses.iter().find(|address| address.is_unspecified()) {
+            return match self.advertise_addresses.is_empty() {
+                true => invalid(format!(
+                    "advertise_addresses is required when binding to {}",
+                    address
+                )),
+                false => invalid(format!("{} is not a usable advertise address", address)),
+            };
+        }
+        // Cluster membership goes through skiff, which only speaks IPv4
+        if node::skiff_address(&addresses).is_none() {
+            return invalid("one of advertise_addresses must be IPv4".to_string());
+        }
+
+        let mut peers = HashSet::new();
+        for peer in &self.peers {
+            if peer.is_unspecified() {
+                return invalid(format!("peer {} is not a usable address", peer));
+            }
+            if node::skiff_address(&[*peer]).is_none() {
+                return invalid(format!("peer {} must be an IPv4 address", peer));
+            }
+            if !peers.insert(peer) {
+                return invalid(format!("peer {} is listed twice", peer));
+            }
+        }
+
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return invalid(format!(
+                "log_level must be one of {}",
+                LOG_LEVELS.join(", ")
+            ));
+        }
+
+        if !self.overcommit.is_finite() || self.overcommit <= 0.0 {
+            return invalid("overcommit must be a positive number".to_string());
+        }
+
+        if self.heartbeat_interval == 0 {
+            return invalid("heartbeat_interval must not be 0".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+
+        let mut paths = HashSet::new();
+        for pool in &self.pools {
+            pool.validate()?;
+            if !paths.insert(&pool.path) {
+                return invalid(format!("pool {} is listed twice", pool.path));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The addresses this node advertises: `advertise_addresses`, or `bind` if there are none
+    pub fn advertise_addresses(&self) -> Vec<IpAddr> {
+        match self.advertise_addresses.is_empty() {
+            true => vec![self.bind],
+            false => self.advertise_addresses.clone(),
+        }
+    }
+
+    pub fn id_file(&self) -> PathBuf {
+        match &self.id_file {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&self.data_dir).join("node_id"),
+        }
+    }
+
+    /// The id of this node: `node_id` if set, otherwise the one kept in the id file, which is
+    /// generated on first use. A fixed id has to match the id file once there is one.
+    pub fn node_id(&self) -> Result<Uuid, Error> {
+        let path = self.id_file();
+        let stored = match fs::read_to_string(&path) {
+            Ok(contents) => Some(Uuid::parse_str(contents.trim()).map_err(|_| {
+                Error::InvalidConfig(format!("{} does not contain a node id", path.display()))
+            })?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let id = match (self.node_id, stored) {
+            (Some(id), Some(stored)) if id != stored => {
+                return Err(Error::InvalidConfig(format!(
+                    "node_id {} differs from {} in {}",
+                    id,
+                    stored,
+                    path.display()
+                )))
+            }
+            (_, Some(stored)) => return Ok(stored),
+            (Some(id), None) => id,
+            (None, None) => Uuid::new_v4(),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, format!("{}\n", id))?;
+        Ok(id)
+    }
+}
+
+/// Parses a comma separated list of addresses
+fn parse_list(value: &str) -> Result<Vec<IpAddr>, std::net::AddrParseError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Splits `--key value` and `--key=value` arguments into settings, turning dashes into
+/// underscores
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, Error> {
+    let mut settings = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let key = match arg.strip_prefix("--") {
+            Some(key) => key,
+            None => return Err(Error::InvalidConfig(format!("unexpected argument {}", arg))),
+        };
+
+        let (key, value) = match key.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => match args.next() {
+                Some(value) => (key, value.clone()),
+                None => return Err(Error::InvalidConfig(format!("missing value for {}", arg))),
+            },
+        };
+
+        settings.push((key.replace('-', "_"), value));
+    }
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_file() {
+        let config = Config::parse(
+            r#"
+            data_dir = "/srv/virtus"
+            bind = "10.0.0.2"
+            port = 9401
+            peers = ["10.0.0.1"]
+            log_level = "debug"
+            reconcile_interval = 0
+
+            [[pools]]
+            path = "/srv/virtus/pool"
+
+            [[pools]]
+            path = "/mnt/nfs"
+            name = "shared"
+            backend = "shared"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!("/srv/virtus", config.data_dir);
+        assert_eq!(IpAddr::from([10, 0, 0, 2]), config.bind);
+        assert_eq!(9401, config.port);
+        assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], config.peers);
+        assert_eq!(vec![config.bind], config.advertise_addresses());
+        assert_eq!(0, config.reconcile_interval);
+        assert_eq!(1.0, config.overcommit);
+        assert_eq!("directory", config.pools[0].backend);
+        assert_eq!(Some("shared".to_string()), config.pools[1].name);
+        config.validate().unwrap();
+
+        assert!(matches!(
+            Config::parse("prot = 9401"),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Config::parse("port = \"many\""),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn parse_tls() {
+        let config = Config::parse(
+            r#"
+            [tls]
+            cert = "/etc/virtus/node.pem"
+            key = "/etc/virtus/node.key"
+            ca = "/etc/virtus/ca.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(TlsConfig {
+                cert: "/etc/virtus/node.pem".to_string(),
+                key: "/etc/virtus/node.key".to_string(),
+                ca: Some("/etc/virtus/ca.pem".to_string()),
+            }),
+            config.tls
+        );
+        config.validate().unwrap();
+
+        let config = Config::parse(
+            r#"
+            [tls]
+            cert = "/etc/virtus/node.pem"
+            key = "/etc/virtus/node.key"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(None, config.tls.unwrap().ca);
+
+        assert!(Config::parse("[tls]\ncert = \"/etc/virtus/node.pem\"\n").is_err());
+        assert!(Config::parse("[tls]\ncert = \"a\"\nkey = \"b\"\ncrl = \"c\"\n").is_err());
+        assert!(Config::load(&args(&["--tls", "on"]), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let dir = Path::new("target/tmp/config/overrides");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let path = dir.join("virtus.toml");
+        fs::write(&path, "port = 9401\nlog_level = \"warn\"\n").unwrap();
+
+        let env = HashMap::from([
+            (
+                "VIRTUS_CONFIG".to_string(),
+                path.to_string_lossy().to_string(),
+            ),
+            ("VIRTUS_PORT".to_string(), "9402".to_string()),
+            ("VIRTUS_PEERS".to_string(), "10.0.0.1, 10.0.0.2".to_string()),
+            // Not ours, like the variables of a wrapper script
+            ("VIRTUS_HOME".to_string(), "/opt/virtus".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+
+        let config = Config::load(&args(&[]), &env).unwrap();
+        assert_eq!(9402, config.port);
+        assert_eq!("warn", config.log_level);
+        assert_eq!(2, config.peers.len());
+
+        let config =
+            Config::load(&args(&["--advertise-addresses", "fd00::2,10.0.0.2"]), &env).unwrap();
+        assert_eq!(
+            vec![
+                "fd00::2".parse::<IpAddr>().unwrap(),
+                IpAddr::from([10, 0, 0, 2])
+            ],
+            config.advertise_addresses()
+        );
+        assert!(Config::load(&args(&["--peers", "10.0.0.1,fd00::zz"]), &env).is_err());
+
+        // The command line wins over the environment
+        let config = Config::load(&args(&["--port", "9403", "--log-level=trace"]), &env).unwrap();
+        assert_eq!(9403, config.port);
+        assert_eq!("trace", config.log_level);
+
+        assert!(Config::load(&args(&["--port"]), &env).is_err());
+        assert!(Config::load(&args(&["--colour", "blue"]), &env).is_err());
+
+        // Known variables are still checked
+        let mut bad = env.clone();
+        bad.insert("VIRTUS_PORT".to_string(), "many".to_string());
+        assert!(Config::load(&args(&[]), &bad).is_err());
+        assert!(Config::load(&args(&["9403"]), &env).is_err());
+        assert!(Config::load(&args(&["--config", "/nonexistent/virtus.toml"]), &env).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let valid = Config::default();
+        valid.validate().unwrap();
+
+        // Listening everywhere, reached through NAT
+        let valid = Config {
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            advertise_addresses: vec![IpAddr::from([203, 0, 113, 7])],
+            advertise_port: Some(19400),
+            ..Config::default()
+        };
+        valid.validate().unwrap();
+
+        // Dual-stack, reached over IPv6 first
+        let valid = Config {
+            bind: Ipv6Addr::UNSPECIFIED.into(),
+            advertise_addresses: vec!["fd00::2".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            peers: vec!["::ffff:10.0.0.1".parse().unwrap()],
+            ..Config::default()
+        };
+        valid.validate().unwrap();
+
+        let invalid = [
+            Config {
+                port: 0,
+                ..Config::default()
+            },
+            Config {
+                bind: Ipv4Addr::UNSPECIFIED.into(),
+                ..Config::default()
+            },
+            Config {
+                bind: Ipv6Addr::UNSPECIFIED.into(),
+                ..Config::default()
+            },
+            Config {
+                advertise_addresses: vec![Ipv4Addr::UNSPECIFIED.into()],
+                ..Config::default()
+            },
+            // No IPv4 address for cluster membership
+            Config {
+                bind: Ipv6Addr::LOCALHOST.into(),
+                ..Config::default()
+            },
+            Config {
+                peers: vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 1])],
+                ..Config::default()
+            },
+            Config {
+                peers: vec![Ipv4Addr::UNSPECIFIED.into()],
+                ..Config::default()
+            },
+            Config {
+                peers: vec!["fd00::1".parse().unwrap()],
+                ..Config::default()
+            },
+            Config {
+                log_level: "loud".to_string(),
+                ..Config::default()
+            },
+            Config {
+                overcommit: 0.0,
+                ..Config::default()
+            },
+            Config {
+                heartbeat_interval: 0,
+                ..Config::default()
+            },
+            Config {
+                tls: Some(TlsConfig {
+                    cert: "/etc/virtus/node.pem".to_string(),
+                    key: String::new(),
+                    ca: None,
+                }),
+                ..Config::default()
+            },
+            Config {
+                pools: vec![PoolConfig {
+                    path: "/srv/pool".to_string(),
+                    name: None,
+                    backend: "ceph".to_string(),
+                }],
+                ..Config::default()
+            },
+        ];
+
+        for config in invalid {
+            assert!(
+                matches!(config.validate(), Err(Error::InvalidConfig(_))),
+                "{:?}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn node_id_persists() {
+        let dir = "target/tmp/config/node_id";
+        let _ = fs::remove_dir_all(dir);
+
+        let config = Config {
+            data_dir: dir.to_string(),
+            ..Config::default()
+        };
+
+        let id = config.node_id().unwrap();
+        assert_eq!(id, config.node_id().unwrap());
+        assert!(Path::new(dir).join("node_id").exists());
+
+        let fixed = Config {
+            node_id: Some(Uuid::new_v4()),
+            ..config.clone()
+        };
+        assert!(matches!(fixed.node_id(), Err(Error::InvalidConfig(_))));
+
+        fs::remove_file(config.id_file()).unwrap();
+        assert_eq!(fixed.node_id, Some(fixed.node_id().unwrap()));
+        assert_eq!(fixed.node_id, Some(config.node_id().unwrap()));
+    }
+}
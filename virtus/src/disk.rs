@@ -1,68 +1,354 @@
+use crate::hypervisor::Hypervisor;
+use crate::image::Image;
 use crate::pool::Pool;
+use crate::qemu_img::{QemuImg, SnapshotOp};
 use crate::{error::Error, virtus::virtus_proto};
 use serde::{Deserialize, Serialize};
 use skiff::Client as SkiffClient;
-use std::path::Path;
-use std::process::Command;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+pub const GIB: u64 = 1024 * 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Disk {
     id: Uuid,
     pool_id: Uuid,
     name: Option<String>,
     size_gb: usize,
-    // source: Option<Image>,
-    // snapshots: Vec<Snapshot>,
+    // Fields below default for records written by older versions, which only made sparse
+    // qcow2 disks
+    #[serde(default)]
+    format: DiskFormat,
+    #[serde(default)]
+    preallocation: Preallocation,
+    // Disk or image this one was created from
+    #[serde(default)]
+    source: Option<Uuid>,
+    // Disks and images this one is layered on, immediate backing file first. Empty for full
+    // copies.
+    #[serde(default)]
+    backing_chain: Vec<Uuid>,
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+    // The image is missing or doesn't match the record, set by the reconciler
+    #[serde(default)]
+    broken: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiskFormat {
+    #[default]
+    Qcow2,
+    Raw,
+}
+
+impl DiskFormat {
+    /// Name used by qemu-img and in the libvirt driver type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "raw",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Raw => "img",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    /// Sparse, space is allocated as the guest writes
+    #[default]
+    Off,
+    /// Only qcow2 metadata is allocated up front
+    Metadata,
+    /// Space is reserved with fallocate, but not written
+    Falloc,
+    /// Space is reserved and zeroed
+    Full,
+}
+
+impl Preallocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Preallocation::Off => "off",
+            Preallocation::Metadata => "metadata",
+            Preallocation::Falloc => "falloc",
+            Preallocation::Full => "full",
+        }
+    }
+}
+
+/// How a new disk image is laid out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskOptions {
+    pub format: DiskFormat,
+    pub preallocation: Preallocation,
+    /// qcow2 cluster size in bytes, qemu-img's default (64k) if unset
+    pub cluster_size: Option<u64>,
+    /// Defer qcow2 refcount updates, faster writes at the cost of a repair after a crash
+    pub lazy_refcounts: bool,
+    /// Disk or image to base the new disk on
+    pub source: Option<Uuid>,
+    /// Copy the source instead of using it as a backing file
+    pub full_copy: bool,
+}
+
+/// What performs a disk resize
+#[derive(Debug, Clone, Copy)]
+pub enum Resizer<'a> {
+    /// The image isn't in use, resize the file directly
+    Offline(&'a QemuImg),
+    /// The image is held open by a running domain
+    Live {
+        vm: Uuid,
+        hypervisor: &'a dyn Hypervisor,
+    },
+}
+
+impl DiskOptions {
+    fn validate(&self) -> Result<(), Error> {
+        if self.format == DiskFormat::Raw {
+            if self.preallocation == Preallocation::Metadata {
+                return Err(Error::InvalidDisk(
+                    "metadata preallocation requires qcow2".into(),
+                ));
+            }
+
+            if self.cluster_size.is_some() || self.lazy_refcounts {
+                return Err(Error::InvalidDisk(
+                    "cluster size and lazy refcounts require qcow2".into(),
+                ));
+            }
+        }
+
+        if let Some(size) = self.cluster_size {
+            if !size.is_power_of_two() || !(512..=2 * 1024 * 1024).contains(&size) {
+                return Err(Error::InvalidDisk(format!(
+                    "cluster size {} must be a power of two between 512 and 2M",
+                    size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The `-o` argument for qemu-img create and convert
+    fn to_create_options(&self) -> String {
+        let mut options = vec![format!("preallocation={}", self.preallocation.as_str())];
+
+        if let Some(size) = self.cluster_size {
+            options.push(format!("cluster_size={}", size));
+        }
+
+        if self.lazy_refcounts {
+            options.push("lazy_refcounts=on".to_string());
+        }
+
+        options.join(",")
+    }
+}
+
+/// An internal qcow2 snapshot, identified by its name within the disk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    name: String,
+    // Seconds since the unix epoch
+    created: u64,
+    // Virtual size of the disk when the snapshot was taken
+    size_gb: usize,
 }
 
 impl Disk {
+    /// Creates an empty disk, or one based on the `options.source` disk or image.
+    ///
+    /// With `options.full_copy` the source is copied into a standalone image, otherwise the
+    /// new disk is a copy-on-write overlay that keeps the source as its backing file. A
+    /// `size_gb` of 0 keeps the size of the source.
+    ///
+    /// Fails with `PoolFull` if the pool's allocated size would exceed its capacity times
+    /// `overcommit`.
     pub async fn create(
         pool_id: Uuid,
         size_gb: usize,
         name: Option<&str>,
+        options: &DiskOptions,
+        overcommit: f64,
+        qemu_img: &QemuImg,
         client: &Arc<Mutex<SkiffClient>>,
     ) -> Result<Self, Error> {
-        let pool = match Pool::get(pool_id, client).await? {
+        options.validate()?;
+        if options.source.is_some() && !options.full_copy && options.format == DiskFormat::Raw {
+            return Err(Error::InvalidDisk(
+                "raw disks can't have a backing file, use a full copy".into(),
+            ));
+        }
+
+        let mut pool = match Pool::get(pool_id, client).await? {
             Some(pool) => pool,
             None => return Err(Error::PoolNotFound),
         };
 
+        let source = match options.source {
+            Some(id) => Some(Source::resolve(id, &pool.get_nodes(), client).await?),
+            None => None,
+        };
+
+        let size_gb = match &source {
+            Some(source) if size_gb == 0 => source.size_gb,
+            Some(source) if size_gb < source.size_gb => {
+                return Err(Error::InvalidDisk(format!(
+                    "size {}G is smaller than the source ({}G)",
+                    size_gb, source.size_gb
+                )))
+            }
+            None if size_gb == 0 => {
+                return Err(Error::InvalidDisk("size must be greater than 0".into()))
+            }
+            _ => size_gb,
+        };
+
+        pool.refresh(client).await?;
+        pool.check_capacity((size_gb as u64).saturating_mul(GIB), overcommit)?;
+
         let disk_id = Uuid::new_v4();
+        let format = options.format.as_str();
 
-        let filename = Path::join(
-            Path::new(&pool.get_path()),
-            Path::new(&format!("{}.qcow2", disk_id)),
-        );
+        let filename = pool.disk_path(disk_id, options.format);
+        let size = (size_gb as u64).saturating_mul(GIB);
+        let create_options = options.to_create_options();
+
+        let backing_chain = match &source {
+            None => {
+                qemu_img.create(&filename, format, size, Some(&create_options), None)?;
+                vec![]
+            }
+            Some(source) => match options.full_copy {
+                true => {
+                    qemu_img.convert(
+                        &source.path,
+                        &source.format,
+                        &filename,
+                        format,
+                        Some(&create_options),
+                    )?;
+                    if size_gb > source.size_gb {
+                        qemu_img.resize(
+                            &filename,
+                            format,
+                            size,
+                            false,
+                            Some(options.preallocation.as_str()),
+                        )?;
+                    }
+
+                    vec![]
+                }
+                false => {
+                    qemu_img.create(
+                        &filename,
+                        format,
+                        size,
+                        Some(&create_options),
+                        Some((&source.path, &source.format)),
+                    )?;
+
+                    let mut chain = vec![source.id];
+                    chain.extend(source.backing_chain.iter());
+                    chain
+                }
+            },
+        };
+
+        let disk = Self {
+            id: disk_id,
+            pool_id,
+            name: name.map(|s| s.to_string()),
+            size_gb,
+            format: options.format,
+            preallocation: options.preallocation,
+            source: source.map(|source| source.id),
+            backing_chain,
+            snapshots: vec![],
+            broken: false,
+        };
+
+        disk.commit(client).await?;
+        Ok(disk)
+    }
+
+    /// Records an image found in `pool` that no disk refers to, growing it to a whole
+    /// number of GiB.
+    ///
+    /// The file keeps its name if it is named after a disk id, and is renamed to the path of a
+    /// new disk otherwise.
+    pub async fn adopt(
+        pool: &Pool,
+        path: &Path,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("qcow2") => DiskFormat::Qcow2,
+            Some("img") => DiskFormat::Raw,
+            _ => return Err(Error::InvalidDisk("unknown image extension".into())),
+        };
+
+        let (virtual_size, backing) = probe(path, format, qemu_img)?;
+        if backing.is_some() {
+            return Err(Error::InvalidDisk("image has a backing file".into()));
+        }
 
-        // Todo: check if filesystem has enough space
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(format!(
-                "qemu-img create -f qcow2 {} {}G",
-                filename.to_str().unwrap(),
-                size_gb,
-            ))
-            .output()?;
-
-        match output.status.success() {
-            true => {
-                let disk = Self {
-                    id: disk_id,
-                    pool_id,
-                    name: name.map(|s| s.to_string()),
-                    size_gb,
-                };
-
-                disk.commit(client).await?;
-                Ok(disk)
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("");
+        let id = match Uuid::parse_str(stem) {
+            // Most likely a disk that is being created or moved right now
+            Ok(id) if Disk::get(id, client).await?.is_some() => {
+                return Err(Error::InvalidDisk(format!("image belongs to disk {}", id)))
+            }
+            Ok(id) => id,
+            Err(_) => {
+                let id = Uuid::new_v4();
+                fs::rename(path, pool.disk_path(id, format))?;
+                id
             }
-            false => Err(Error::CommandFailed(
-                String::from_utf8(output.stderr).unwrap(),
-            )),
+        };
+
+        let path = pool.disk_path(id, format);
+        let size_gb = virtual_size.div_ceil(GIB).max(1) as usize;
+        let size = (size_gb as u64).saturating_mul(GIB);
+        if virtual_size < size {
+            qemu_img.resize(&path, format.as_str(), size, false, None)?;
         }
+
+        let disk = Self {
+            id,
+            pool_id: pool.get_id(),
+            name: None,
+            size_gb,
+            format,
+            preallocation: Preallocation::Off,
+            source: None,
+            backing_chain: vec![],
+            snapshots: vec![],
+            broken: false,
+        };
+
+        disk.commit(client).await?;
+        Ok(disk)
     }
 
     pub fn get_id(&self) -> Uuid {
@@ -73,6 +359,294 @@ impl Disk {
         self.pool_id
     }
 
+    pub fn get_format(&self) -> DiskFormat {
+        self.format
+    }
+
+    pub fn get_size_gb(&self) -> usize {
+        self.size_gb
+    }
+
+    /// Virtual size in bytes
+    pub fn get_size(&self) -> u64 {
+        self.size_gb as u64 * GIB
+    }
+
+    pub fn get_source(&self) -> Option<Uuid> {
+        self.source
+    }
+
+    pub fn get_backing_chain(&self) -> Vec<Uuid> {
+        self.backing_chain.clone()
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    pub async fn set_broken(
+        &mut self,
+        broken: bool,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        self.broken = broken;
+        self.commit(client).await
+    }
+
+    pub async fn get_path(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<PathBuf, Error> {
+        let pool = match Pool::get(self.pool_id, client).await? {
+            Some(pool) => pool,
+            None => return Err(Error::PoolNotFound),
+        };
+
+        Ok(pool.disk_path(self.id, self.format))
+    }
+
+    /// Resizes the disk image to `size_gb`.
+    ///
+    /// Shrinking discards everything past the new end of the disk, so it is rejected unless
+    /// `force` is set. Disks of running domains have to be resized through the hypervisor.
+    pub async fn resize(
+        &mut self,
+        size_gb: usize,
+        force: bool,
+        resizer: Resizer<'_>,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        if size_gb == 0 {
+            return Err(Error::InvalidDisk("size must be greater than 0".into()));
+        }
+
+        let shrink = size_gb < self.size_gb;
+        if shrink && !force {
+            return Err(Error::InvalidDisk(format!(
+                "shrinking from {}G to {}G requires force",
+                self.size_gb, size_gb
+            )));
+        }
+
+        if size_gb == self.size_gb {
+            return Ok(());
+        }
+
+        let path = self.get_path(client).await?;
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let size = (size_gb as u64).saturating_mul(GIB);
+
+        match resizer {
+            // Guests can't cope with disks shrinking underneath them
+            Resizer::Live { .. } if shrink => {
+                return Err(Error::InvalidDisk(
+                    "can't shrink the disk of a running VM".into(),
+                ))
+            }
+            Resizer::Live { vm, hypervisor } => {
+                // libvirt names the disk by its path, as a string
+                let source = path.to_str().ok_or_else(|| {
+                    Error::InvalidDisk(format!("path {} is not valid UTF-8", path.display()))
+                })?;
+                hypervisor.resize_disk(vm, source, size)?
+            }
+            Resizer::Offline(qemu_img) => qemu_img.resize(
+                &path,
+                self.format.as_str(),
+                size,
+                shrink,
+                // Preallocation only applies to the added space
+                (!shrink).then(|| self.preallocation.as_str()),
+            )?,
+        }
+
+        self.size_gb = size_gb;
+        self.commit(client).await
+    }
+
+    pub fn get_snapshots(&self) -> Vec<Snapshot> {
+        self.snapshots.clone()
+    }
+
+    /// Takes an internal snapshot of the disk. The disk must not be in use by a running VM.
+    pub async fn create_snapshot(
+        &mut self,
+        name: &str,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Snapshot, Error> {
+        if name.is_empty() {
+            return Err(Error::InvalidDisk("snapshot name is empty".into()));
+        }
+
+        if self.format != DiskFormat::Qcow2 {
+            return Err(Error::InvalidDisk("snapshots require qcow2".into()));
+        }
+
+        if self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(Error::SnapshotExists);
+        }
+
+        let path = self.get_path(client).await?;
+        qemu_img.snapshot(&path, SnapshotOp::Create, name)?;
+
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            size_gb: self.size_gb,
+        };
+
+        self.snapshots.push(snapshot.clone());
+        self.commit(client).await?;
+        Ok(snapshot)
+    }
+
+    /// Rolls the disk back to the named snapshot, including its size at the time
+    pub async fn revert_snapshot(
+        &mut self,
+        name: &str,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        let snapshot = match self.snapshots.iter().find(|snapshot| snapshot.name == name) {
+            Some(snapshot) => snapshot.clone(),
+            None => return Err(Error::SnapshotNotFound),
+        };
+
+        let path = self.get_path(client).await?;
+        qemu_img.snapshot(&path, SnapshotOp::Apply, name)?;
+
+        // Trust the image over our record for the restored size
+        self.size_gb = match qemu_img.info(&path) {
+            Ok(info) => info.virtual_size.div_ceil(GIB) as usize,
+            Err(_) => snapshot.size_gb,
+        };
+        self.commit(client).await
+    }
+
+    pub async fn delete_snapshot(
+        &mut self,
+        name: &str,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        if !self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(Error::SnapshotNotFound);
+        }
+
+        let path = self.get_path(client).await?;
+        qemu_img.snapshot(&path, SnapshotOp::Delete, name)?;
+
+        self.snapshots.retain(|snapshot| snapshot.name != name);
+        self.commit(client).await
+    }
+
+    /// Replaces the contents of the disk with the `format` image at `image`, converting it if
+    /// the disk has a different format. `image` is consumed.
+    ///
+    /// The disk takes the size of the new image, rounded up to whole GiB, and no longer has a
+    /// backing file or snapshots.
+    pub async fn replace_image(
+        &mut self,
+        image: &Path,
+        format: DiskFormat,
+        overcommit: f64,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        let (virtual_size, backing) = probe(image, format, qemu_img)?;
+
+        // It would be opened with the guest's privileges
+        if backing.is_some() {
+            return Err(Error::InvalidDisk("image has a backing file".into()));
+        }
+
+        let size_gb = virtual_size.div_ceil(GIB).max(1) as usize;
+        let size = (size_gb as u64).saturating_mul(GIB);
+
+        let mut pool = match Pool::get(self.pool_id, client).await? {
+            Some(pool) => pool,
+            None => return Err(Error::PoolNotFound),
+        };
+        pool.refresh(client).await?;
+        if size > self.get_size() {
+            pool.check_capacity(size - self.get_size(), overcommit)?;
+        }
+
+        let path = self.get_path(client).await?;
+        let target = self.format.as_str();
+        match format == self.format {
+            true => fs::rename(image, &path)?,
+            false => {
+                // Convert next to the disk so it is only replaced once conversion succeeded
+                let mut converted = path.as_os_str().to_owned();
+                converted.push(".converted");
+                let converted = PathBuf::from(converted);
+
+                let options = format!("preallocation={}", self.preallocation.as_str());
+                if let Err(e) =
+                    qemu_img.convert(image, format.as_str(), &converted, target, Some(&options))
+                {
+                    let _ = fs::remove_file(&converted);
+                    return Err(e);
+                }
+
+                fs::rename(&converted, &path)?;
+                fs::remove_file(image)?;
+            }
+        }
+
+        if virtual_size < size {
+            qemu_img.resize(&path, target, size, false, None)?;
+        }
+
+        self.size_gb = size_gb;
+        self.backing_chain = vec![];
+        self.snapshots = vec![];
+        self.commit(client).await
+    }
+
+    /// Records the image received into `pool_id` as disk `id`.
+    ///
+    /// When `id` is this disk's id, the disk was moved and its record now points at the new
+    /// pool. Otherwise a new disk sourced from this one is created. Flattened images no longer
+    /// have their internal snapshots.
+    pub async fn relocate(
+        &self,
+        id: Uuid,
+        pool_id: Uuid,
+        name: Option<&str>,
+        flattened: bool,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Disk, Error> {
+        let moved = id == self.id;
+        let disk = Self {
+            id,
+            pool_id,
+            name: match moved {
+                true => self.name.clone(),
+                false => name.map(|s| s.to_string()),
+            },
+            size_gb: self.size_gb,
+            format: self.format,
+            preallocation: self.preallocation,
+            source: match moved {
+                true => self.source,
+                false => Some(self.id),
+            },
+            // Backing files stay behind on the old node, so transfers are always flattened
+            backing_chain: vec![],
+            snapshots: match flattened {
+                true => vec![],
+                false => self.snapshots.clone(),
+            },
+            broken: false,
+        };
+
+        disk.commit(client).await?;
+        Ok(disk)
+    }
+
     async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
         client
             .lock()
@@ -83,6 +657,34 @@ impl Disk {
         Ok(())
     }
 
+    pub async fn delete(self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        let pool = match Pool::get(self.pool_id, client).await? {
+            Some(pool) => pool,
+            None => return Err(Error::PoolNotFound),
+        };
+
+        // Including an upload that was never completed
+        for path in [
+            pool.disk_path(self.id, self.format),
+            pool.upload_path(self.id),
+        ] {
+            match fs::remove_file(path) {
+                Ok(_) => {}
+                // The file is already gone, only the record is left to clean up
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        client
+            .lock()
+            .await
+            .remove(format!("disks/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<Disk>, Error> {
         let disk = client
             .lock()
@@ -113,6 +715,141 @@ impl Disk {
     }
 }
 
+/// Returns the virtual size and backing file of the `format` image at `path`.
+///
+/// Raw images are never probed, since a guest can write anything into them, including a
+/// qcow2 header.
+pub fn probe(
+    path: &Path,
+    format: DiskFormat,
+    qemu_img: &QemuImg,
+) -> Result<(u64, Option<String>), Error> {
+    match format {
+        DiskFormat::Raw => Ok((fs::metadata(path)?.len(), None)),
+        DiskFormat::Qcow2 => {
+            let info = qemu_img.info(path)?;
+            if info.format != "qcow2" {
+                return Err(Error::InvalidDisk("image is not qcow2".into()));
+            }
+
+            Ok((info.virtual_size, info.backing_filename))
+        }
+    }
+}
+
+/// A disk or image that a new disk is created from
+struct Source {
+    id: Uuid,
+    // Absolute, since backing file paths are stored in the overlay
+    path: PathBuf,
+    format: String,
+    size_gb: usize,
+    backing_chain: Vec<Uuid>,
+}
+
+impl Source {
+    /// Finds the disk or image `id`. Image copies are looked up for each of the pool's
+    /// `nodes`, taking the one present on this node.
+    async fn resolve(
+        id: Uuid,
+        nodes: &[Uuid],
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        if let Some(disk) = Disk::get(id, client).await? {
+            let path = disk.get_path(client).await?;
+            return Ok(Self {
+                id,
+                path: fs::canonicalize(&path).unwrap_or(path),
+                format: disk.format.as_str().to_string(),
+                size_gb: disk.size_gb,
+                backing_chain: disk.backing_chain,
+            });
+        }
+
+        let image = match Image::get(id, client).await? {
+            Some(image) => image,
+            None => return Err(Error::DiskNotFound),
+        };
+
+        if image.get_format() == "iso" {
+            return Err(Error::InvalidDisk(
+                "ISO images can't be used as a disk source".into(),
+            ));
+        }
+
+        let path = match nodes
+            .iter()
+            .filter_map(|node| image.get_path(*node))
+            .find(|path| path.exists())
+        {
+            Some(path) => path,
+            None => {
+                return Err(Error::InvalidDisk(
+                    "source image has no copy on this node".into(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            id,
+            path,
+            format: image.get_format(),
+            size_gb: image.get_size().div_ceil(GIB) as usize,
+            backing_chain: vec![],
+        })
+    }
+}
+
+impl From<Snapshot> for virtus_proto::Snapshot {
+    fn from(val: Snapshot) -> Self {
+        virtus_proto::Snapshot {
+            name: val.name,
+            created: val.created,
+            size_gb: val.size_gb as u64,
+        }
+    }
+}
+
+impl From<virtus_proto::DiskFormat> for DiskFormat {
+    fn from(val: virtus_proto::DiskFormat) -> Self {
+        match val {
+            virtus_proto::DiskFormat::Qcow2 => DiskFormat::Qcow2,
+            virtus_proto::DiskFormat::Raw => DiskFormat::Raw,
+        }
+    }
+}
+
+impl From<DiskFormat> for virtus_proto::DiskFormat {
+    fn from(val: DiskFormat) -> Self {
+        match val {
+            DiskFormat::Qcow2 => virtus_proto::DiskFormat::Qcow2,
+            DiskFormat::Raw => virtus_proto::DiskFormat::Raw,
+        }
+    }
+}
+
+impl From<virtus_proto::Preallocation> for Preallocation {
+    fn from(val: virtus_proto::Preallocation) -> Self {
+        match val {
+            virtus_proto::Preallocation::Off => Preallocation::Off,
+            virtus_proto::Preallocation::Metadata => Preallocation::Metadata,
+            virtus_proto::Preallocation::Falloc => Preallocation::Falloc,
+            virtus_proto::Preallocation::Full => Preallocation::Full,
+        }
+    }
+}
+
+impl From<Preallocation> for virtus_proto::Preallocation {
+    fn from(val: Preallocation) -> Self {
+        match val {
+            Preallocation::Off => virtus_proto::Preallocation::Off,
+            Preallocation::Metadata => virtus_proto::Preallocation::Metadata,
+            Preallocation::Falloc => virtus_proto::Preallocation::Falloc,
+            Preallocation::Full => virtus_proto::Preallocation::Full,
+        }
+    }
+}
+
 impl From<Disk> for virtus_proto::Disk {
     fn from(val: Disk) -> Self {
         virtus_proto::Disk {
@@ -120,6 +857,95 @@ impl From<Disk> for virtus_proto::Disk {
             pool: val.pool_id.to_string(),
             name: val.name,
             size_gb: val.size_gb as u64,
+            format: virtus_proto::DiskFormat::from(val.format).into(),
+            preallocation: virtus_proto::Preallocation::from(val.preallocation).into(),
+            source: val.source.map(|id| id.to_string()),
+            backing_chain: val
+                .backing_chain
+                .into_iter()
+                .map(|id| id.to_string())
+                .collect(),
+            broken: val.broken,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_old_record() {
+        let json = format!(
+            r#"{{"id":"{}","pool_id":"{}","name":"old","size_gb":10}}"#,
+            Uuid::new_v4(),
+            Uuid::new_v4()
+        );
+
+        let disk: Disk = serde_json::from_str(&json).unwrap();
+        assert_eq!(DiskFormat::Qcow2, disk.format);
+        assert_eq!(Preallocation::Off, disk.preallocation);
+        assert_eq!(None, disk.source);
+        assert!(disk.backing_chain.is_empty());
+        assert!(disk.snapshots.is_empty());
+        assert!(!disk.broken);
+    }
+
+    #[test]
+    fn create_options() {
+        assert_eq!(
+            "preallocation=off",
+            DiskOptions::default().to_create_options()
+        );
+
+        let options = DiskOptions {
+            preallocation: Preallocation::Metadata,
+            cluster_size: Some(2 * 1024 * 1024),
+            lazy_refcounts: true,
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+        assert_eq!(
+            "preallocation=metadata,cluster_size=2097152,lazy_refcounts=on",
+            options.to_create_options()
+        );
+    }
+
+    #[test]
+    fn raw_options() {
+        let options = DiskOptions {
+            format: DiskFormat::Raw,
+            preallocation: Preallocation::Full,
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+
+        for options in [
+            DiskOptions {
+                preallocation: Preallocation::Metadata,
+                ..options.clone()
+            },
+            DiskOptions {
+                cluster_size: Some(65536),
+                ..options.clone()
+            },
+            DiskOptions {
+                lazy_refcounts: true,
+                ..options.clone()
+            },
+        ] {
+            assert!(matches!(options.validate(), Err(Error::InvalidDisk(_))));
+        }
+    }
+
+    #[test]
+    fn cluster_size() {
+        for size in [256, 3000, 4 * 1024 * 1024] {
+            let options = DiskOptions {
+                cluster_size: Some(size),
+                ..Default::default()
+            };
+            assert!(options.validate().is_err());
         }
     }
 }
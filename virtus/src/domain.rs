@@ -0,0 +1,1002 @@
+use crate::error::Error;
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::reader::Reader;
+use quick_xml::writer::Writer;
+use std::io::Cursor;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskBus {
+    Virtio,
+    Sata,
+    Scsi,
+}
+
+impl DiskBus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DiskBus::Virtio => "virtio",
+            DiskBus::Sata => "sata",
+            DiskBus::Scsi => "scsi",
+        }
+    }
+
+    // Prefix of the guest device name, e.g. vda or sda
+    fn prefix(&self) -> &'static str {
+        match self {
+            DiskBus::Virtio => "vd",
+            DiskBus::Sata | DiskBus::Scsi => "sd",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "virtio" => Ok(DiskBus::Virtio),
+            "sata" => Ok(DiskBus::Sata),
+            "scsi" => Ok(DiskBus::Scsi),
+            _ => Err(Error::InvalidDomain(format!(
+                "unsupported disk bus {}",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskDevice {
+    Disk,
+    Cdrom,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainDisk {
+    device: DiskDevice,
+    format: String,
+    source: String,
+    bus: DiskBus,
+    // Assigned by DomainBuilder::build
+    target: String,
+    readonly: bool,
+    boot_order: Option<u32>,
+}
+
+impl DomainDisk {
+    pub fn new(source: &str, format: &str) -> Self {
+        Self {
+            device: DiskDevice::Disk,
+            format: format.to_string(),
+            source: source.to_string(),
+            bus: DiskBus::Virtio,
+            target: String::new(),
+            readonly: false,
+            boot_order: None,
+        }
+    }
+
+    pub fn cdrom(source: &str) -> Self {
+        Self {
+            device: DiskDevice::Cdrom,
+            format: "raw".to_string(),
+            source: source.to_string(),
+            bus: DiskBus::Sata,
+            target: String::new(),
+            readonly: true,
+            boot_order: None,
+        }
+    }
+
+    pub fn bus(mut self, bus: DiskBus) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    pub fn boot_order(mut self, order: u32) -> Self {
+        self.boot_order = Some(order);
+        self
+    }
+
+    pub fn get_source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn get_target(&self) -> String {
+        self.target.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterfaceSource {
+    // Attach to a linux or OVS bridge
+    Bridge(String),
+    // macvtap on top of a host device
+    Direct(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainInterface {
+    source: InterfaceSource,
+    model: String,
+    mac: Option<String>,
+    // Port on an Open vSwitch bridge rather than a linux bridge
+    openvswitch: bool,
+    vlan: Option<u32>,
+}
+
+impl DomainInterface {
+    pub fn new(source: InterfaceSource) -> Self {
+        Self {
+            source,
+            model: "virtio".to_string(),
+            mac: None,
+            openvswitch: false,
+            vlan: None,
+        }
+    }
+
+    pub fn mac(mut self, mac: &str) -> Self {
+        self.mac = Some(mac.to_string());
+        self
+    }
+
+    /// Attaches to an Open vSwitch bridge, which libvirt has to be told about
+    pub fn openvswitch(mut self) -> Self {
+        self.openvswitch = true;
+        self
+    }
+
+    /// Tags the traffic of the interface. Linux bridges can't tag ports, so this needs
+    /// `openvswitch`.
+    pub fn vlan(mut self, tag: u32) -> Self {
+        self.vlan = Some(tag);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Console {
+    Pty,
+    File(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Graphics {
+    Spice,
+    Vnc,
+}
+
+/// A libvirt domain definition.
+///
+/// Built with `DomainBuilder`, rendered with `to_xml` and recovered from libvirt XML with
+/// `from_xml`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainSpec {
+    id: Uuid,
+    name: String,
+    memory: u64,
+    vcpus: u32,
+    arch: String,
+    machine: String,
+    disks: Vec<DomainDisk>,
+    interfaces: Vec<DomainInterface>,
+    consoles: Vec<Console>,
+    graphics: Option<Graphics>,
+    tablet: bool,
+    rng: bool,
+}
+
+impl DomainSpec {
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn get_disks(&self) -> Vec<DomainDisk> {
+        self.disks.clone()
+    }
+
+    pub fn to_xml(&self) -> Result<String, Error> {
+        let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+
+        // <domain type=kvm>
+        writer
+            .create_element("domain")
+            .with_attribute(("type", "kvm"))
+            .write_inner_content::<_, Error>(|writer| {
+                writer
+                    .create_element("name")
+                    .write_text_content(BytesText::new(&self.name))?;
+
+                writer
+                    .create_element("uuid")
+                    .write_text_content(BytesText::new(&self.id.to_string()))?;
+
+                writer
+                    .create_element("memory")
+                    .with_attribute(("unit", "bytes"))
+                    .write_text_content(BytesText::new(&self.memory.to_string()))?;
+
+                writer
+                    .create_element("vcpu")
+                    .write_text_content(BytesText::new(&self.vcpus.to_string()))?;
+
+                // <os>
+                //  <type arch=x86_64 machine=q35>hvm</type>
+                // </os>
+                writer
+                    .create_element("os")
+                    .write_inner_content::<_, Error>(|writer| {
+                        writer
+                            .create_element("type")
+                            .with_attributes([
+                                ("arch", self.arch.as_str()),
+                                ("machine", self.machine.as_str()),
+                            ])
+                            .write_text_content(BytesText::new("hvm"))?;
+
+                        Ok(())
+                    })?;
+
+                writer
+                    .create_element("devices")
+                    .write_inner_content::<_, Error>(|writer| self.write_devices(writer))?;
+
+                Ok(())
+            })?;
+
+        let xml = writer.into_inner().into_inner();
+        Ok(String::from_utf8(xml).unwrap())
+    }
+
+    fn write_devices(&self, writer: &mut Writer<Cursor<Vec<u8>>>) -> Result<(), Error> {
+        for disk in &self.disks {
+            let device = match disk.device {
+                DiskDevice::Disk => "disk",
+                DiskDevice::Cdrom => "cdrom",
+            };
+
+            // <disk type=file device=disk>
+            //  <driver name=qemu type=qcow2 />
+            //  <source file=... />
+            //  <target dev=vda bus=virtio />
+            // </disk>
+            writer
+                .create_element("disk")
+                .with_attributes([("type", "file"), ("device", device)])
+                .write_inner_content::<_, Error>(|writer| {
+                    writer
+                        .create_element("driver")
+                        .with_attributes([("name", "qemu"), ("type", disk.format.as_str())])
+                        .write_empty()?;
+
+                    writer
+                        .create_element("source")
+                        .with_attribute(("file", disk.source.as_str()))
+                        .write_empty()?;
+
+                    writer
+                        .create_element("target")
+                        .with_attributes([
+                            ("dev", disk.target.as_str()),
+                            ("bus", disk.bus.as_str()),
+                        ])
+                        .write_empty()?;
+
+                    if disk.readonly {
+                        writer.create_element("readonly").write_empty()?;
+                    }
+
+                    if let Some(order) = disk.boot_order {
+                        writer
+                            .create_element("boot")
+                            .with_attribute(("order", order.to_string().as_str()))
+                            .write_empty()?;
+                    }
+
+                    Ok(())
+                })?;
+        }
+
+        for interface in &self.interfaces {
+            let (kind, source) = match &interface.source {
+                InterfaceSource::Bridge(bridge) => ("bridge", ("bridge", bridge.as_str())),
+                InterfaceSource::Direct(dev) => ("direct", ("dev", dev.as_str())),
+            };
+
+            writer
+                .create_element("interface")
+                .with_attribute(("type", kind))
+                .write_inner_content::<_, Error>(|writer| {
+                    if let Some(mac) = &interface.mac {
+                        writer
+                            .create_element("mac")
+                            .with_attribute(("address", mac.as_str()))
+                            .write_empty()?;
+                    }
+
+                    let mut element = writer.create_element("source").with_attribute(source);
+                    if kind == "direct" {
+                        element = element.with_attribute(("mode", "bridge"));
+                    }
+                    element.write_empty()?;
+
+                    if interface.openvswitch {
+                        writer
+                            .create_element("virtualport")
+                            .with_attribute(("type", "openvswitch"))
+                            .write_empty()?;
+                    }
+
+                    // <vlan><tag id=100 /></vlan>
+                    if let Some(tag) = interface.vlan {
+                        writer
+                            .create_element("vlan")
+                            .write_inner_content::<_, Error>(|writer| {
+                                writer
+                                    .create_element("tag")
+                                    .with_attribute(("id", tag.to_string().as_str()))
+                                    .write_empty()?;
+                                Ok(())
+                            })?;
+                    }
+
+                    writer
+                        .create_element("model")
+                        .with_attribute(("type", interface.model.as_str()))
+                        .write_empty()?;
+
+                    Ok(())
+                })?;
+        }
+
+        for console in &self.consoles {
+            match console {
+                // <console type=pty />
+                Console::Pty => {
+                    writer
+                        .create_element("console")
+                        .with_attribute(("type", "pty"))
+                        .write_empty()?;
+                }
+                Console::File(path) => {
+                    writer
+                        .create_element("console")
+                        .with_attribute(("type", "file"))
+                        .write_inner_content::<_, Error>(|writer| {
+                            writer
+                                .create_element("source")
+                                .with_attribute(("path", path.as_str()))
+                                .write_empty()?;
+
+                            Ok(())
+                        })?;
+                }
+            }
+        }
+
+        // <input type=tablet bus=usb /> (provides absolute cursor movement)
+        if self.tablet {
+            writer
+                .create_element("input")
+                .with_attributes([("type", "tablet"), ("bus", "usb")])
+                .write_empty()?;
+        }
+
+        match self.graphics {
+            // <graphics type=spice port=-1 tlsPort=-1 autoport=yes>
+            //  <image compression=off />
+            // </graphics>
+            Some(Graphics::Spice) => {
+                writer
+                    .create_element("graphics")
+                    .with_attributes([
+                        ("type", "spice"),
+                        ("port", "-1"),
+                        ("tlsPort", "-1"),
+                        ("autoport", "yes"),
+                    ])
+                    .write_inner_content::<_, Error>(|writer| {
+                        writer
+                            .create_element("image")
+                            .with_attribute(("compression", "off"))
+                            .write_empty()?;
+
+                        Ok(())
+                    })?;
+            }
+            Some(Graphics::Vnc) => {
+                writer
+                    .create_element("graphics")
+                    .with_attributes([("type", "vnc"), ("port", "-1"), ("autoport", "yes")])
+                    .write_empty()?;
+            }
+            None => {}
+        }
+
+        // <rng model=virtio>
+        //  <backend model=random>/dev/urandom</backend>
+        // </rng>
+        if self.rng {
+            writer
+                .create_element("rng")
+                .with_attribute(("model", "virtio"))
+                .write_inner_content::<_, Error>(|writer| {
+                    writer
+                        .create_element("backend")
+                        .with_attribute(("model", "random"))
+                        .write_text_content(BytesText::new("/dev/urandom"))?;
+
+                    Ok(())
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Parses domain XML, such as the output of `virsh dumpxml`.
+    ///
+    /// Elements that the spec doesn't model (controllers, PCI addresses, etc.) are ignored.
+    pub fn from_xml(xml: &str) -> Result<Self, Error> {
+        let root = Element::parse(xml)?;
+        if root.name != "domain" {
+            return Err(Error::InvalidDomain(
+                "root element is not <domain>".to_string(),
+            ));
+        }
+
+        let id = match root.child("uuid").map(|e| Uuid::parse_str(&e.text)) {
+            Some(Ok(id)) => id,
+            _ => {
+                return Err(Error::InvalidDomain(
+                    "missing or invalid <uuid>".to_string(),
+                ))
+            }
+        };
+
+        let name = match root.child("name") {
+            Some(name) => name.text.clone(),
+            None => return Err(Error::InvalidDomain("missing <name>".to_string())),
+        };
+
+        let memory = match root.child("memory") {
+            Some(memory) => parse_memory(&memory.text, memory.attr("unit"))?,
+            None => return Err(Error::InvalidDomain("missing <memory>".to_string())),
+        };
+
+        let vcpus = match root.child("vcpu").map(|e| e.text.parse::<u32>()) {
+            Some(Ok(vcpus)) => vcpus,
+            _ => {
+                return Err(Error::InvalidDomain(
+                    "missing or invalid <vcpu>".to_string(),
+                ))
+            }
+        };
+
+        let os_type = root.child("os").and_then(|os| os.child("type"));
+        let mut spec = Self {
+            id,
+            name,
+            memory,
+            vcpus,
+            arch: os_type
+                .and_then(|t| t.attr("arch"))
+                .unwrap_or("x86_64")
+                .to_string(),
+            machine: os_type
+                .and_then(|t| t.attr("machine"))
+                .unwrap_or("q35")
+                .to_string(),
+            disks: vec![],
+            interfaces: vec![],
+            consoles: vec![],
+            graphics: None,
+            tablet: false,
+            rng: false,
+        };
+
+        let devices = match root.child("devices") {
+            Some(devices) => devices,
+            None => return Ok(spec),
+        };
+
+        for device in &devices.children {
+            match device.name.as_str() {
+                "disk" => spec.disks.push(DomainDisk::from_element(device)?),
+                "interface" => spec.interfaces.push(DomainInterface::from_element(device)?),
+                "console" => match device.attr("type") {
+                    Some("pty") => spec.consoles.push(Console::Pty),
+                    Some("file") => match device.child("source").and_then(|s| s.attr("path")) {
+                        Some(path) => spec.consoles.push(Console::File(path.to_string())),
+                        None => {
+                            return Err(Error::InvalidDomain(
+                                "file console without a source path".to_string(),
+                            ))
+                        }
+                    },
+                    _ => {}
+                },
+                "input" if device.attr("type") == Some("tablet") => spec.tablet = true,
+                "graphics" => match device.attr("type") {
+                    Some("spice") => spec.graphics = Some(Graphics::Spice),
+                    Some("vnc") => spec.graphics = Some(Graphics::Vnc),
+                    _ => {}
+                },
+                "rng" => spec.rng = true,
+                _ => {}
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl DomainDisk {
+    fn from_element(element: &Element) -> Result<Self, Error> {
+        let device = match element.attr("device") {
+            Some("cdrom") => DiskDevice::Cdrom,
+            _ => DiskDevice::Disk,
+        };
+
+        let target = match element.child("target") {
+            Some(target) => target,
+            None => return Err(Error::InvalidDomain("disk without a <target>".to_string())),
+        };
+
+        Ok(Self {
+            device,
+            format: element
+                .child("driver")
+                .and_then(|d| d.attr("type"))
+                .unwrap_or("raw")
+                .to_string(),
+            source: element
+                .child("source")
+                .and_then(|s| s.attr("file"))
+                .unwrap_or_default()
+                .to_string(),
+            bus: DiskBus::parse(target.attr("bus").unwrap_or("virtio"))?,
+            target: target.attr("dev").unwrap_or_default().to_string(),
+            readonly: element.child("readonly").is_some(),
+            boot_order: element
+                .child("boot")
+                .and_then(|b| b.attr("order"))
+                .and_then(|order| order.parse().ok()),
+        })
+    }
+}
+
+impl DomainInterface {
+    fn from_element(element: &Element) -> Result<Self, Error> {
+        let source = element.child("source");
+        let source = match element.attr("type") {
+            Some("bridge") => match source.and_then(|s| s.attr("bridge")) {
+                Some(bridge) => InterfaceSource::Bridge(bridge.to_string()),
+                None => return Err(Error::InvalidDomain("bridge without a source".to_string())),
+            },
+            Some("direct") => match source.and_then(|s| s.attr("dev")) {
+                Some(dev) => InterfaceSource::Direct(dev.to_string()),
+                None => return Err(Error::InvalidDomain("direct without a source".to_string())),
+            },
+            other => {
+                return Err(Error::InvalidDomain(format!(
+                    "unsupported interface type {}",
+                    other.unwrap_or_default()
+                )))
+            }
+        };
+
+        Ok(Self {
+            source,
+            model: element
+                .child("model")
+                .and_then(|m| m.attr("type"))
+                .unwrap_or("virtio")
+                .to_string(),
+            mac: element
+                .child("mac")
+                .and_then(|m| m.attr("address"))
+                .map(|mac| mac.to_string()),
+            openvswitch: element.child("virtualport").and_then(|v| v.attr("type"))
+                == Some("openvswitch"),
+            vlan: element
+                .child("vlan")
+                .and_then(|v| v.child("tag"))
+                .and_then(|t| t.attr("id"))
+                .and_then(|id| id.parse().ok()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DomainBuilder {
+    id: Uuid,
+    name: String,
+    memory: u64,
+    vcpus: u32,
+    machine: String,
+    disks: Vec<DomainDisk>,
+    interfaces: Vec<DomainInterface>,
+    consoles: Vec<Console>,
+    graphics: Option<Graphics>,
+    tablet: bool,
+    rng: bool,
+}
+
+impl DomainBuilder {
+    pub fn new(id: Uuid, name: &str) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            memory: 1024 * 1024 * 1024,
+            vcpus: 1,
+            machine: "q35".to_string(),
+            disks: vec![],
+            interfaces: vec![],
+            consoles: vec![],
+            graphics: None,
+            tablet: false,
+            rng: false,
+        }
+    }
+
+    /// Memory in bytes
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn vcpus(mut self, vcpus: u32) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+
+    pub fn machine(mut self, machine: &str) -> Self {
+        self.machine = machine.to_string();
+        self
+    }
+
+    pub fn disk(mut self, disk: DomainDisk) -> Self {
+        self.disks.push(disk);
+        self
+    }
+
+    pub fn interface(mut self, interface: DomainInterface) -> Self {
+        self.interfaces.push(interface);
+        self
+    }
+
+    pub fn console(mut self, console: Console) -> Self {
+        self.consoles.push(console);
+        self
+    }
+
+    pub fn graphics(mut self, graphics: Graphics) -> Self {
+        self.graphics = Some(graphics);
+        self
+    }
+
+    pub fn tablet(mut self) -> Self {
+        self.tablet = true;
+        self
+    }
+
+    pub fn rng(mut self) -> Self {
+        self.rng = true;
+        self
+    }
+
+    pub fn build(self) -> Result<DomainSpec, Error> {
+        if self.name.is_empty() {
+            return Err(Error::InvalidDomain("name is empty".to_string()));
+        }
+
+        if self.vcpus == 0 {
+            return Err(Error::InvalidDomain("vcpus must be non-zero".to_string()));
+        }
+
+        if self.memory == 0 {
+            return Err(Error::InvalidDomain("memory must be non-zero".to_string()));
+        }
+
+        // libvirt refuses to define these
+        if let Some(interface) = self
+            .interfaces
+            .iter()
+            .find(|interface| interface.vlan.is_some() && !interface.openvswitch)
+        {
+            return Err(Error::InvalidDomain(format!(
+                "vlan on {:?} needs an openvswitch bridge",
+                interface.source
+            )));
+        }
+
+        // Number the disks per device prefix, in the order they were added,
+        // so every disk gets a unique target (vda, vdb, ..., sda, sdb, ...)
+        let mut disks = self.disks;
+        let mut counts = std::collections::HashMap::<&str, usize>::new();
+        for disk in disks.iter_mut() {
+            let index = counts.entry(disk.bus.prefix()).or_default();
+            disk.target = device_name(disk.bus.prefix(), *index);
+            *index += 1;
+        }
+
+        Ok(DomainSpec {
+            id: self.id,
+            name: self.name,
+            memory: self.memory,
+            vcpus: self.vcpus,
+            arch: "x86_64".to_string(),
+            machine: self.machine,
+            disks,
+            interfaces: self.interfaces,
+            consoles: self.consoles,
+            graphics: self.graphics,
+            tablet: self.tablet,
+            rng: self.rng,
+        })
+    }
+}
+
+// Linux style device naming: vda..vdz, vdaa..vdaz, vdba, ...
+fn device_name(prefix: &str, index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut index = index;
+    loop {
+        suffix.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    suffix.reverse();
+
+    format!("{}{}", prefix, String::from_utf8(suffix).unwrap())
+}
+
+fn parse_memory(value: &str, unit: Option<&str>) -> Result<u64, Error> {
+    let value = match value.parse::<u64>() {
+        Ok(value) => value,
+        Err(_) => return Err(Error::InvalidDomain(format!("invalid memory {}", value))),
+    };
+
+    // libvirt defaults to KiB when no unit is given
+    let multiplier: u64 = match unit.unwrap_or("KiB") {
+        "b" | "bytes" => 1,
+        "KB" => 1000,
+        "k" | "KiB" => 1024,
+        "MB" => 1000 * 1000,
+        "M" | "MiB" => 1024 * 1024,
+        "GB" => 1000 * 1000 * 1000,
+        "G" | "GiB" => 1024 * 1024 * 1024,
+        "TB" => 1000 * 1000 * 1000 * 1000,
+        "T" | "TiB" => 1024 * 1024 * 1024 * 1024,
+        unit => {
+            return Err(Error::InvalidDomain(format!(
+                "unknown memory unit {}",
+                unit
+            )))
+        }
+    };
+
+    value.checked_mul(multiplier).ok_or_else(|| {
+        Error::InvalidDomain(format!(
+            "memory {} {} is too large",
+            value,
+            unit.unwrap_or("KiB")
+        ))
+    })
+}
+
+// Minimal DOM used to pull the fields we care about out of domain XML
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn parse(xml: &str) -> Result<Self, Error> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let mut stack: Vec<Element> = vec![];
+        loop {
+            match reader.read_event()? {
+                Event::Start(start) => stack.push(Element::from_start(&start)?),
+                Event::Empty(start) => {
+                    let element = Element::from_start(&start)?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(element),
+                    }
+                }
+                Event::Text(text) => {
+                    if let Some(current) = stack.last_mut() {
+                        current.text.push_str(&text.unescape()?);
+                    }
+                }
+                Event::End(_) => {
+                    let element = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(element),
+                    }
+                }
+                Event::Eof => {
+                    return Err(Error::InvalidDomain("unexpected end of XML".to_string()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn from_start(start: &BytesStart) -> Result<Self, Error> {
+        let mut element = Element {
+            name: String::from_utf8_lossy(start.name().as_ref()).to_string(),
+            ..Default::default()
+        };
+
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(quick_xml::Error::from)?;
+            element.attributes.push((
+                String::from_utf8_lossy(attribute.key.as_ref()).to_string(),
+                attribute.unescape_value()?.to_string(),
+            ));
+        }
+
+        Ok(element)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minimal() -> DomainSpec {
+        DomainBuilder::new(
+            Uuid::parse_str("6f1c2b52-3a9d-4a57-9b55-1d2a3c4e5f60").unwrap(),
+            "minimal",
+        )
+        .memory(512 * 1024 * 1024)
+        .disk(DomainDisk::new("/var/lib/virtus/pool/root.qcow2", "qcow2"))
+        .console(Console::Pty)
+        .build()
+        .unwrap()
+    }
+
+    fn full() -> DomainSpec {
+        DomainBuilder::new(
+            Uuid::parse_str("0b6e8c1e-8d7f-4b8e-a1c3-2f4d6e8a0b2c").unwrap(),
+            "full & <escaped>",
+        )
+        .memory(4 * 1024 * 1024 * 1024)
+        .vcpus(4)
+        .disk(DomainDisk::new("/pool/root.qcow2", "qcow2").boot_order(2))
+        .disk(DomainDisk::new("/pool/data.raw", "raw"))
+        .disk(DomainDisk::cdrom("/images/installer.iso").boot_order(1))
+        .disk(DomainDisk::new("/pool/scratch.qcow2", "qcow2").bus(DiskBus::Scsi))
+        .interface(
+            DomainInterface::new(InterfaceSource::Bridge("virtus-ovs".to_string()))
+                .openvswitch()
+                .vlan(100),
+        )
+        .interface(
+            DomainInterface::new(InterfaceSource::Direct("eth1".to_string()))
+                .mac("52:54:00:12:34:56"),
+        )
+        .console(Console::Pty)
+        .console(Console::File("/var/log/virtus/full.log".to_string()))
+        .tablet()
+        .graphics(Graphics::Spice)
+        .rng()
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn minimal_matches_golden() {
+        assert_eq!(
+            include_str!("../testdata/domain/minimal.xml").trim_end(),
+            minimal().to_xml().unwrap()
+        );
+    }
+
+    #[test]
+    fn full_matches_golden() {
+        assert_eq!(
+            include_str!("../testdata/domain/full.xml").trim_end(),
+            full().to_xml().unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for spec in [minimal(), full()] {
+            assert_eq!(spec, DomainSpec::from_xml(&spec.to_xml().unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn unique_targets() {
+        let mut builder = DomainBuilder::new(Uuid::new_v4(), "many");
+        for i in 0..30 {
+            builder = builder.disk(DomainDisk::new(&format!("/pool/{}.qcow2", i), "qcow2"));
+        }
+        let spec = builder
+            .disk(DomainDisk::cdrom("/images/a.iso"))
+            .disk(DomainDisk::cdrom("/images/b.iso"))
+            .build()
+            .unwrap();
+
+        let targets: Vec<String> = spec.get_disks().iter().map(|d| d.get_target()).collect();
+        assert_eq!("vda", targets[0]);
+        assert_eq!("vdz", targets[25]);
+        assert_eq!("vdaa", targets[26]);
+        assert_eq!("sda", targets[30]);
+        assert_eq!("sdb", targets[31]);
+
+        let mut deduped = targets.clone();
+        deduped.sort();
+        deduped.dedup();
+        assert_eq!(targets.len(), deduped.len());
+    }
+
+    #[test]
+    fn parse_libvirt_dumpxml() {
+        let spec = DomainSpec::from_xml(include_str!("../testdata/domain/dumpxml.xml")).unwrap();
+        let expected = DomainBuilder::new(
+            Uuid::parse_str("6f1c2b52-3a9d-4a57-9b55-1d2a3c4e5f60").unwrap(),
+            "minimal",
+        )
+        .memory(512 * 1024 * 1024)
+        .machine("pc-q35-8.2")
+        .disk(DomainDisk::new("/var/lib/virtus/pool/root.qcow2", "qcow2"))
+        .interface(
+            DomainInterface::new(InterfaceSource::Bridge("virtus-br".to_string()))
+                .mac("52:54:00:ab:cd:ef"),
+        )
+        .console(Console::Pty)
+        .build()
+        .unwrap();
+
+        assert_eq!(expected, spec);
+    }
+
+    #[test]
+    fn invalid_spec() {
+        assert!(DomainBuilder::new(Uuid::new_v4(), "").build().is_err());
+        assert!(DomainBuilder::new(Uuid::new_v4(), "a")
+            .vcpus(0)
+            .build()
+            .is_err());
+        assert!(DomainSpec::from_xml("<domain><name>a</name></domain>").is_err());
+        assert!(DomainBuilder::new(Uuid::new_v4(), "a")
+            .interface(
+                DomainInterface::new(InterfaceSource::Bridge("virtus-br".to_string())).vlan(100)
+            )
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(512 * 1024, parse_memory("512", None).unwrap());
+        assert_eq!(
+            2 * 1024 * 1024 * 1024,
+            parse_memory("2", Some("GiB")).unwrap()
+        );
+        assert!(parse_memory("2", Some("PiB")).is_err());
+        assert!(parse_memory(&u64::MAX.to_string(), Some("TiB")).is_err());
+    }
+}
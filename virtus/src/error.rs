@@ -15,8 +15,74 @@ pub enum Error {
     NoLeaderElected,
     #[error("Pool not found")]
     PoolNotFound,
+    #[error("Pool still contains disks")]
+    PoolNotEmpty,
+    #[error("Pool does not have enough capacity")]
+    PoolFull,
+    #[error("Invalid pool: {0}")]
+    InvalidPool(String),
+    #[error("Pool storage is not available: {0}")]
+    PoolUnavailable(String),
+    #[error("Node not found")]
+    NodeNotFound,
+    #[error("Node still owns pools or VMs")]
+    NodeNotEmpty,
+    #[error("Disk not found")]
+    DiskNotFound,
+    #[error("Snapshot not found")]
+    SnapshotNotFound,
+    #[error("Snapshot of that name already exists")]
+    SnapshotExists,
+    #[error("Invalid disk: {0}")]
+    InvalidDisk(String),
+    #[error("Image not found")]
+    ImageNotFound,
+    #[error("Image of that name already exists")]
+    ImageExists,
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+    #[error("VM not found")]
+    VMNotFound,
+    #[error("VM of that name already exists")]
+    VMExists,
+    #[error("Domain not found")]
+    DomainNotFound,
+    #[error("No hypervisor configured")]
+    NoHypervisor,
+    #[error("Hypervisor error: {0}")]
+    HypervisorError(String),
+    #[error("Network not found")]
+    NetworkNotFound,
+    #[error("Network of that name already exists")]
+    NetworkExists,
+    #[error("Physical network already exists")]
+    PhysicalNetworkExists,
+    #[error("Network still has interfaces attached")]
+    NetworkInUse,
+    #[error("Invalid network: {0}")]
+    InvalidNetwork(String),
+    #[error("Invalid domain: {0}")]
+    InvalidDomain(String),
+    #[error("XML error")]
+    XMLError(quick_xml::Error),
     #[error("Command failed: {0}")]
     CommandFailed(String),
+    #[error("qemu-img is not installed")]
+    QemuImgNotFound,
+    #[error("Disk image is locked by another process: {0}")]
+    DiskLocked(String),
+    #[error("Unexpected qemu-img output: {0}")]
+    InvalidQemuImgOutput(String),
+    #[error("Disk transfer failed: {0}")]
+    TransferFailed(String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Unsupported address: {0}")]
+    UnsupportedAddress(String),
+    #[error("Node failed to start: {0}")]
+    StartFailed(String),
+    #[error("Node failed to shut down: {0}")]
+    ShutdownFailed(String),
 }
 
 impl From<skiff::Error> for Error {
@@ -25,8 +91,21 @@ impl From<skiff::Error> for Error {
     }
 }
 
+impl From<quick_xml::Error> for Error {
+    fn from(err: quick_xml::Error) -> Self {
+        Self::XMLError(err)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Self::IOError(err)
     }
 }
+
+#[cfg(feature = "libvirt")]
+impl From<virt::error::Error> for Error {
+    fn from(err: virt::error::Error) -> Self {
+        Self::HypervisorError(err.to_string())
+    }
+}
@@ -0,0 +1,77 @@
+use crate::error::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Outcome of the startup of a node, `None` while it is still starting
+pub(crate) type Startup = Option<Result<(), String>>;
+
+/// A started `Virtus`, returned by `Virtus::start`.
+///
+/// Dropping the handle leaves the node running, like dropping a `JoinHandle`.
+pub struct Handle {
+    ready: watch::Receiver<Startup>,
+    shutdown: watch::Sender<bool>,
+    server: JoinHandle<Result<(), tonic::transport::Error>>,
+    background: JoinHandle<()>,
+}
+
+impl Handle {
+    pub(crate) fn new(
+        ready: watch::Receiver<Startup>,
+        shutdown: watch::Sender<bool>,
+        server: JoinHandle<Result<(), tonic::transport::Error>>,
+        background: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            ready,
+            shutdown,
+            server,
+            background,
+        }
+    }
+
+    /// Resolves once a leader is elected and this node is registered with its default pools,
+    /// or with the reason it couldn't start.
+    pub async fn ready(&self) -> Result<(), Error> {
+        let mut ready = self.ready.clone();
+        let startup = match ready.wait_for(Option::is_some).await {
+            Ok(startup) => startup.clone(),
+            // Shut down before it was ready
+            Err(_) => return Err(Error::StartFailed("node was shut down".to_string())),
+        };
+
+        match startup {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => Err(Error::StartFailed(e)),
+            None => unreachable!(),
+        }
+    }
+
+    /// Stops the node. The background tasks stop, and the gRPC server, which also carries
+    /// skiff's raft traffic, stops accepting connections and finishes the requests in flight.
+    ///
+    /// The node stays a member of the cluster and rejoins when started again with the same
+    /// id. Use `RemoveNode` to leave it for good.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        // Fails only if everything listening has already stopped
+        let _ = self.shutdown.send(true);
+
+        self.background
+            .await
+            .map_err(|e| Error::ShutdownFailed(e.to_string()))?;
+
+        match self.server.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(Error::ShutdownFailed(e.to_string())),
+            Err(e) => Err(Error::ShutdownFailed(e.to_string())),
+        }
+    }
+}
+
+/// Resolves once shutdown is requested through `Handle::shutdown`. A dropped handle never
+/// requests it.
+pub(crate) async fn requested(mut shutdown: watch::Receiver<bool>) {
+    if shutdown.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
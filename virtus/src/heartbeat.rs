@@ -0,0 +1,157 @@
+use crate::error::Error;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use skiff::Client as SkiffClient;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Version of this agent, published with every heartbeat
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Heartbeats a node may miss before it is considered down
+const MISSED_BEATS: u64 = 3;
+
+/// Liveness of a node, derived from its last heartbeat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    /// The node never published a heartbeat, or it couldn't be read
+    Unknown,
+    Ready,
+    /// The node missed several heartbeats in a row
+    NotReady,
+}
+
+/// What a node publishes every heartbeat interval.
+///
+/// Kept apart from the node record, so heartbeats don't race with pool and VM updates.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+    node: Uuid,
+    // Seconds since the unix epoch, on the node's clock
+    last_seen: u64,
+    version: String,
+    // Seconds since the agent started
+    uptime: u64,
+    // Seconds until the next heartbeat is due
+    interval: u64,
+}
+
+impl Heartbeat {
+    pub fn new(node: Uuid, uptime: Duration, interval: Duration) -> Self {
+        Self {
+            node,
+            last_seen: now(),
+            version: VERSION.to_string(),
+            uptime: uptime.as_secs(),
+            interval: interval.as_secs().max(1),
+        }
+    }
+
+    /// The status of the node at `now`, in seconds since the unix epoch. Clocks are
+    /// assumed to be roughly in sync, a heartbeat from the future counts as current.
+    pub fn status(&self, now: u64) -> NodeStatus {
+        match now.saturating_sub(self.last_seen) <= self.interval.saturating_mul(MISSED_BEATS) {
+            true => NodeStatus::Ready,
+            false => NodeStatus::NotReady,
+        }
+    }
+
+    pub async fn publish(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("heartbeats/{}", self.node).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(
+        node: Uuid,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Heartbeat>, Error> {
+        let heartbeat = client
+            .lock()
+            .await
+            .get::<Heartbeat>(format!("heartbeats/{}", node).as_str())
+            .await?;
+
+        Ok(heartbeat)
+    }
+
+    pub async fn delete(node: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .remove(format!("heartbeats/{}", node).as_str())
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The current status of `node` and its last heartbeat
+pub async fn status(
+    node: Uuid,
+    client: &Arc<Mutex<SkiffClient>>,
+) -> (NodeStatus, Option<Heartbeat>) {
+    match Heartbeat::get(node, client).await {
+        Ok(Some(heartbeat)) => (heartbeat.status(now()), Some(heartbeat)),
+        _ => (NodeStatus::Unknown, None),
+    }
+}
+
+/// Seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl From<NodeStatus> for virtus_proto::NodeStatus {
+    fn from(val: NodeStatus) -> Self {
+        match val {
+            NodeStatus::Unknown => virtus_proto::NodeStatus::Unknown,
+            NodeStatus::Ready => virtus_proto::NodeStatus::Ready,
+            NodeStatus::NotReady => virtus_proto::NodeStatus::NotReady,
+        }
+    }
+}
+
+impl From<Heartbeat> for virtus_proto::Heartbeat {
+    fn from(val: Heartbeat) -> Self {
+        virtus_proto::Heartbeat {
+            last_seen: val.last_seen,
+            version: val.version,
+            uptime: val.uptime,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_from_last_seen() {
+        let heartbeat = Heartbeat::new(Uuid::new_v4(), Duration::ZERO, Duration::from_secs(10));
+        let seen = heartbeat.last_seen;
+
+        assert_eq!(NodeStatus::Ready, heartbeat.status(seen));
+        assert_eq!(NodeStatus::Ready, heartbeat.status(seen + 30));
+        assert_eq!(NodeStatus::NotReady, heartbeat.status(seen + 31));
+        // Clock skew in the other direction
+        assert_eq!(NodeStatus::Ready, heartbeat.status(seen - 60));
+
+        // Intervals under a second still allow for some delay
+        let heartbeat = Heartbeat::new(Uuid::new_v4(), Duration::ZERO, Duration::from_millis(100));
+        assert_eq!(NodeStatus::Ready, heartbeat.status(seen + 3));
+
+        // Intervals too long to multiply never expire
+        let heartbeat = Heartbeat::new(Uuid::new_v4(), Duration::ZERO, Duration::MAX);
+        assert_eq!(NodeStatus::Ready, heartbeat.status(u64::MAX));
+    }
+}
@@ -0,0 +1,136 @@
+use super::{DomainState, Hypervisor};
+use crate::error::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+struct FakeDomain {
+    xml: String,
+    state: DomainState,
+}
+
+/// In-process hypervisor that only tracks domain state.
+///
+/// Used by tests and on hosts without KVM or libvirtd.
+#[derive(Debug, Default)]
+pub struct FakeHypervisor {
+    domains: Mutex<HashMap<Uuid, FakeDomain>>,
+}
+
+impl FakeHypervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn transition(
+        &self,
+        id: Uuid,
+        transition: impl FnOnce(DomainState) -> Result<DomainState, Error>,
+    ) -> Result<(), Error> {
+        let mut domains = self.domains.lock().unwrap();
+        match domains.get_mut(&id) {
+            Some(domain) => {
+                domain.state = transition(domain.state)?;
+                Ok(())
+            }
+            None => Err(Error::DomainNotFound),
+        }
+    }
+}
+
+impl Hypervisor for FakeHypervisor {
+    fn define(&self, id: Uuid, xml: &str) -> Result<(), Error> {
+        let mut domains = self.domains.lock().unwrap();
+        match domains.get_mut(&id) {
+            Some(domain) => domain.xml = xml.to_string(),
+            None => {
+                domains.insert(
+                    id,
+                    FakeDomain {
+                        xml: xml.to_string(),
+                        state: DomainState::Stopped,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn undefine(&self, id: Uuid) -> Result<(), Error> {
+        let mut domains = self.domains.lock().unwrap();
+        match domains.get(&id).map(|domain| domain.state) {
+            Some(DomainState::Stopped) => {
+                domains.remove(&id);
+                Ok(())
+            }
+            Some(_) => Err(Error::HypervisorError("domain is active".to_string())),
+            None => Err(Error::DomainNotFound),
+        }
+    }
+
+    fn start(&self, id: Uuid) -> Result<(), Error> {
+        self.transition(id, |state| match state {
+            DomainState::Stopped | DomainState::Paused | DomainState::Suspended => {
+                Ok(DomainState::Running)
+            }
+            _ => Err(Error::HypervisorError(
+                "domain is already active".to_string(),
+            )),
+        })
+    }
+
+    fn stop(&self, id: Uuid) -> Result<(), Error> {
+        // There is no guest to wait on, so shutdown completes immediately
+        self.transition(id, |state| match state {
+            DomainState::Running
+            | DomainState::Paused
+            | DomainState::Suspended
+            | DomainState::ShuttingDown => Ok(DomainState::Stopped),
+            _ => Err(Error::HypervisorError("domain is not running".to_string())),
+        })
+    }
+
+    fn destroy(&self, id: Uuid) -> Result<(), Error> {
+        self.transition(id, |state| match state {
+            DomainState::Stopped => {
+                Err(Error::HypervisorError("domain is not running".to_string()))
+            }
+            _ => Ok(DomainState::Stopped),
+        })
+    }
+
+    fn state(&self, id: Uuid) -> Result<DomainState, Error> {
+        Ok(self
+            .domains
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|domain| domain.state)
+            .unwrap_or(DomainState::Undefined))
+    }
+
+    fn xml(&self, id: Uuid) -> Result<String, Error> {
+        match self.domains.lock().unwrap().get(&id) {
+            Some(domain) => Ok(domain.xml.clone()),
+            None => Err(Error::DomainNotFound),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>, Error> {
+        Ok(self.domains.lock().unwrap().keys().copied().collect())
+    }
+
+    fn resize_disk(&self, id: Uuid, _path: &str, _bytes: u64) -> Result<(), Error> {
+        match self.state(id)? {
+            DomainState::Running | DomainState::Paused | DomainState::Suspended => Ok(()),
+            DomainState::Undefined => Err(Error::DomainNotFound),
+            _ => Err(Error::HypervisorError("domain is not running".to_string())),
+        }
+    }
+
+    fn libvirt_version(&self) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,143 @@
+use super::{DomainState, Hypervisor};
+use crate::error::Error;
+use std::fmt;
+use std::os::raw::{c_int, c_uint};
+use std::sync::Mutex;
+use uuid::Uuid;
+use virt::connect::Connect;
+use virt::domain::Domain;
+
+extern "C" {
+    // Not wrapped by the virt crate, but exported by the libvirt it links against
+    fn virDomainPMWakeup(domain: virt::sys::virDomainPtr, flags: c_uint) -> c_int;
+}
+
+pub struct Libvirt {
+    uri: String,
+    conn: Mutex<Connect>,
+}
+
+impl Libvirt {
+    pub fn connect(uri: &str) -> Result<Self, Error> {
+        Ok(Self {
+            uri: uri.to_string(),
+            conn: Mutex::new(Connect::open(Some(uri))?),
+        })
+    }
+
+    fn lookup(&self, id: Uuid) -> Result<Option<Domain>, Error> {
+        let conn = self.conn.lock().unwrap();
+        match Domain::lookup_by_uuid_string(&conn, &id.to_string()) {
+            Ok(domain) => Ok(Some(domain)),
+            Err(e) if e.code() == virt::error::ErrorNumber::NoDomain => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn domain(&self, id: Uuid) -> Result<Domain, Error> {
+        self.lookup(id)?.ok_or(Error::DomainNotFound)
+    }
+}
+
+/// Wakes a guest that suspended itself to memory or disk
+fn pm_wakeup(domain: &Domain) -> Result<u32, virt::error::Error> {
+    match unsafe { virDomainPMWakeup(domain.as_ptr(), 0) } {
+        -1 => Err(virt::error::Error::last_error()),
+        ret => Ok(ret as u32),
+    }
+}
+
+impl fmt::Debug for Libvirt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Libvirt").field("uri", &self.uri).finish()
+    }
+}
+
+impl Hypervisor for Libvirt {
+    fn define(&self, _id: Uuid, xml: &str) -> Result<(), Error> {
+        // The domain uuid is taken from the XML itself
+        Domain::define_xml(&self.conn.lock().unwrap(), xml)?;
+        Ok(())
+    }
+
+    fn undefine(&self, id: Uuid) -> Result<(), Error> {
+        self.domain(id)?.undefine()?;
+        Ok(())
+    }
+
+    fn start(&self, id: Uuid) -> Result<(), Error> {
+        let domain = self.domain(id)?;
+        match self.state(id)? {
+            DomainState::Paused => domain.resume()?,
+            DomainState::Suspended => pm_wakeup(&domain)?,
+            _ => domain.create()?,
+        };
+
+        Ok(())
+    }
+
+    fn stop(&self, id: Uuid) -> Result<(), Error> {
+        self.domain(id)?.shutdown()?;
+        Ok(())
+    }
+
+    fn destroy(&self, id: Uuid) -> Result<(), Error> {
+        self.domain(id)?.destroy()?;
+        Ok(())
+    }
+
+    fn state(&self, id: Uuid) -> Result<DomainState, Error> {
+        let domain = match self.lookup(id)? {
+            Some(domain) => domain,
+            None => return Ok(DomainState::Undefined),
+        };
+
+        match domain.get_state()?.0 {
+            // Blocked domains are running guests waiting on I/O
+            virt::sys::VIR_DOMAIN_RUNNING | virt::sys::VIR_DOMAIN_BLOCKED => {
+                Ok(DomainState::Running)
+            }
+            virt::sys::VIR_DOMAIN_PAUSED => Ok(DomainState::Paused),
+            virt::sys::VIR_DOMAIN_PMSUSPENDED => Ok(DomainState::Suspended),
+            virt::sys::VIR_DOMAIN_SHUTDOWN => Ok(DomainState::ShuttingDown),
+            virt::sys::VIR_DOMAIN_SHUTOFF | virt::sys::VIR_DOMAIN_CRASHED => {
+                Ok(DomainState::Stopped)
+            }
+            _ => Ok(DomainState::Undefined),
+        }
+    }
+
+    fn xml(&self, id: Uuid) -> Result<String, Error> {
+        Ok(self.domain(id)?.get_xml_desc(0)?)
+    }
+
+    fn list(&self) -> Result<Vec<Uuid>, Error> {
+        let domains = self.conn.lock().unwrap().list_all_domains(
+            virt::sys::VIR_CONNECT_LIST_DOMAINS_ACTIVE
+                | virt::sys::VIR_CONNECT_LIST_DOMAINS_INACTIVE,
+        )?;
+
+        Ok(domains
+            .into_iter()
+            .filter_map(|domain| domain.get_uuid_string().ok())
+            .filter_map(|uuid| Uuid::parse_str(&uuid).ok())
+            .collect())
+    }
+
+    fn resize_disk(&self, id: Uuid, path: &str, bytes: u64) -> Result<(), Error> {
+        self.domain(id)?
+            .block_resize(path, bytes, virt::sys::VIR_DOMAIN_BLOCK_RESIZE_BYTES)?;
+        Ok(())
+    }
+
+    fn libvirt_version(&self) -> Option<String> {
+        // Encoded as major * 1,000,000 + minor * 1,000 + release
+        let version = self.conn.lock().unwrap().get_lib_version().ok()?;
+        Some(format!(
+            "{}.{}.{}",
+            version / 1_000_000,
+            version / 1_000 % 1_000,
+            version % 1_000
+        ))
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::Error;
+use std::fmt::Debug;
+use uuid::Uuid;
+
+mod fake;
+#[cfg(feature = "libvirt")]
+mod libvirt;
+
+pub use fake::FakeHypervisor;
+#[cfg(feature = "libvirt")]
+pub use libvirt::Libvirt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainState {
+    Undefined,
+    Running,
+    ShuttingDown,
+    Stopped,
+    Paused,
+    /// The guest suspended itself to memory or disk, it is woken up again by `start`
+    Suspended,
+}
+
+/// A backend capable of running VM domains on the local node.
+///
+/// Domains are keyed by the id of the VM record they belong to.
+pub trait Hypervisor: Debug + Send + Sync {
+    /// Defines (or redefines) a persistent domain from libvirt domain XML.
+    fn define(&self, id: Uuid, xml: &str) -> Result<(), Error>;
+
+    /// Removes the domain definition. The domain must not be running.
+    fn undefine(&self, id: Uuid) -> Result<(), Error>;
+
+    fn start(&self, id: Uuid) -> Result<(), Error>;
+
+    /// Requests a graceful shutdown of the guest.
+    fn stop(&self, id: Uuid) -> Result<(), Error>;
+
+    /// Immediately powers off the domain.
+    fn destroy(&self, id: Uuid) -> Result<(), Error>;
+
+    fn state(&self, id: Uuid) -> Result<DomainState, Error>;
+
+    /// Returns the domain XML as currently defined on this node.
+    fn xml(&self, id: Uuid) -> Result<String, Error>;
+
+    fn list(&self) -> Result<Vec<Uuid>, Error>;
+
+    /// Grows the disk image at `path` of a running domain to `bytes`, so that the guest sees
+    /// the new size. The image is locked while the domain runs, so the hypervisor resizes it.
+    fn resize_disk(&self, id: Uuid, path: &str, bytes: u64) -> Result<(), Error>;
+
+    /// Version of the libvirt library behind the hypervisor, if any.
+    fn libvirt_version(&self) -> Option<String>;
+}
+
+impl From<DomainState> for crate::virtus::virtus_proto::VmState {
+    fn from(val: DomainState) -> Self {
+        use crate::virtus::virtus_proto::VmState;
+
+        match val {
+            DomainState::Undefined => VmState::Undefined,
+            DomainState::Running => VmState::Running,
+            DomainState::ShuttingDown => VmState::ShuttingDown,
+            DomainState::Stopped => VmState::Stopped,
+            DomainState::Paused => VmState::Paused,
+            DomainState::Suspended => VmState::Suspended,
+        }
+    }
+}
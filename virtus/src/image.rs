@@ -0,0 +1,330 @@
+use crate::error::Error;
+use crate::qemu_img::QemuImg;
+use crate::transfer;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use skiff::Client as SkiffClient;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const ISO_MAGIC: &[u8] = b"CD001";
+// The primary volume descriptor starts after 16 2048-byte system sectors
+const ISO_MAGIC_OFFSET: u64 = 0x8001;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Image {
+    id: Uuid,
+    name: Option<String>,
+    // qcow2, raw or iso
+    format: String,
+    // Virtual size in bytes
+    size: u64,
+    // Hex encoded sha256 of the image file
+    checksum: String,
+    os: Option<String>,
+    installer: bool,
+    copies: Vec<ImageCopy>,
+}
+
+/// A copy of an image stored locally on a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageCopy {
+    node_id: Uuid,
+    path: PathBuf,
+}
+
+impl Image {
+    /// Registers the image file at `path` on this node.
+    ///
+    /// If an image with the same checksum is already registered, the file is recorded as
+    /// another copy of that image instead of creating a new one. A name or os given for the
+    /// copy fills in what the image doesn't have yet, and must match otherwise.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn register(
+        node_id: Uuid,
+        path: &str,
+        name: Option<&str>,
+        os: Option<&str>,
+        installer: bool,
+        checksum: Option<&str>,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        // Other nodes and libvirt need to be able to find the file again
+        let path = fs::canonicalize(path)?;
+        let (format, size) = inspect(&path)?;
+
+        // Disks are layered on images, which have to stand on their own
+        if format == "qcow2" && qemu_img.info(&path)?.backing_filename.is_some() {
+            return Err(Error::InvalidImage("image has a backing file".to_string()));
+        }
+
+        // Images are large, so hash them without blocking the runtime
+        let actual = transfer::sha256(&path).await?;
+
+        if let Some(expected) = checksum {
+            if !expected.eq_ignore_ascii_case(&actual) {
+                return Err(Error::InvalidImage(format!(
+                    "checksum mismatch, expected {} got {}",
+                    expected, actual
+                )));
+            }
+        }
+
+        if let Some(mut image) = Image::find_by_checksum(&actual, client).await? {
+            if let Some(name) = name {
+                match &image.name {
+                    Some(existing) if existing != name => return Err(Error::ImageExists),
+                    Some(_) => {}
+                    None => {
+                        if Image::find(name, client).await?.is_some() {
+                            return Err(Error::ImageExists);
+                        }
+                        image.name = Some(name.to_string());
+                    }
+                }
+            }
+
+            if let Some(os) = os {
+                match &image.os {
+                    Some(existing) if existing != os => {
+                        return Err(Error::InvalidImage(format!(
+                            "image is already registered with os {}",
+                            existing
+                        )))
+                    }
+                    _ => image.os = Some(os.to_string()),
+                }
+            }
+
+            image.installer |= installer;
+            image.copies.retain(|copy| copy.node_id != node_id);
+            image.copies.push(ImageCopy { node_id, path });
+            image.commit(client).await?;
+            return Ok(image);
+        }
+
+        if let Some(name) = name {
+            if Image::find(name, client).await?.is_some() {
+                return Err(Error::ImageExists);
+            }
+        }
+
+        let image = Self {
+            id: Uuid::new_v4(),
+            name: name.map(|s| s.to_string()),
+            format,
+            size,
+            checksum: actual,
+            os: os.map(|s| s.to_string()),
+            installer,
+            copies: vec![ImageCopy { node_id, path }],
+        };
+
+        image.commit(client).await?;
+        Ok(image)
+    }
+
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn get_format(&self) -> String {
+        self.format.clone()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_installer(&self) -> bool {
+        self.installer
+    }
+
+    /// Path of the copy on `node_id`, if the node has one
+    pub fn get_path(&self, node_id: Uuid) -> Option<PathBuf> {
+        self.copies
+            .iter()
+            .find(|copy| copy.node_id == node_id)
+            .map(|copy| copy.path.clone())
+    }
+
+    async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("images/{}", self.id).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes the image from the catalog. The image files are left in place.
+    pub async fn delete(self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .remove(format!("images/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<Image>, Error> {
+        let image = client
+            .lock()
+            .await
+            .get::<Image>(format!("images/{}", id).as_str())
+            .await?;
+
+        Ok(image)
+    }
+
+    pub async fn find(
+        name: &str,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Image>, Error> {
+        Ok(Image::list(client)
+            .await?
+            .into_iter()
+            .find(|image| image.name.as_deref() == Some(name)))
+    }
+
+    pub async fn find_by_checksum(
+        checksum: &str,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Image>, Error> {
+        Ok(Image::list(client)
+            .await?
+            .into_iter()
+            .find(|image| image.checksum == checksum))
+    }
+
+    pub async fn list(client: &Arc<Mutex<SkiffClient>>) -> Result<Vec<Image>, Error> {
+        let image_ids = client.lock().await.list_keys("images/").await?;
+
+        let mut images = Vec::new();
+        for image in image_ids {
+            images.push(
+                client
+                    .lock()
+                    .await
+                    .get::<Image>(image.as_str())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        Ok(images)
+    }
+}
+
+/// Detects the format and virtual size of an image file
+fn inspect(path: &Path) -> Result<(String, u64), Error> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+
+    let mut header = [0u8; 32];
+    if length >= header.len() as u64 {
+        file.read_exact(&mut header)?;
+        if &header[..4] == QCOW2_MAGIC {
+            // The virtual size is a big endian u64 at offset 24 of the qcow2 header
+            let size = u64::from_be_bytes(header[24..32].try_into().unwrap());
+            return Ok(("qcow2".to_string(), size));
+        }
+    }
+
+    if length >= ISO_MAGIC_OFFSET + ISO_MAGIC.len() as u64 {
+        let mut magic = [0u8; 5];
+        file.seek(SeekFrom::Start(ISO_MAGIC_OFFSET))?;
+        file.read_exact(&mut magic)?;
+        if magic == ISO_MAGIC {
+            return Ok(("iso".to_string(), length));
+        }
+    }
+
+    Ok(("raw".to_string(), length))
+}
+
+impl From<Image> for virtus_proto::Image {
+    fn from(val: Image) -> Self {
+        virtus_proto::Image {
+            id: val.id.to_string(),
+            name: val.name,
+            format: val.format,
+            size: val.size,
+            checksum: val.checksum,
+            os: val.os,
+            installer: val.installer,
+            copies: val
+                .copies
+                .into_iter()
+                .map(|copy| virtus_proto::ImageCopy {
+                    node: copy.node_id.to_string(),
+                    path: copy.path.to_string_lossy().to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_file(name: &str, contents: &[u8]) -> PathBuf {
+        let dir = Path::new("target/tmp/image");
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn inspect_qcow2() {
+        let mut header = QCOW2_MAGIC.to_vec();
+        header.extend_from_slice(&[0u8; 20]);
+        header.extend_from_slice(&(10u64 << 30).to_be_bytes());
+        let path = write_file("test.qcow2", &header);
+
+        assert_eq!(("qcow2".to_string(), 10 << 30), inspect(&path).unwrap());
+    }
+
+    #[test]
+    fn inspect_iso() {
+        let mut contents = vec![0u8; ISO_MAGIC_OFFSET as usize];
+        contents.extend_from_slice(ISO_MAGIC);
+        contents.extend_from_slice(&[0u8; 2043]);
+        let path = write_file("test.iso", &contents);
+
+        assert_eq!(
+            ("iso".to_string(), contents.len() as u64),
+            inspect(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn inspect_raw() {
+        let path = write_file("test.img", &[1u8; 4096]);
+        assert_eq!(("raw".to_string(), 4096), inspect(&path).unwrap());
+
+        let path = write_file("short.img", b"abc");
+        assert_eq!(("raw".to_string(), 3), inspect(&path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let path = write_file("checksum.img", b"abc");
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            transfer::sha256(&path).await.unwrap()
+        );
+    }
+}
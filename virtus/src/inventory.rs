@@ -0,0 +1,367 @@
+use crate::error::Error;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use skiff::Client as SkiffClient;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// A NUMA node of the host
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NumaNode {
+    id: u32,
+    cpus: Vec<u32>,
+    memory_total: u64,
+    memory_free: u64,
+}
+
+/// Reserved huge pages of one page size
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HugePages {
+    // Bytes per page
+    size: u64,
+    total: u64,
+    free: u64,
+}
+
+/// Hardware and software of a node, published for scheduling and capacity planning.
+///
+/// Kept apart from the node record like heartbeats, as free memory changes all the time.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Inventory {
+    node: Uuid,
+    cpu_model: String,
+    // Logical CPUs
+    cpus: u32,
+    sockets: u32,
+    numa_nodes: Vec<NumaNode>,
+    // Bytes
+    memory_total: u64,
+    memory_free: u64,
+    hugepages: Vec<HugePages>,
+    kvm: bool,
+    kernel_version: String,
+    qemu_img_version: Option<String>,
+    libvirt_version: Option<String>,
+}
+
+impl Inventory {
+    /// Reads the host from `/proc`, `/sys` and `/dev` below `root`, which is `/` outside of
+    /// tests. Only `/proc/cpuinfo` and `/proc/meminfo` are required, everything else is
+    /// left empty when the host doesn't have it.
+    pub fn discover(node: Uuid, root: &Path) -> Result<Self, Error> {
+        let cpuinfo = read(root, "proc/cpuinfo")?.ok_or_else(|| not_found(root, "proc/cpuinfo"))?;
+        let meminfo = read(root, "proc/meminfo")?.ok_or_else(|| not_found(root, "proc/meminfo"))?;
+
+        let mut cpu_model = String::new();
+        let mut cpus = 0;
+        let mut sockets = HashSet::new();
+        for (key, value) in cpuinfo.lines().filter_map(|line| line.split_once(':')) {
+            match key.trim() {
+                "processor" => cpus += 1,
+                "model name" if cpu_model.is_empty() => cpu_model = value.trim().to_string(),
+                "physical id" => {
+                    sockets.insert(value.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+
+        let memory_total = kib(&meminfo, "MemTotal").unwrap_or(0);
+        // MemFree leaves out caches the kernel gives up under pressure
+        let memory_free = kib(&meminfo, "MemAvailable")
+            .or_else(|| kib(&meminfo, "MemFree"))
+            .unwrap_or(0);
+
+        Ok(Self {
+            node,
+            cpu_model,
+            cpus,
+            // Not every architecture reports sockets, assume one
+            sockets: match sockets.len() as u32 {
+                0 if cpus > 0 => 1,
+                sockets => sockets,
+            },
+            numa_nodes: numa_nodes(root)?,
+            memory_total,
+            memory_free,
+            hugepages: hugepages(root)?,
+            kvm: root.join("dev/kvm").exists(),
+            kernel_version: read(root, "proc/sys/kernel/osrelease")?
+                .map(|release| release.trim().to_string())
+                .unwrap_or_default(),
+            qemu_img_version: None,
+            libvirt_version: None,
+        })
+    }
+
+    /// Adds the versions of the tools virtus drives, which aren't found below the root
+    pub fn with_versions(mut self, qemu_img: Option<String>, libvirt: Option<String>) -> Self {
+        self.qemu_img_version = qemu_img;
+        self.libvirt_version = libvirt;
+        self
+    }
+
+    pub async fn publish(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("inventory/{}", self.node).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(
+        node: Uuid,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Inventory>, Error> {
+        let inventory = client
+            .lock()
+            .await
+            .get::<Inventory>(format!("inventory/{}", node).as_str())
+            .await?;
+
+        Ok(inventory)
+    }
+
+    pub async fn delete(node: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .remove(format!("inventory/{}", node).as_str())
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Reads `path` below `root`, `None` if it doesn't exist
+fn read(root: &Path, path: &str) -> Result<Option<String>, Error> {
+    match fs::read_to_string(root.join(path)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn not_found(root: &Path, path: &str) -> Error {
+    Error::IOError(std::io::Error::new(
+        ErrorKind::NotFound,
+        format!("{} not found", root.join(path).display()),
+    ))
+}
+
+/// Bytes of a `Key:  1234 kB` line, as in `/proc/meminfo`. Lines may carry a prefix like
+/// `Node 0` in the per-node meminfo.
+fn kib(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.split_whitespace().last()? != key {
+            return None;
+        }
+
+        let kib: u64 = value.split_whitespace().next()?.parse().ok()?;
+        Some(kib * 1024)
+    })
+}
+
+/// Parses a CPU list like `0-3,8,10-11`
+fn cpu_list(list: &str) -> Vec<u32> {
+    list.trim()
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some((start.parse().ok()?..=end.parse().ok()?).collect()),
+            None => range.parse().ok().map(|cpu| vec![cpu]),
+        })
+        .flatten()
+        .collect()
+}
+
+/// Numbered entries of a directory below `root`, like `node0` or `hugepages-2048kB`
+fn entries(root: &Path, dir: &str, prefix: &str, suffix: &str) -> Result<Vec<u64>, Error> {
+    let entries = match fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut numbers = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(number) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_suffix(suffix))
+            .and_then(|number| number.parse().ok())
+        {
+            numbers.push(number);
+        }
+    }
+
+    numbers.sort();
+    Ok(numbers)
+}
+
+fn numa_nodes(root: &Path) -> Result<Vec<NumaNode>, Error> {
+    let mut nodes = Vec::new();
+    for id in entries(root, "sys/devices/system/node", "node", "")? {
+        let dir = format!("sys/devices/system/node/node{}", id);
+        let meminfo = read(root, &format!("{}/meminfo", dir))?.unwrap_or_default();
+
+        nodes.push(NumaNode {
+            id: id as u32,
+            cpus: cpu_list(&read(root, &format!("{}/cpulist", dir))?.unwrap_or_default()),
+            memory_total: kib(&meminfo, "MemTotal").unwrap_or(0),
+            memory_free: kib(&meminfo, "MemFree").unwrap_or(0),
+        });
+    }
+
+    Ok(nodes)
+}
+
+fn hugepages(root: &Path) -> Result<Vec<HugePages>, Error> {
+    let mut pools = Vec::new();
+    for size in entries(root, "sys/kernel/mm/hugepages", "hugepages-", "kB")? {
+        let dir = format!("sys/kernel/mm/hugepages/hugepages-{}kB", size);
+        let count = |file: &str| -> Result<u64, Error> {
+            Ok(read(root, &format!("{}/{}", dir, file))?
+                .and_then(|count| count.trim().parse().ok())
+                .unwrap_or(0))
+        };
+
+        pools.push(HugePages {
+            size: size * 1024,
+            total: count("nr_hugepages")?,
+            free: count("free_hugepages")?,
+        });
+    }
+
+    Ok(pools)
+}
+
+impl From<Inventory> for virtus_proto::Inventory {
+    fn from(val: Inventory) -> Self {
+        virtus_proto::Inventory {
+            cpu_model: val.cpu_model,
+            cpus: val.cpus,
+            sockets: val.sockets,
+            numa_nodes: val
+                .numa_nodes
+                .into_iter()
+                .map(|node| virtus_proto::NumaNode {
+                    id: node.id,
+                    cpus: node.cpus,
+                    memory_total: node.memory_total,
+                    memory_free: node.memory_free,
+                })
+                .collect(),
+            memory_total: val.memory_total,
+            memory_free: val.memory_free,
+            hugepages: val
+                .hugepages
+                .into_iter()
+                .map(|pages| virtus_proto::HugePages {
+                    size: pages.size,
+                    total: pages.total,
+                    free: pages.free,
+                })
+                .collect(),
+            kvm: val.kvm,
+            kernel_version: val.kernel_version,
+            qemu_img_version: val.qemu_img_version,
+            libvirt_version: val.libvirt_version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn discover_numa_host() {
+        let node = Uuid::new_v4();
+        let inventory = Inventory::discover(node, Path::new("testdata/host/numa")).unwrap();
+
+        assert_eq!(
+            "Intel(R) Xeon(R) Silver 4110 CPU @ 2.10GHz",
+            inventory.cpu_model
+        );
+        assert_eq!(4, inventory.cpus);
+        assert_eq!(2, inventory.sockets);
+        assert_eq!(64 * GIB, inventory.memory_total);
+        assert_eq!(48 * GIB, inventory.memory_free);
+        assert!(inventory.kvm);
+        assert_eq!("6.8.0-45-generic", inventory.kernel_version);
+
+        assert_eq!(
+            vec![
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1],
+                    memory_total: 32 * GIB,
+                    memory_free: 20 * GIB,
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![2, 3],
+                    memory_total: 32 * GIB,
+                    memory_free: 24 * GIB,
+                },
+            ],
+            inventory.numa_nodes
+        );
+
+        assert_eq!(
+            vec![
+                HugePages {
+                    size: 2 * 1024 * 1024,
+                    total: 512,
+                    free: 256,
+                },
+                HugePages {
+                    size: GIB,
+                    total: 4,
+                    free: 4,
+                },
+            ],
+            inventory.hugepages
+        );
+
+        let inventory = inventory.with_versions(Some("8.2.2".to_string()), None);
+        assert_eq!(Some("8.2.2".to_string()), inventory.qemu_img_version);
+        assert_eq!(None, inventory.libvirt_version);
+    }
+
+    #[test]
+    fn discover_minimal_host() {
+        // No sockets, NUMA, hugepages, KVM or MemAvailable, like in some containers
+        let inventory =
+            Inventory::discover(Uuid::new_v4(), Path::new("testdata/host/minimal")).unwrap();
+
+        assert_eq!("", inventory.cpu_model);
+        assert_eq!(2, inventory.cpus);
+        assert_eq!(1, inventory.sockets);
+        assert_eq!(GIB, inventory.memory_total);
+        assert_eq!(GIB / 2, inventory.memory_free);
+        assert!(inventory.numa_nodes.is_empty());
+        assert!(inventory.hugepages.is_empty());
+        assert!(!inventory.kvm);
+        assert_eq!("", inventory.kernel_version);
+
+        assert!(Inventory::discover(Uuid::new_v4(), Path::new("testdata/host/none")).is_err());
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(vec![0, 1, 2, 3, 8, 10, 11], cpu_list("0-3,8,10-11\n"));
+        assert_eq!(Vec::<u32>::new(), cpu_list(""));
+    }
+}
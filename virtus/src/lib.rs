@@ -1,10 +1,36 @@
 mod builder;
+mod config;
 mod disk;
+mod domain;
 mod error;
+mod handle;
+mod heartbeat;
+mod hypervisor;
+mod image;
+mod inventory;
+mod network;
 mod node;
 mod pool;
+mod qemu_img;
+mod reconcile;
+mod routing;
+mod transfer;
 mod virtus;
+mod vm;
 
 pub use builder::Builder;
+pub use config::{Config, PoolConfig, TlsConfig};
+pub use domain::{
+    Console, DiskBus, DomainBuilder, DomainDisk, DomainInterface, DomainSpec, Graphics,
+    InterfaceSource,
+};
 pub use error::Error;
+pub use handle::Handle;
+#[cfg(feature = "libvirt")]
+pub use hypervisor::Libvirt;
+pub use hypervisor::{DomainState, FakeHypervisor, Hypervisor};
+pub use qemu_img::{
+    CheckResult, FakeQemuImgRunner, ImageInfo, QemuImg, QemuImgRunner, RunOutput, SnapshotInfo,
+    SnapshotOp, SystemRunner,
+};
 pub use virtus::Virtus;
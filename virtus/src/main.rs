@@ -1,11 +1,34 @@
+use std::collections::HashMap;
+use tokio::signal::unix::{signal, SignalKind};
+
 #[tokio::main]
 pub async fn main() -> Result<(), anyhow::Error> {
-    let virtus = virtus::Builder::new()
-        .set_dir("/tmp/virtus")
-        .bind("0.0.0.0".parse()?)
-        .build()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let env: HashMap<String, String> = std::env::vars().collect();
+    let config = virtus::Config::load(&args, &env)?;
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+
+    let virtus = virtus::Builder::from_config(&config)?.build()?;
+
+    let handle = virtus.start().await?;
+    handle.ready().await?;
 
-    virtus.start().await?;
+    shutdown_signal().await?;
+    log::info!("Shutting down");
+    handle.shutdown().await?;
+
+    Ok(())
+}
 
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() -> Result<(), anyhow::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
     Ok(())
 }
@@ -0,0 +1,209 @@
+use crate::error::Error;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use skiff::Client as SkiffClient;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+const DEFAULT_BRIDGE: &str = "virtus-br";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Network {
+    id: Uuid,
+    name: Option<String>,
+    // Tagged networks need `bridge_name` to be an Open vSwitch bridge, libvirt can't tag ports
+    // of a linux bridge
+    vlan: u32,
+    external: bool,
+    // Will need to handle uplink being different on different hosts, tuple with node id, int name?
+    bridge_name: String,
+    cidr4: Option<String>,
+    interfaces: Vec<Uuid>,
+}
+
+impl Network {
+    pub async fn create(
+        name: Option<&str>,
+        vlan: Option<u32>,
+        cidr4: Option<&str>,
+        physical_uplink: Option<&str>,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        let vlan = vlan.unwrap_or(0);
+        if vlan > 4094 {
+            return Err(Error::InvalidNetwork(format!("invalid vlan {}", vlan)));
+        }
+
+        if let Some(cidr) = cidr4 {
+            validate_cidr4(cidr)?;
+        }
+
+        if let Some(name) = name {
+            if Network::find(name, client).await?.is_some() {
+                return Err(Error::NetworkExists);
+            }
+        }
+
+        if let Some(uplink) = physical_uplink {
+            if Network::get_external_network(uplink, client)
+                .await?
+                .is_some()
+            {
+                return Err(Error::PhysicalNetworkExists);
+            }
+        }
+
+        let network = Self {
+            id: Uuid::new_v4(),
+            name: name.map(|s| s.to_string()),
+            vlan,
+            external: physical_uplink.is_some(),
+            bridge_name: physical_uplink.unwrap_or(DEFAULT_BRIDGE).to_string(),
+            cidr4: cidr4.map(|s| s.to_string()),
+            interfaces: vec![],
+        };
+
+        network.commit(client).await?;
+        Ok(network)
+    }
+
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn get_bridge_name(&self) -> String {
+        self.bridge_name.clone()
+    }
+
+    /// The vlan tag of the network, 0 if untagged
+    pub fn get_vlan(&self) -> u32 {
+        self.vlan
+    }
+
+    /// Records an interface of `vm` on the network, which keeps the network from being removed
+    pub async fn attach(
+        &mut self,
+        vm: Uuid,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        if !self.interfaces.contains(&vm) {
+            self.interfaces.push(vm);
+            self.commit(client).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn detach(
+        &mut self,
+        vm: Uuid,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        self.interfaces.retain(|id| *id != vm);
+        self.commit(client).await
+    }
+
+    async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("networks/{}", self.id).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        if !self.interfaces.is_empty() {
+            return Err(Error::NetworkInUse);
+        }
+
+        client
+            .lock()
+            .await
+            .remove(format!("networks/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<Network>, Error> {
+        let network = client
+            .lock()
+            .await
+            .get::<Network>(format!("networks/{}", id).as_str())
+            .await?;
+
+        Ok(network)
+    }
+
+    pub async fn find(
+        name: &str,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Network>, Error> {
+        Ok(Network::list(client)
+            .await?
+            .into_iter()
+            .find(|network| network.name.as_deref() == Some(name)))
+    }
+
+    pub async fn get_external_network(
+        uplink: &str,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Option<Network>, Error> {
+        Ok(Network::list(client)
+            .await?
+            .into_iter()
+            .find(|network| network.external && network.bridge_name == uplink))
+    }
+
+    pub async fn list(client: &Arc<Mutex<SkiffClient>>) -> Result<Vec<Network>, Error> {
+        let network_ids = client.lock().await.list_keys("networks/").await?;
+
+        let mut networks = Vec::new();
+        for network in network_ids {
+            networks.push(
+                client
+                    .lock()
+                    .await
+                    .get::<Network>(network.as_str())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        Ok(networks)
+    }
+}
+
+fn validate_cidr4(cidr: &str) -> Result<(), Error> {
+    let invalid = || Error::InvalidNetwork(format!("invalid cidr4 {}", cidr));
+
+    let (address, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+    address.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+    match prefix.parse::<u8>() {
+        Ok(prefix) if prefix <= 32 => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+impl From<Network> for virtus_proto::Network {
+    fn from(val: Network) -> Self {
+        virtus_proto::Network {
+            id: val.id.to_string(),
+            name: val.name,
+            vlan: val.vlan,
+            external: val.external,
+            bridge_name: val.bridge_name,
+            cidr4: val.cidr4,
+            interfaces: val
+                .interfaces
+                .into_iter()
+                .map(|id| id.to_string())
+                .collect(),
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use crate::error::Error;
-use crate::pool::Pool;
-use crate::virtus::virtus_proto;
+use crate::network::Network;
+use crate::pool::{one_or_many, BackendKind, Pool};
+use crate::virtus::{virtus_proto, DEFAULT_PORT};
+use crate::vm::VM;
 use serde::{Deserialize, Serialize};
 use skiff::Client as SkiffClient;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use uuid::Uuid;
@@ -11,23 +13,37 @@ use uuid::Uuid;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Node {
     id: Uuid,
-    address: Ipv4Addr,
+    // Where the other nodes reach this one, which may differ from where it listens. Tried in
+    // order. Older records have a single `address` and no port.
+    #[serde(alias = "address", deserialize_with = "one_or_many")]
+    addresses: Vec<IpAddr>,
+    #[serde(default = "default_port")]
+    port: u16,
     hostname: String,
     pools: Vec<Uuid>,
+    #[serde(default)]
+    vms: Vec<Uuid>,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
 }
 
 impl Node {
     pub async fn create(
         id: Uuid,
         hostname: &str,
-        address: Ipv4Addr,
+        addresses: Vec<IpAddr>,
+        port: u16,
         client: &Arc<Mutex<SkiffClient>>,
     ) -> Result<Self, Error> {
         let node = Self {
             id,
             hostname: String::from(hostname),
-            address,
+            addresses,
+            port,
             pools: vec![],
+            vms: vec![],
         };
 
         node.commit(client).await?;
@@ -38,8 +54,34 @@ impl Node {
         self.id
     }
 
-    pub fn get_addr(&self) -> Ipv4Addr {
-        self.address
+    pub fn get_addrs(&self) -> Vec<IpAddr> {
+        self.addresses.clone()
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Updates the addresses and port the other nodes use to reach this one, e.g. after a
+    /// restart with a new configuration
+    pub async fn advertise(
+        &mut self,
+        addresses: Vec<IpAddr>,
+        port: u16,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        if self.addresses == addresses && self.port == port {
+            return Ok(());
+        }
+
+        self.addresses = addresses;
+        self.port = port;
+        self.commit(client).await
+    }
+
+    /// Whether any pools or VMs still live on this node
+    pub fn has_resources(&self) -> bool {
+        !self.pools.is_empty() || !self.vms.is_empty()
     }
 
     async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
@@ -52,6 +94,20 @@ impl Node {
         Ok(())
     }
 
+    pub async fn delete(self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        if self.has_resources() {
+            return Err(Error::NodeNotEmpty);
+        }
+
+        client
+            .lock()
+            .await
+            .remove(format!("nodes/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<Node>, Error> {
         let node = client
             .lock()
@@ -81,18 +137,55 @@ impl Node {
         Ok(nodes)
     }
 
+    /// Creates a pool on this node. Shared pools are also listed on the `other` nodes that
+    /// can access them.
     pub async fn create_pool(
         &mut self,
         path: &str,
         name: Option<&str>,
+        backend: BackendKind,
+        other: &[Uuid],
         client: &Arc<Mutex<SkiffClient>>,
     ) -> Result<Pool, Error> {
-        let pool = Pool::create(self.id, path, name, client).await?;
+        let mut nodes = vec![self.id];
+        nodes.extend(other.iter().filter(|id| **id != self.id));
+
+        let pool = Pool::create(nodes, path, name, backend, client).await?;
         self.pools.push(pool.get_id());
         self.commit(client).await?;
+
+        for id in other.iter().filter(|id| **id != self.id) {
+            let mut node = Node::get(*id, client).await?.ok_or(Error::NodeNotFound)?;
+            node.pools.push(pool.get_id());
+            node.commit(client).await?;
+        }
+
         Ok(pool)
     }
 
+    pub async fn remove_pool(
+        &mut self,
+        pool: Pool,
+        cascade: bool,
+        remove_dir: bool,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        let id = pool.get_id();
+        let nodes = pool.get_nodes();
+        pool.delete(cascade, remove_dir, client).await?;
+        self.pools.retain(|pool| *pool != id);
+        self.commit(client).await?;
+
+        for node in nodes.into_iter().filter(|node| *node != self.id) {
+            if let Some(mut node) = Node::get(node, client).await? {
+                node.pools.retain(|pool| *pool != id);
+                node.commit(client).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn list_pools(&self, client: Arc<Mutex<SkiffClient>>) -> Result<Vec<Pool>, Error> {
         let mut pools = Vec::<Pool>::new();
         for pool in &self.pools {
@@ -101,15 +194,132 @@ impl Node {
 
         Ok(pools)
     }
+
+    pub async fn create_vm(
+        &mut self,
+        name: &str,
+        cpus: u32,
+        memory: u64,
+        disks: Vec<Uuid>,
+        interfaces: Vec<Uuid>,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<VM, Error> {
+        let vm = VM::create(self.id, name, cpus, memory, disks, interfaces, client).await?;
+        self.vms.push(vm.get_id());
+        self.commit(client).await?;
+
+        for id in vm.get_interfaces() {
+            if let Some(mut network) = Network::get(id, client).await? {
+                network.attach(vm.get_id(), client).await?;
+            }
+        }
+
+        Ok(vm)
+    }
+
+    pub async fn remove_vm(
+        &mut self,
+        vm: VM,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        self.vms.retain(|id| *id != vm.get_id());
+
+        for id in vm.get_interfaces() {
+            if let Some(mut network) = Network::get(id, client).await? {
+                network.detach(vm.get_id(), client).await?;
+            }
+        }
+
+        vm.delete(client).await?;
+        self.commit(client).await?;
+        Ok(())
+    }
+
+    pub async fn list_vms(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<Vec<VM>, Error> {
+        let mut vms = Vec::<VM>::new();
+        for vm in &self.vms {
+            if let Some(vm) = VM::get(*vm, client).await? {
+                vms.push(vm);
+            }
+        }
+
+        Ok(vms)
+    }
 }
 
 impl From<Node> for virtus_proto::Node {
     fn from(val: Node) -> Self {
         virtus_proto::Node {
             id: val.id.to_string(),
-            ip: val.address.to_string(),
+            ip: val
+                .addresses
+                .first()
+                .map(|address| address.to_string())
+                .unwrap_or_default(),
+            ips: val
+                .addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            port: val.port as u32,
             hostname: val.hostname,
             pools: val.pools.into_iter().map(|p| p.to_string()).collect(),
+            vms: val.vms.into_iter().map(|v| v.to_string()).collect(),
+            // Filled in from the node's heartbeat by the leader
+            status: virtus_proto::NodeStatus::Unknown.into(),
+            heartbeat: None,
+            inventory: None,
         }
     }
 }
+
+/// The address skiff knows a node by. Skiff only speaks IPv4, so this is the first IPv4 (or
+/// IPv4-mapped IPv6) address.
+pub fn skiff_address(addresses: &[IpAddr]) -> Option<Ipv4Addr> {
+    addresses.iter().find_map(|address| match address {
+        IpAddr::V4(address) => Some(*address),
+        IpAddr::V6(address) => address.to_ipv4_mapped(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skiff_address_prefers_ipv4() {
+        let parse = |addresses: &[&str]| -> Vec<IpAddr> {
+            addresses.iter().map(|a| a.parse().unwrap()).collect()
+        };
+
+        assert_eq!(
+            Some(Ipv4Addr::new(10, 0, 0, 2)),
+            skiff_address(&parse(&["fd00::2", "10.0.0.2"]))
+        );
+        assert_eq!(
+            Some(Ipv4Addr::new(10, 0, 0, 3)),
+            skiff_address(&parse(&["::ffff:10.0.0.3"]))
+        );
+        assert_eq!(None, skiff_address(&parse(&["fd00::2"])));
+        assert_eq!(None, skiff_address(&[]));
+    }
+
+    #[test]
+    fn deserialize_old_record() {
+        let json = format!(
+            r#"{{"id":"{}","address":"10.0.0.2","hostname":"old","pools":[]}}"#,
+            Uuid::new_v4()
+        );
+
+        let node: Node = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            vec![IpAddr::from(Ipv4Addr::new(10, 0, 0, 2))],
+            node.addresses
+        );
+        assert_eq!(DEFAULT_PORT, node.port);
+        assert!(node.vms.is_empty());
+
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(node, serde_json::from_str(&json).unwrap());
+    }
+}
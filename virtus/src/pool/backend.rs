@@ -0,0 +1,179 @@
+use crate::error::Error;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Storage behind a pool directory.
+///
+/// The pool record keeps the path, the backend decides how the directory is set up and torn
+/// down, and whether more than one node can reach it.
+pub trait PoolBackend: Debug + Send + Sync {
+    /// Whether several nodes can access the pool at the same path
+    fn is_shared(&self) -> bool;
+
+    /// Makes the pool directory usable on this node when the pool is created.
+    fn prepare(&self, path: &Path) -> Result<(), Error>;
+
+    /// Returns the total and used bytes of the storage holding the pool.
+    fn usage(&self, path: &Path) -> Result<(u64, u64), Error>;
+
+    /// Cleans up the (empty) pool directory after the pool is deleted.
+    fn remove(&self, path: &Path) -> Result<(), Error>;
+}
+
+/// Which `PoolBackend` a pool uses, as stored in the pool record
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    /// A local directory, only reachable from the node it was created on
+    #[default]
+    Directory,
+    /// A directory mounted at the same path on several nodes, like an NFS export
+    Shared,
+}
+
+impl BackendKind {
+    pub fn backend(&self) -> &'static dyn PoolBackend {
+        match self {
+            BackendKind::Directory => &Directory,
+            BackendKind::Shared => &Shared,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Directory;
+
+impl PoolBackend for Directory {
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    fn prepare(&self, path: &Path) -> Result<(), Error> {
+        fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn usage(&self, path: &Path) -> Result<(u64, u64), Error> {
+        statvfs(path)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        match fs::remove_dir(path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Shared;
+
+impl PoolBackend for Shared {
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    /// The directory is mounted by the operator. Creating it here would hide a missing mount
+    /// behind an empty local directory.
+    fn prepare(&self, path: &Path) -> Result<(), Error> {
+        match path.is_dir() {
+            true => Ok(()),
+            false => Err(Error::PoolUnavailable(path.display().to_string())),
+        }
+    }
+
+    fn usage(&self, path: &Path) -> Result<(u64, u64), Error> {
+        statvfs(path)
+    }
+
+    /// Mount points are left alone, other nodes may still have the storage mounted.
+    fn remove(&self, _path: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl From<BackendKind> for virtus_proto::PoolBackend {
+    fn from(val: BackendKind) -> Self {
+        match val {
+            BackendKind::Directory => virtus_proto::PoolBackend::Directory,
+            BackendKind::Shared => virtus_proto::PoolBackend::Shared,
+        }
+    }
+}
+
+impl From<virtus_proto::PoolBackend> for BackendKind {
+    fn from(val: virtus_proto::PoolBackend) -> Self {
+        match val {
+            virtus_proto::PoolBackend::Directory => BackendKind::Directory,
+            virtus_proto::PoolBackend::Shared => BackendKind::Shared,
+        }
+    }
+}
+
+/// Returns the total and used bytes of the filesystem containing `path`
+fn statvfs(path: &Path) -> Result<(u64, u64), Error> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::IOError(io::Error::new(ErrorKind::InvalidInput, e)))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // Safety: path is a valid nul-terminated string and stat is only read on success
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        stat.assume_init()
+    };
+
+    let block_size = stat.f_frsize as u64;
+    let total = stat.f_blocks as u64 * block_size;
+    let used = (stat.f_blocks - stat.f_bfree) as u64 * block_size;
+    Ok((total, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statvfs_reports_usage() {
+        let (total, used) = statvfs(Path::new(".")).unwrap();
+        assert!(total > 0);
+        assert!(used <= total);
+
+        assert!(statvfs(Path::new("/nonexistent/virtus")).is_err());
+    }
+
+    #[test]
+    fn shared_requires_mount() {
+        let dir = Path::new("target/tmp/backend/shared");
+        let _ = fs::remove_dir_all(dir);
+
+        let shared = BackendKind::Shared.backend();
+        assert!(shared.is_shared());
+        assert!(matches!(
+            shared.prepare(dir),
+            Err(Error::PoolUnavailable(_))
+        ));
+        assert!(!dir.exists());
+
+        let directory = BackendKind::Directory.backend();
+        assert!(!directory.is_shared());
+        directory.prepare(dir).unwrap();
+        assert!(dir.is_dir());
+        shared.prepare(dir).unwrap();
+
+        // Only the directory backend removes what it created
+        shared.remove(dir).unwrap();
+        assert!(dir.is_dir());
+        directory.remove(dir).unwrap();
+        assert!(!dir.exists());
+        directory.remove(dir).unwrap();
+    }
+}
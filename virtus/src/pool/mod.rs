@@ -0,0 +1,470 @@
+use crate::disk::{Disk, DiskFormat, DiskOptions, Resizer, GIB};
+use crate::error::Error;
+use crate::qemu_img::QemuImg;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Deserializer, Serialize};
+use skiff::Client as SkiffClient;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+mod backend;
+
+pub use backend::{BackendKind, PoolBackend};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pool {
+    id: Uuid,
+    // Nodes with access to the pool, starting with the one it was created on. Only shared
+    // pools have more than one. Older records have a single `node_id`.
+    #[serde(alias = "node_id", deserialize_with = "one_or_many")]
+    nodes: Vec<Uuid>,
+    #[serde(default)]
+    backend: BackendKind,
+    name: Option<String>,
+    path: String,
+    disks: Vec<Uuid>,
+    // Capacity of the filesystem holding the pool, as of the last change on any of its nodes.
+    // Records from before capacity tracking start at 0 until the next refresh.
+    #[serde(default)]
+    total_bytes: u64,
+    #[serde(default)]
+    used_bytes: u64,
+    // Sum of the virtual sizes of the pool's disks, recomputed on every refresh
+    #[serde(default)]
+    allocated_bytes: u64,
+}
+
+/// Deserializes a list that older records stored as a single value
+pub(crate) fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl Pool {
+    /// Creates a pool reachable from `nodes`, preparing the directory on the first one. The
+    /// other nodes of a shared pool are expected to have the storage mounted at `path` too;
+    /// the reconciler reports them otherwise.
+    pub async fn create(
+        nodes: Vec<Uuid>,
+        path: &str,
+        name: Option<&str>,
+        backend: BackendKind,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        if nodes.is_empty() {
+            return Err(Error::InvalidPool(
+                "a pool needs at least one node".to_string(),
+            ));
+        }
+        if nodes.len() > 1 && !backend.backend().is_shared() {
+            return Err(Error::InvalidPool(
+                "only shared pools can have more than one node".to_string(),
+            ));
+        }
+
+        let mut pool = Self {
+            id: Uuid::new_v4(),
+            nodes,
+            backend,
+            name: name.map(|s| s.to_string()),
+            path: path.to_string(),
+            disks: vec![],
+            total_bytes: 0,
+            used_bytes: 0,
+            allocated_bytes: 0,
+        };
+
+        pool.backend().prepare(Path::new(path))?;
+        pool.refresh(client).await?;
+
+        pool.commit(client).await?;
+        Ok(pool)
+    }
+
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    /// The node the pool was created on
+    pub fn get_node_id(&self) -> Uuid {
+        self.nodes[0]
+    }
+
+    pub fn get_nodes(&self) -> Vec<Uuid> {
+        self.nodes.clone()
+    }
+
+    /// Whether the pool's storage is reachable from `node`
+    pub fn has_node(&self, node: Uuid) -> bool {
+        self.nodes.contains(&node)
+    }
+
+    pub fn backend(&self) -> &'static dyn PoolBackend {
+        self.backend.backend()
+    }
+
+    pub fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn get_disks(&self) -> Vec<Uuid> {
+        self.disks.clone()
+    }
+
+    /// Where the image of disk `id` is stored in this pool
+    pub fn disk_path(&self, id: Uuid, format: DiskFormat) -> PathBuf {
+        Path::new(&self.path).join(format!("{}.{}", id, format.extension()))
+    }
+
+    /// Where an incomplete upload to disk `id` is kept
+    pub fn upload_path(&self, id: Uuid) -> PathBuf {
+        Path::new(&self.path).join(format!("{}.upload", id))
+    }
+
+    /// Updates the filesystem usage from the pool directory, and the allocation from the
+    /// records of the pool's disks. Only meaningful on a node with access to the pool.
+    pub async fn refresh(&mut self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        let (total, used) = self.backend().usage(Path::new(&self.path))?;
+        self.total_bytes = total;
+        self.used_bytes = used;
+        self.refresh_allocation(client).await
+    }
+
+    /// Updates the allocation from the records of the pool's disks
+    async fn refresh_allocation(&mut self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        let mut allocated = 0;
+        for id in &self.disks {
+            if let Some(disk) = Disk::get(*id, client).await? {
+                allocated += disk.get_size();
+            }
+        }
+        self.allocated_bytes = allocated;
+
+        Ok(())
+    }
+
+    /// Checks whether `bytes` more can be allocated without going over the overcommit ratio
+    pub fn check_capacity(&self, bytes: u64, overcommit: f64) -> Result<(), Error> {
+        let limit = self.total_bytes as f64 * overcommit;
+        match self.allocated_bytes.saturating_add(bytes) as f64 > limit {
+            true => Err(Error::PoolFull),
+            false => Ok(()),
+        }
+    }
+
+    async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("pools/{}", self.id).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the pool record.
+    ///
+    /// Fails if the pool still has disks unless `cascade` is set, in which case every disk
+    /// file and record is deleted first. With `remove_dir`, the (now empty) pool directory
+    /// is cleaned up by the backend as well.
+    pub async fn delete(
+        mut self,
+        cascade: bool,
+        remove_dir: bool,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        if !self.disks.is_empty() && !cascade {
+            return Err(Error::PoolNotEmpty);
+        }
+
+        for id in self.disks.clone() {
+            match Disk::get(id, client).await? {
+                Some(disk) => self.remove_disk(disk, client).await?,
+                None => {
+                    self.disks.retain(|disk| *disk != id);
+                    self.commit(client).await?;
+                }
+            }
+        }
+
+        if remove_dir {
+            self.backend().remove(Path::new(&self.path))?;
+        }
+
+        client
+            .lock()
+            .await
+            .remove(format!("pools/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<Pool>, Error> {
+        let pool = client
+            .lock()
+            .await
+            .get::<Pool>(format!("pools/{}", id).as_str())
+            .await?;
+
+        Ok(pool)
+    }
+
+    pub async fn list(client: &Arc<Mutex<SkiffClient>>) -> Result<Vec<Pool>, Error> {
+        let pool_ids = client.lock().await.list_keys("pools/").await?;
+
+        let mut pools = Vec::new();
+        for pool in pool_ids {
+            pools.push(
+                client
+                    .lock()
+                    .await
+                    .get::<Pool>(pool.as_str())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        Ok(pools)
+    }
+
+    pub async fn create_disk(
+        &mut self,
+        size: usize,
+        name: Option<&str>,
+        options: &DiskOptions,
+        overcommit: f64,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Disk, Error> {
+        let disk = Disk::create(self.id, size, name, options, overcommit, qemu_img, client).await?;
+        if !self.disks.contains(&disk.get_id()) {
+            self.disks.push(disk.get_id());
+        }
+        self.refresh(client).await?;
+        self.commit(client).await?;
+        Ok(disk)
+    }
+
+    /// Resizes one of the pool's disks, see `Disk::resize`. Growing is subject to the same
+    /// overcommit limit as creating disks.
+    pub async fn resize_disk(
+        &mut self,
+        disk: &mut Disk,
+        size_gb: usize,
+        force: bool,
+        overcommit: f64,
+        resizer: Resizer<'_>,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        let old_size = disk.get_size();
+        let new_size = (size_gb as u64).saturating_mul(GIB);
+
+        self.refresh(client).await?;
+        if new_size > old_size {
+            self.check_capacity(new_size - old_size, overcommit)?;
+        }
+
+        disk.resize(size_gb, force, resizer, client).await?;
+
+        self.refresh(client).await?;
+        self.commit(client).await
+    }
+
+    /// Reverts one of the pool's disks to a snapshot, which may change its size
+    pub async fn revert_disk_snapshot(
+        &mut self,
+        disk: &mut Disk,
+        name: &str,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        disk.revert_snapshot(name, qemu_img, client).await?;
+
+        self.refresh(client).await?;
+        self.commit(client).await
+    }
+
+    /// Adds the image received from another pool to this one, see `Disk::relocate`. A moved
+    /// disk leaves its old pool in the same step, the image it left behind is deleted
+    /// afterwards with `release_disk`.
+    ///
+    /// Runs on the leader, which may not have access to either pool, so only the allocation
+    /// of the pools is updated. Committing the same transfer again changes nothing.
+    pub async fn receive_disk(
+        &mut self,
+        source: &Disk,
+        id: Uuid,
+        name: Option<&str>,
+        flattened: bool,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Disk, Error> {
+        let disk = source
+            .relocate(id, self.id, name, flattened, client)
+            .await?;
+        if !self.disks.contains(&id) {
+            self.disks.push(id);
+        }
+        self.refresh_allocation(client).await?;
+        self.commit(client).await?;
+
+        // Not only the pool of `source`, which is already this one when a commit is repeated
+        if id == source.get_id() {
+            for mut old in Pool::list(client).await? {
+                if old.id != self.id && old.disks.contains(&id) {
+                    old.disks.retain(|disk| *disk != id);
+                    old.refresh_allocation(client).await?;
+                    old.commit(client).await?;
+                }
+            }
+        }
+
+        Ok(disk)
+    }
+
+    /// Deletes the image left behind by a disk moved to another pool, once the records point
+    /// at the new one. `disk` is the record from before the move.
+    pub async fn release_disk(
+        &mut self,
+        disk: &Disk,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        match fs::remove_file(self.disk_path(disk.get_id(), disk.get_format())) {
+            Ok(_) => {}
+            // Already deleted by an earlier attempt
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.refresh(client).await?;
+        self.commit(client).await
+    }
+
+    /// Adds an image found in the pool directory as a new disk, see `Disk::adopt`
+    pub async fn adopt_disk(
+        &mut self,
+        path: &Path,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Disk, Error> {
+        let disk = Disk::adopt(self, path, qemu_img, client).await?;
+        if !self.disks.contains(&disk.get_id()) {
+            self.disks.push(disk.get_id());
+        }
+        self.refresh(client).await?;
+        self.commit(client).await?;
+        Ok(disk)
+    }
+
+    /// Replaces the image of one of the pool's disks, see `Disk::replace_image`
+    pub async fn replace_disk_image(
+        &mut self,
+        disk: &mut Disk,
+        image: &Path,
+        format: DiskFormat,
+        overcommit: f64,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        disk.replace_image(image, format, overcommit, qemu_img, client)
+            .await?;
+
+        self.refresh(client).await?;
+        self.commit(client).await
+    }
+
+    pub async fn remove_disk(
+        &mut self,
+        disk: Disk,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<(), Error> {
+        let id = disk.get_id();
+        disk.delete(client).await?;
+        self.disks.retain(|disk| *disk != id);
+        self.refresh(client).await?;
+        self.commit(client).await?;
+        Ok(())
+    }
+}
+
+impl From<Pool> for virtus_proto::Pool {
+    fn from(val: Pool) -> Self {
+        virtus_proto::Pool {
+            id: val.id.to_string(),
+            node: val.nodes[0].to_string(),
+            nodes: val.nodes.iter().map(|id| id.to_string()).collect(),
+            backend: virtus_proto::PoolBackend::from(val.backend).into(),
+            name: val.name,
+            path: val.path,
+            disks: val.disks.into_iter().map(|id| id.to_string()).collect(),
+            total_bytes: val.total_bytes,
+            used_bytes: val.used_bytes,
+            allocated_bytes: val.allocated_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_respects_overcommit() {
+        let pool = Pool {
+            id: Uuid::new_v4(),
+            nodes: vec![Uuid::new_v4()],
+            backend: BackendKind::Directory,
+            name: None,
+            path: String::new(),
+            disks: vec![],
+            total_bytes: 10 * GIB,
+            used_bytes: 0,
+            allocated_bytes: 8 * GIB,
+        };
+
+        assert!(pool.check_capacity(2 * GIB, 1.0).is_ok());
+        assert!(matches!(
+            pool.check_capacity(3 * GIB, 1.0),
+            Err(Error::PoolFull)
+        ));
+        assert!(pool.check_capacity(7 * GIB, 1.5).is_ok());
+    }
+
+    #[test]
+    fn deserialize_old_record() {
+        let node = Uuid::new_v4();
+        let json = format!(
+            r#"{{"id":"{}","node_id":"{}","name":null,"path":"/pool","disks":[]}}"#,
+            Uuid::new_v4(),
+            node
+        );
+
+        let pool: Pool = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![node], pool.nodes);
+        assert_eq!(BackendKind::Directory, pool.backend);
+        assert_eq!(0, pool.total_bytes);
+        assert_eq!(0, pool.allocated_bytes);
+
+        // And records written since
+        let json = serde_json::to_string(&pool).unwrap();
+        assert_eq!(pool, serde_json::from_str(&json).unwrap());
+    }
+}
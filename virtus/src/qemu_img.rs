@@ -0,0 +1,752 @@
+use crate::error::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Exit status and output of a qemu-img invocation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunOutput {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Executes qemu-img with the given arguments.
+///
+/// Arguments are passed as argv, never through a shell.
+pub trait QemuImgRunner: Debug + Send + Sync {
+    fn run(&self, args: &[String]) -> Result<RunOutput, Error>;
+}
+
+/// Runs the qemu-img binary from `PATH`
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl QemuImgRunner for SystemRunner {
+    fn run(&self, args: &[String]) -> Result<RunOutput, Error> {
+        let output = match Command::new("qemu-img").args(args).output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::QemuImgNotFound),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(RunOutput {
+            code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
+/// `qemu-img info --output=json`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ImageInfo {
+    pub filename: String,
+    pub format: String,
+    pub virtual_size: u64,
+    pub actual_size: Option<u64>,
+    pub cluster_size: Option<u64>,
+    pub backing_filename: Option<String>,
+    #[serde(rename = "backing-filename-format")]
+    pub backing_format: Option<String>,
+    #[serde(default)]
+    pub dirty_flag: bool,
+    #[serde(default)]
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub name: String,
+    pub vm_state_size: u64,
+    pub date_sec: u64,
+}
+
+/// `qemu-img check --output=json`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CheckResult {
+    pub filename: String,
+    pub format: String,
+    #[serde(default)]
+    pub check_errors: u64,
+    #[serde(default)]
+    pub corruptions: u64,
+    #[serde(default)]
+    pub leaks: u64,
+    pub total_clusters: Option<u64>,
+    pub allocated_clusters: Option<u64>,
+    pub image_end_offset: Option<u64>,
+}
+
+impl CheckResult {
+    pub fn is_clean(&self) -> bool {
+        self.check_errors == 0 && self.corruptions == 0 && self.leaks == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotOp {
+    Create,
+    Apply,
+    Delete,
+}
+
+impl SnapshotOp {
+    fn flag(&self) -> &'static str {
+        match self {
+            SnapshotOp::Create => "-c",
+            SnapshotOp::Apply => "-a",
+            SnapshotOp::Delete => "-d",
+        }
+    }
+}
+
+/// Typed wrapper around the qemu-img commands used to manage disk images
+#[derive(Debug, Clone)]
+pub struct QemuImg {
+    runner: Arc<dyn QemuImgRunner>,
+}
+
+impl Default for QemuImg {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemRunner))
+    }
+}
+
+impl QemuImg {
+    pub fn new(runner: Arc<dyn QemuImgRunner>) -> Self {
+        Self { runner }
+    }
+
+    fn run(&self, args: Vec<String>) -> Result<String, Error> {
+        let output = self.runner.run(&args)?;
+        match output.code {
+            0 => Ok(output.stdout),
+            _ => Err(failure(&output.stderr)),
+        }
+    }
+
+    /// Creates an image of `size` bytes, optionally as an overlay of `backing`, given as a
+    /// path and format.
+    pub fn create(
+        &self,
+        path: &Path,
+        format: &str,
+        size: u64,
+        options: Option<&str>,
+        backing: Option<(&Path, &str)>,
+    ) -> Result<(), Error> {
+        let mut args = vec!["create".to_string(), "-f".to_string(), format.to_string()];
+
+        if let Some(options) = options {
+            args.extend(["-o".to_string(), options.to_string()]);
+        }
+
+        if let Some((backing, backing_format)) = backing {
+            args.extend([
+                "-b".to_string(),
+                path_arg(backing),
+                "-F".to_string(),
+                backing_format.to_string(),
+            ]);
+        }
+
+        args.extend([path_arg(path), size.to_string()]);
+        self.run(args).map(|_| ())
+    }
+
+    pub fn convert(
+        &self,
+        source: &Path,
+        source_format: &str,
+        dest: &Path,
+        format: &str,
+        options: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut args = vec![
+            "convert".to_string(),
+            "-f".to_string(),
+            source_format.to_string(),
+            "-O".to_string(),
+            format.to_string(),
+        ];
+
+        if let Some(options) = options {
+            args.extend(["-o".to_string(), options.to_string()]);
+        }
+
+        args.extend([path_arg(source), path_arg(dest)]);
+        self.run(args).map(|_| ())
+    }
+
+    /// Resizes the image to `size` bytes. `shrink` has to be set if the image gets smaller.
+    pub fn resize(
+        &self,
+        path: &Path,
+        format: &str,
+        size: u64,
+        shrink: bool,
+        preallocation: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut args = vec!["resize".to_string(), "-f".to_string(), format.to_string()];
+
+        if shrink {
+            args.push("--shrink".to_string());
+        }
+
+        if let Some(preallocation) = preallocation {
+            args.extend(["--preallocation".to_string(), preallocation.to_string()]);
+        }
+
+        args.extend([path_arg(path), size.to_string()]);
+        self.run(args).map(|_| ())
+    }
+
+    pub fn snapshot(&self, path: &Path, op: SnapshotOp, name: &str) -> Result<(), Error> {
+        self.run(vec![
+            "snapshot".to_string(),
+            op.flag().to_string(),
+            name.to_string(),
+            path_arg(path),
+        ])
+        .map(|_| ())
+    }
+
+    pub fn info(&self, path: &Path) -> Result<ImageInfo, Error> {
+        // Sharing lets us read images that a running domain holds open
+        let stdout = self.run(vec![
+            "info".to_string(),
+            "--output=json".to_string(),
+            "--force-share".to_string(),
+            path_arg(path),
+        ])?;
+
+        serde_json::from_str(&stdout).map_err(|e| Error::InvalidQemuImgOutput(e.to_string()))
+    }
+
+    pub fn check(&self, path: &Path, format: &str) -> Result<CheckResult, Error> {
+        let output = self.runner.run(&[
+            "check".to_string(),
+            "--output=json".to_string(),
+            "-f".to_string(),
+            format.to_string(),
+            path_arg(path),
+        ])?;
+
+        // 2 and 3 mean the check completed, but found corruptions or leaks
+        match output.code {
+            0 | 2 | 3 => serde_json::from_str(&output.stdout)
+                .map_err(|e| Error::InvalidQemuImgOutput(e.to_string())),
+            _ => Err(failure(&output.stderr)),
+        }
+    }
+
+    /// Returns the version of qemu-img, e.g. `8.2.2`
+    pub fn version(&self) -> Result<String, Error> {
+        // qemu-img version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)
+        let stdout = self.run(vec!["--version".to_string()])?;
+        stdout
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("qemu-img version "))
+            .and_then(|version| version.split_whitespace().next())
+            .map(|version| version.to_string())
+            .ok_or_else(|| Error::InvalidQemuImgOutput(stdout.clone()))
+    }
+}
+
+fn path_arg(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+fn failure(stderr: &str) -> Error {
+    let stderr = stderr.trim().to_string();
+    // e.g. Failed to get "write" lock. Is another process using the image? Other errors may
+    // mention a lock too, like a path in /var/lock or a block device that's locked.
+    match stderr.contains("Failed to get") && stderr.contains("lock") {
+        true => Error::DiskLocked(stderr),
+        false => Error::CommandFailed(stderr),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FakeImage {
+    format: String,
+    virtual_size: u64,
+    backing: Option<(String, String)>,
+    // Name and virtual size at the time of the snapshot
+    snapshots: Vec<(String, u64)>,
+}
+
+/// In-process stand-in for qemu-img.
+///
+/// Creates empty (sparse) files instead of real images and keeps their metadata in memory.
+/// Records every invocation for tests.
+#[derive(Debug, Default)]
+pub struct FakeQemuImgRunner {
+    images: Mutex<HashMap<PathBuf, FakeImage>>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+impl FakeQemuImgRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Arguments of every invocation so far
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn write(&self, path: &Path, image: FakeImage) -> Result<(), Error> {
+        let file = File::create(path)?;
+        if image.format == "raw" {
+            file.set_len(image.virtual_size)?;
+        }
+
+        self.images
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), image);
+        Ok(())
+    }
+
+    /// Images that weren't created by us, e.g. registered images, are treated as raw files
+    fn image(&self, path: &Path) -> Option<FakeImage> {
+        match self.images.lock().unwrap().get(path) {
+            Some(image) => Some(image.clone()),
+            None => fs::metadata(path).ok().map(|metadata| FakeImage {
+                format: "raw".to_string(),
+                virtual_size: metadata.len(),
+                backing: None,
+                snapshots: vec![],
+            }),
+        }
+    }
+}
+
+impl QemuImgRunner for FakeQemuImgRunner {
+    fn run(&self, args: &[String]) -> Result<RunOutput, Error> {
+        self.calls.lock().unwrap().push(args.to_vec());
+
+        let mut flags = HashMap::new();
+        let mut positional = Vec::new();
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--shrink" | "--output=json" | "--force-share" => {
+                    flags.insert(arg.as_str(), String::new());
+                }
+                flag if flag.starts_with('-') => {
+                    flags.insert(flag, iter.next().cloned().unwrap_or_default());
+                }
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        let fail = |stderr: String| {
+            Ok(RunOutput {
+                code: 1,
+                stdout: String::new(),
+                stderr,
+            })
+        };
+        let ok = |stdout: String| {
+            Ok(RunOutput {
+                code: 0,
+                stdout,
+                stderr: String::new(),
+            })
+        };
+
+        if args.first().map(|arg| arg.as_str()) == Some("--version") {
+            return ok("qemu-img version 8.2.2\n".to_string());
+        }
+
+        let path = match positional.first() {
+            Some(path) => PathBuf::from(path),
+            None => return fail("Expecting image file name".to_string()),
+        };
+
+        match args.first().map(|arg| arg.as_str()) {
+            Some("create") => {
+                let size = positional.get(1).and_then(|size| size.parse().ok());
+                self.write(
+                    &path,
+                    FakeImage {
+                        format: flags.get("-f").cloned().unwrap_or("raw".to_string()),
+                        virtual_size: size.unwrap_or(0),
+                        backing: flags
+                            .get("-b")
+                            .map(|b| (b.clone(), flags.get("-F").cloned().unwrap_or_default())),
+                        snapshots: vec![],
+                    },
+                )?;
+                ok(String::new())
+            }
+            Some("convert") => {
+                let source = match self.image(&path) {
+                    Some(source) => source,
+                    None => return fail(format!("Could not open '{}'", path.display())),
+                };
+
+                let dest = match positional.get(1) {
+                    Some(dest) => PathBuf::from(dest),
+                    None => return fail("Expecting output file name".to_string()),
+                };
+
+                self.write(
+                    &dest,
+                    FakeImage {
+                        format: flags.get("-O").cloned().unwrap_or("raw".to_string()),
+                        virtual_size: source.virtual_size,
+                        backing: None,
+                        snapshots: vec![],
+                    },
+                )?;
+                ok(String::new())
+            }
+            Some("resize") => {
+                let mut image = match self.image(&path) {
+                    Some(image) => image,
+                    None => return fail(format!("Could not open '{}'", path.display())),
+                };
+
+                let size = match positional.get(1).and_then(|size| size.parse().ok()) {
+                    Some(size) => size,
+                    None => return fail("Invalid image size specified".to_string()),
+                };
+
+                if size < image.virtual_size && !flags.contains_key("--shrink") {
+                    return fail("Use the --shrink option to perform a shrink operation".into());
+                }
+
+                // Unlike create and convert, resizing keeps the contents
+                if image.format == "raw" {
+                    OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+                }
+
+                image.virtual_size = size;
+                self.images.lock().unwrap().insert(path, image);
+                ok(String::new())
+            }
+            Some("snapshot") => {
+                let mut images = self.images.lock().unwrap();
+                let image = match images.get_mut(&path) {
+                    Some(image) if image.format == "qcow2" => image,
+                    _ => return fail("This image format does not support snapshots".into()),
+                };
+
+                let exists = |name: &str| image.snapshots.iter().position(|(n, _)| n == name);
+                if let Some(name) = flags.get("-c") {
+                    if exists(name).is_some() {
+                        return fail(format!("Snapshot '{}' already exists", name));
+                    }
+                    image.snapshots.push((name.clone(), image.virtual_size));
+                } else if let Some(name) = flags.get("-a") {
+                    match exists(name) {
+                        Some(index) => image.virtual_size = image.snapshots[index].1,
+                        None => return fail(format!("Could not apply snapshot '{}'", name)),
+                    }
+                } else if let Some(name) = flags.get("-d") {
+                    match exists(name) {
+                        Some(index) => {
+                            image.snapshots.remove(index);
+                        }
+                        None => return fail(format!("Could not delete snapshot '{}'", name)),
+                    }
+                }
+
+                ok(String::new())
+            }
+            Some("info") => {
+                let image = match self.image(&path) {
+                    Some(image) => image,
+                    None => return fail(format!("Could not open '{}'", path.display())),
+                };
+
+                let snapshots: Vec<_> = image
+                    .snapshots
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, _))| {
+                        serde_json::json!({
+                            "id": (i + 1).to_string(),
+                            "name": name,
+                            "vm-state-size": 0,
+                            "date-sec": 0,
+                        })
+                    })
+                    .collect();
+
+                let mut info = serde_json::json!({
+                    "filename": path.to_string_lossy(),
+                    "format": image.format,
+                    "virtual-size": image.virtual_size,
+                    "dirty-flag": false,
+                    "snapshots": snapshots,
+                });
+                if let Some((backing, format)) = image.backing {
+                    info["backing-filename"] = backing.into();
+                    info["backing-filename-format"] = format.into();
+                }
+
+                ok(info.to_string())
+            }
+            Some("check") => match self.image(&path) {
+                Some(image) => ok(serde_json::json!({
+                    "filename": path.to_string_lossy(),
+                    "format": image.format,
+                    "check-errors": 0,
+                })
+                .to_string()),
+                None => fail(format!("Could not open '{}'", path.display())),
+            },
+            _ => fail(format!("Command not found: {}", args.join(" "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays a fixed output and records the arguments it was called with
+    #[derive(Debug, Default)]
+    struct ReplayRunner {
+        output: RunOutput,
+        args: Mutex<Vec<String>>,
+    }
+
+    impl QemuImgRunner for ReplayRunner {
+        fn run(&self, args: &[String]) -> Result<RunOutput, Error> {
+            *self.args.lock().unwrap() = args.to_vec();
+            Ok(self.output.clone())
+        }
+    }
+
+    fn replay(code: i32, stdout: &str, stderr: &str) -> (QemuImg, Arc<ReplayRunner>) {
+        let runner = Arc::new(ReplayRunner {
+            output: RunOutput {
+                code,
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            },
+            ..Default::default()
+        });
+        (QemuImg::new(runner.clone()), runner)
+    }
+
+    #[test]
+    fn create_args() {
+        let (qemu_img, runner) = replay(0, "", "");
+        qemu_img
+            .create(
+                Path::new("/pools/with space/disk.qcow2"),
+                "qcow2",
+                1 << 30,
+                Some("preallocation=metadata"),
+                Some((Path::new("/images/base;rm -rf.img"), "raw")),
+            )
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                "create",
+                "-f",
+                "qcow2",
+                "-o",
+                "preallocation=metadata",
+                "-b",
+                "/images/base;rm -rf.img",
+                "-F",
+                "raw",
+                "/pools/with space/disk.qcow2",
+                "1073741824",
+            ],
+            *runner.args.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn resize_args() {
+        let (qemu_img, runner) = replay(0, "", "");
+        qemu_img
+            .resize(Path::new("disk.img"), "raw", 1024, true, Some("falloc"))
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                "resize",
+                "-f",
+                "raw",
+                "--shrink",
+                "--preallocation",
+                "falloc",
+                "disk.img",
+                "1024"
+            ],
+            *runner.args.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_info() {
+        let (qemu_img, _) = replay(
+            0,
+            r#"{
+                "children": [],
+                "snapshots": [
+                    {
+                        "icount": 0,
+                        "vm-clock-nsec": 0,
+                        "name": "before_upgrade",
+                        "date-sec": 1700000000,
+                        "date-nsec": 0,
+                        "vm-clock-sec": 0,
+                        "id": "1",
+                        "vm-state-size": 0
+                    }
+                ],
+                "virtual-size": 10737418240,
+                "filename": "disk.qcow2",
+                "cluster-size": 65536,
+                "format": "qcow2",
+                "actual-size": 200704,
+                "backing-filename": "/images/base.qcow2",
+                "backing-filename-format": "qcow2",
+                "format-specific": {"type": "qcow2", "data": {"compat": "1.1"}},
+                "dirty-flag": false
+            }"#,
+            "",
+        );
+
+        let info = qemu_img.info(Path::new("disk.qcow2")).unwrap();
+        assert_eq!("qcow2", info.format);
+        assert_eq!(10 << 30, info.virtual_size);
+        assert_eq!(Some(200704), info.actual_size);
+        assert_eq!(Some(65536), info.cluster_size);
+        assert_eq!(
+            Some("/images/base.qcow2".to_string()),
+            info.backing_filename
+        );
+        assert_eq!(Some("qcow2".to_string()), info.backing_format);
+        assert_eq!(1, info.snapshots.len());
+        assert_eq!("before_upgrade", info.snapshots[0].name);
+        assert_eq!(1700000000, info.snapshots[0].date_sec);
+
+        let (qemu_img, _) = replay(0, "not json", "");
+        assert!(matches!(
+            qemu_img.info(Path::new("disk.qcow2")),
+            Err(Error::InvalidQemuImgOutput(_))
+        ));
+    }
+
+    #[test]
+    fn parse_check() {
+        let (qemu_img, _) = replay(
+            3,
+            r#"{
+                "image-end-offset": 262144,
+                "total-clusters": 16384,
+                "check-errors": 0,
+                "leaks": 2,
+                "filename": "disk.qcow2",
+                "format": "qcow2",
+                "fragmented-clusters": 0
+            }"#,
+            "",
+        );
+
+        let check = qemu_img.check(Path::new("disk.qcow2"), "qcow2").unwrap();
+        assert_eq!(2, check.leaks);
+        assert_eq!(Some(16384), check.total_clusters);
+        assert!(!check.is_clean());
+
+        let (qemu_img, _) = replay(1, "", "qemu-img: Could not open 'disk.qcow2'");
+        assert!(matches!(
+            qemu_img.check(Path::new("disk.qcow2"), "qcow2"),
+            Err(Error::CommandFailed(_))
+        ));
+    }
+
+    #[test]
+    fn version() {
+        let (qemu_img, runner) = replay(
+            0,
+            "qemu-img version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\nCopyright (c) 2003-2023 Fabrice Bellard and the QEMU Project developers\n",
+            "",
+        );
+        assert_eq!("8.2.2", qemu_img.version().unwrap());
+        assert_eq!(vec!["--version"], *runner.args.lock().unwrap());
+
+        let (qemu_img, _) = replay(0, "qemu-img: unknown\n", "");
+        assert!(matches!(
+            qemu_img.version(),
+            Err(Error::InvalidQemuImgOutput(_))
+        ));
+
+        let fake = QemuImg::new(Arc::new(FakeQemuImgRunner::new()));
+        assert_eq!("8.2.2", fake.version().unwrap());
+    }
+
+    #[test]
+    fn failures() {
+        let (qemu_img, _) = replay(
+            1,
+            "",
+            "qemu-img: Failed to get \"write\" lock\nIs another process using the image?\n",
+        );
+        assert!(matches!(
+            qemu_img.snapshot(Path::new("disk.qcow2"), SnapshotOp::Create, "snap"),
+            Err(Error::DiskLocked(_))
+        ));
+
+        // Only qemu's own image locking means the disk is in use
+        let (qemu_img, _) = replay(
+            1,
+            "",
+            "qemu-img: Could not open '/var/lock/disk.qcow2': No such file or directory\n",
+        );
+        assert!(matches!(
+            qemu_img.snapshot(Path::new("disk.qcow2"), SnapshotOp::Create, "snap"),
+            Err(Error::CommandFailed(_))
+        ));
+    }
+
+    #[test]
+    fn fake_runner() {
+        fs::create_dir_all("target/tmp/qemu_img").unwrap();
+        let path = Path::new("target/tmp/qemu_img/fake.qcow2");
+        let runner = Arc::new(FakeQemuImgRunner::new());
+        let qemu_img = QemuImg::new(runner.clone());
+
+        qemu_img.create(path, "qcow2", 1 << 30, None, None).unwrap();
+        qemu_img.snapshot(path, SnapshotOp::Create, "snap").unwrap();
+        qemu_img
+            .resize(path, "qcow2", 2 << 30, false, None)
+            .unwrap();
+        assert!(qemu_img
+            .resize(path, "qcow2", 1 << 20, false, None)
+            .is_err());
+
+        let info = qemu_img.info(path).unwrap();
+        assert_eq!(2 << 30, info.virtual_size);
+        assert_eq!("snap", info.snapshots[0].name);
+
+        qemu_img.snapshot(path, SnapshotOp::Apply, "snap").unwrap();
+        assert_eq!(1 << 30, qemu_img.info(path).unwrap().virtual_size);
+        assert!(qemu_img.check(path, "qcow2").unwrap().is_clean());
+
+        assert_eq!(8, runner.calls().len());
+    }
+}
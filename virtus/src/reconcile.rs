@@ -0,0 +1,442 @@
+use crate::disk::{probe, Disk, DiskFormat};
+use crate::error::Error;
+use crate::image::Image;
+use crate::pool::Pool;
+use crate::qemu_img::QemuImg;
+use crate::virtus::virtus_proto;
+use skiff::Client as SkiffClient;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Extensions of disk images in a pool directory. Anything else, like partial uploads and
+/// transfers, is left alone.
+const IMAGE_EXTENSIONS: [&str; 2] = ["qcow2", "img"];
+
+/// Images written more recently than this aren't orphans yet, they may belong to a disk being
+/// created or received whose record isn't committed.
+const ORPHAN_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftKind {
+    /// The pool directory doesn't exist
+    MissingPool,
+    /// A disk's image isn't in its pool
+    MissingFile,
+    /// An image in a pool directory that no disk refers to
+    OrphanFile,
+    /// The virtual size of the image differs from the disk record
+    SizeMismatch { expected: u64, actual: u64 },
+}
+
+/// A difference between the cluster state and the files on a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    kind: DriftKind,
+    pool: Uuid,
+    disk: Option<Uuid>,
+    path: PathBuf,
+    // The orphan was adopted, or the disk marked as broken
+    fixed: bool,
+}
+
+impl Drift {
+    fn new(kind: DriftKind, pool: Uuid, disk: Option<Uuid>, path: PathBuf) -> Self {
+        Self {
+            kind,
+            pool,
+            disk,
+            path,
+            fixed: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    // Seconds since the unix epoch, 0 until the first scan
+    scanned_at: u64,
+    drift: Vec<Drift>,
+}
+
+/// Compares the pools and disks of a node against the files in the pool directories
+#[derive(Debug, Clone)]
+pub struct Reconciler {
+    interval: Option<Duration>,
+    adopt: bool,
+    last: Arc<Mutex<Report>>,
+}
+
+impl Default for Reconciler {
+    fn default() -> Self {
+        Self::new(Some(Duration::from_secs(300)), false)
+    }
+}
+
+impl Reconciler {
+    /// Scans every `interval`, or only on request if unset. With `adopt`, orphan images are
+    /// adopted as disks, and disks with missing or mismatched images are marked as broken.
+    pub fn new(interval: Option<Duration>, adopt: bool) -> Self {
+        Self {
+            interval,
+            adopt,
+            last: Arc::new(Mutex::new(Report::default())),
+        }
+    }
+
+    pub fn get_interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// The report of the last scan
+    pub async fn last(&self) -> Report {
+        self.last.lock().await.clone()
+    }
+
+    /// Scans the pools of `node_id`, fixing what it finds in adopt mode
+    pub async fn reconcile(
+        &self,
+        node_id: Uuid,
+        qemu_img: &QemuImg,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Report, Error> {
+        let pools = Pool::list(client).await?;
+        let disks = Disk::list(client).await?;
+        let images = Image::list(client).await?;
+
+        let mut drift = scan(node_id, &pools, &disks, &images, qemu_img);
+        if self.adopt {
+            apply(&mut drift, node_id, &pools, &disks, qemu_img, client).await?;
+        }
+
+        let report = Report {
+            scanned_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            drift,
+        };
+
+        *self.last.lock().await = report.clone();
+        Ok(report)
+    }
+}
+
+/// A disk image a pool directory should contain
+struct Expected {
+    id: Uuid,
+    path: PathBuf,
+    format: DiskFormat,
+    size: u64,
+}
+
+pub fn scan(
+    node_id: Uuid,
+    pools: &[Pool],
+    disks: &[Disk],
+    images: &[Image],
+    qemu_img: &QemuImg,
+) -> Vec<Drift> {
+    // Registered images may live in a pool directory
+    let registered: Vec<PathBuf> = images
+        .iter()
+        .filter_map(|image| image.get_path(node_id))
+        .collect();
+
+    let mut drift = Vec::new();
+    for pool in pools.iter().filter(|pool| pool.has_node(node_id)) {
+        let expected: Vec<Expected> = disks
+            .iter()
+            .filter(|disk| disk.get_pool_id() == pool.get_id())
+            .map(|disk| Expected {
+                id: disk.get_id(),
+                path: pool.disk_path(disk.get_id(), disk.get_format()),
+                format: disk.get_format(),
+                size: disk.get_size(),
+            })
+            .collect();
+
+        drift.extend(scan_pool(
+            pool.get_id(),
+            Path::new(&pool.get_path()),
+            &expected,
+            &registered,
+            qemu_img,
+        ));
+    }
+
+    drift
+}
+
+fn scan_pool(
+    pool: Uuid,
+    dir: &Path,
+    expected: &[Expected],
+    registered: &[PathBuf],
+    qemu_img: &QemuImg,
+) -> Vec<Drift> {
+    let mut drift = Vec::new();
+    if !dir.is_dir() {
+        drift.push(Drift::new(
+            DriftKind::MissingPool,
+            pool,
+            None,
+            dir.to_path_buf(),
+        ));
+    }
+
+    for disk in expected {
+        if !disk.path.exists() {
+            drift.push(Drift::new(
+                DriftKind::MissingFile,
+                pool,
+                Some(disk.id),
+                disk.path.clone(),
+            ));
+            continue;
+        }
+
+        // Images qemu-img can't read are a job for qemu-img check, not for us
+        if let Ok((actual, _)) = probe(&disk.path, disk.format, qemu_img) {
+            if actual != disk.size {
+                drift.push(Drift::new(
+                    DriftKind::SizeMismatch {
+                        expected: disk.size,
+                        actual,
+                    },
+                    pool,
+                    Some(disk.id),
+                    disk.path.clone(),
+                ));
+            }
+        }
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return drift,
+    };
+
+    for path in entries.flatten().map(|entry| entry.path()) {
+        let is_image = path.is_file()
+            && path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension));
+        if !is_image || expected.iter().any(|disk| disk.path == path) || is_recent(&path) {
+            continue;
+        }
+
+        let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+        if !registered.contains(&canonical) {
+            drift.push(Drift::new(DriftKind::OrphanFile, pool, None, path));
+        }
+    }
+
+    drift
+}
+
+/// Whether `path` was modified within the grace period, or in the future
+fn is_recent(path: &Path) -> bool {
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified
+            .elapsed()
+            .map_or(true, |elapsed| elapsed < ORPHAN_GRACE),
+        Err(_) => false,
+    }
+}
+
+/// Adopts orphan images and marks disks with missing or mismatched images as broken. Broken
+/// disks of the node that no longer drift are repaired.
+///
+/// Shared pools are scanned by all of their nodes, but only fixed by the node they were
+/// created on, so that a node missing the mount doesn't mark every disk as broken.
+///
+/// Records are read again before changing them, as they may have changed since the scan.
+async fn apply(
+    drift: &mut [Drift],
+    node_id: Uuid,
+    pools: &[Pool],
+    disks: &[Disk],
+    qemu_img: &QemuImg,
+    client: &Arc<Mutex<SkiffClient>>,
+) -> Result<(), Error> {
+    let local: Vec<Uuid> = pools
+        .iter()
+        .filter(|pool| pool.get_node_id() == node_id)
+        .map(|pool| pool.get_id())
+        .collect();
+
+    for item in drift.iter_mut().filter(|item| local.contains(&item.pool)) {
+        match (item.kind, item.disk) {
+            (DriftKind::OrphanFile, _) => {
+                let mut pool = match Pool::get(item.pool, client).await? {
+                    Some(pool) => pool,
+                    None => continue,
+                };
+
+                // Images that can't be adopted stay orphans for an operator to look at
+                if item.path.exists() {
+                    if let Ok(disk) = pool.adopt_disk(&item.path, qemu_img, client).await {
+                        item.disk = Some(disk.get_id());
+                        item.fixed = true;
+                    }
+                }
+            }
+            (DriftKind::MissingFile, Some(id)) | (DriftKind::SizeMismatch { .. }, Some(id)) => {
+                if let Some(mut disk) = Disk::get(id, client).await? {
+                    if disk.get_pool_id() == item.pool {
+                        if !disk.is_broken() {
+                            disk.set_broken(true, client).await?;
+                        }
+                        item.fixed = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for disk in disks {
+        let drifting = drift.iter().any(|item| item.disk == Some(disk.get_id()));
+        if !disk.is_broken() || drifting || !local.contains(&disk.get_pool_id()) {
+            continue;
+        }
+
+        if let Some(mut disk) = Disk::get(disk.get_id(), client).await? {
+            disk.set_broken(false, client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+impl From<Drift> for virtus_proto::Drift {
+    fn from(val: Drift) -> Self {
+        let (kind, expected_size, actual_size) = match val.kind {
+            DriftKind::MissingPool => (virtus_proto::DriftKind::MissingPool, 0, 0),
+            DriftKind::MissingFile => (virtus_proto::DriftKind::MissingFile, 0, 0),
+            DriftKind::OrphanFile => (virtus_proto::DriftKind::OrphanFile, 0, 0),
+            DriftKind::SizeMismatch { expected, actual } => {
+                (virtus_proto::DriftKind::SizeMismatch, expected, actual)
+            }
+        };
+
+        virtus_proto::Drift {
+            kind: kind.into(),
+            pool: val.pool.to_string(),
+            disk: val.disk.map(|id| id.to_string()),
+            path: val.path.to_string_lossy().to_string(),
+            expected_size,
+            actual_size,
+            fixed: val.fixed,
+        }
+    }
+}
+
+impl From<Report> for virtus_proto::GetDriftReply {
+    fn from(val: Report) -> Self {
+        virtus_proto::GetDriftReply {
+            drift: val.drift.into_iter().map(|drift| drift.into()).collect(),
+            scanned_at: val.scanned_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::GIB;
+    use crate::qemu_img::FakeQemuImgRunner;
+    use std::fs::File;
+
+    #[test]
+    fn scan_pool_finds_drift() {
+        let dir = Path::new("target/tmp/reconcile/pool");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let raw = |name: &str, size: u64| {
+            let path = dir.join(name);
+            let file = File::create(&path).unwrap();
+            file.set_len(size).unwrap();
+            file.set_modified(SystemTime::now() - 2 * ORPHAN_GRACE)
+                .unwrap();
+            path
+        };
+
+        let pool = Uuid::new_v4();
+        let expected: Vec<Expected> = [(GIB, GIB), (GIB, 2 * GIB), (GIB, 0)]
+            .into_iter()
+            .map(|(size, actual)| {
+                let id = Uuid::new_v4();
+                let name = format!("{}.img", id);
+                let path = match actual {
+                    0 => dir.join(name),
+                    actual => raw(&name, actual),
+                };
+
+                Expected {
+                    id,
+                    path,
+                    format: DiskFormat::Raw,
+                    size,
+                }
+            })
+            .collect();
+
+        let orphan = raw("orphan.qcow2", 0);
+        let image = raw("image.img", 512);
+        raw("partial.img.part", 512);
+        raw("notes.txt", 10);
+
+        // Still being written, or waiting for its record
+        File::create(dir.join("creating.qcow2")).unwrap();
+
+        let qemu_img = QemuImg::new(Arc::new(FakeQemuImgRunner::new()));
+        let registered = vec![fs::canonicalize(&image).unwrap()];
+        let drift = scan_pool(pool, dir, &expected, &registered, &qemu_img);
+
+        assert_eq!(3, drift.len());
+        assert!(drift.contains(&Drift::new(
+            DriftKind::SizeMismatch {
+                expected: GIB,
+                actual: 2 * GIB
+            },
+            pool,
+            Some(expected[1].id),
+            expected[1].path.clone()
+        )));
+        assert!(drift.contains(&Drift::new(
+            DriftKind::MissingFile,
+            pool,
+            Some(expected[2].id),
+            expected[2].path.clone()
+        )));
+        assert!(drift.contains(&Drift::new(DriftKind::OrphanFile, pool, None, orphan)));
+    }
+
+    #[test]
+    fn scan_missing_pool() {
+        let dir = Path::new("target/tmp/reconcile/missing");
+        let _ = fs::remove_dir_all(dir);
+
+        let pool = Uuid::new_v4();
+        let qemu_img = QemuImg::new(Arc::new(FakeQemuImgRunner::new()));
+        let disk = Expected {
+            id: Uuid::new_v4(),
+            path: dir.join("disk.qcow2"),
+            format: DiskFormat::Qcow2,
+            size: GIB,
+        };
+
+        let kinds: Vec<DriftKind> = scan_pool(pool, dir, &[disk], &[], &qemu_img)
+            .iter()
+            .map(|drift| drift.kind)
+            .collect();
+        assert_eq!(vec![DriftKind::MissingPool, DriftKind::MissingFile], kinds);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::error::Error;
+use skiff::ElectionState;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+/// Metadata key set on requests the leader forwards to the owning node
+// Todo: more rigorous way to indicate forwarded request
+pub const FORWARDED: &str = "forwarded";
+
+/// Where a request has to be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Cluster metadata only, handled by the skiff leader
+    Leader,
+    /// Has a local side effect on the given node (files, domains, ...). The request goes
+    /// through the leader first, which forwards it to the node.
+    Node(Uuid),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Local,
+    /// Forward to `node`, marking the request as forwarded if `mark` is set
+    Forward {
+        node: Uuid,
+        mark: bool,
+    },
+}
+
+/// Outcome of `Virtus::route`
+pub enum Routed<T, R> {
+    /// The request should be handled here
+    Local(Request<T>),
+    /// The reply from the next hop
+    Forwarded(Result<Response<R>, Status>),
+}
+
+/// Decides whether this node handles a request or passes it on.
+///
+/// `forwarded` is whether the request already carries the `FORWARDED` marker.
+pub fn resolve(
+    state: &ElectionState,
+    id: Uuid,
+    target: Target,
+    forwarded: bool,
+) -> Result<Route, Error> {
+    match (state, target) {
+        (ElectionState::Candidate, _) => Err(Error::NoLeaderElected),
+        (ElectionState::Leader, Target::Leader) => Ok(Route::Local),
+        (ElectionState::Follower(leader), Target::Leader) => Ok(Route::Forward {
+            node: *leader,
+            mark: false,
+        }),
+        (ElectionState::Leader, Target::Node(owner)) => match owner == id {
+            true => Ok(Route::Local),
+            false => Ok(Route::Forward {
+                node: owner,
+                mark: true,
+            }),
+        },
+        (ElectionState::Follower(leader), Target::Node(_)) => match forwarded {
+            // Already went through the leader, so we are the owner
+            true => Ok(Route::Local),
+            false => Ok(Route::Forward {
+                node: *leader,
+                mark: false,
+            }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidate_rejects() {
+        let id = Uuid::new_v4();
+        assert!(resolve(&ElectionState::Candidate, id, Target::Leader, false).is_err());
+        assert!(resolve(&ElectionState::Candidate, id, Target::Node(id), true).is_err());
+    }
+
+    #[test]
+    fn leader_target() {
+        let id = Uuid::new_v4();
+        let leader = Uuid::new_v4();
+
+        assert_eq!(
+            Route::Local,
+            resolve(&ElectionState::Leader, id, Target::Leader, false).unwrap()
+        );
+        assert_eq!(
+            Route::Forward {
+                node: leader,
+                mark: false
+            },
+            resolve(&ElectionState::Follower(leader), id, Target::Leader, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn node_target_on_leader() {
+        let id = Uuid::new_v4();
+        let owner = Uuid::new_v4();
+
+        assert_eq!(
+            Route::Local,
+            resolve(&ElectionState::Leader, id, Target::Node(id), false).unwrap()
+        );
+        assert_eq!(
+            Route::Forward {
+                node: owner,
+                mark: true
+            },
+            resolve(&ElectionState::Leader, id, Target::Node(owner), false).unwrap()
+        );
+    }
+
+    #[test]
+    fn node_target_on_follower() {
+        let id = Uuid::new_v4();
+        let leader = Uuid::new_v4();
+
+        // Even requests for our own resources go through the leader first
+        assert_eq!(
+            Route::Forward {
+                node: leader,
+                mark: false
+            },
+            resolve(
+                &ElectionState::Follower(leader),
+                id,
+                Target::Node(id),
+                false
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Route::Local,
+            resolve(&ElectionState::Follower(leader), id, Target::Node(id), true).unwrap()
+        );
+    }
+}
@@ -0,0 +1,501 @@
+use crate::error::Error;
+use crate::virtus::virtus_proto::{
+    DiskChunk, DownloadChunk, TransferHeader, TransferProgress, UploadChunk,
+};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::Status;
+
+/// Size of the data in each `DiskChunk`, well below tonic's 4M message limit
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Progress reported to the caller of `CopyDisk` and `MoveDisk`
+pub type TransferStream = Pin<Box<dyn Stream<Item = Result<TransferProgress, Status>> + Send>>;
+
+/// Image data returned by `DownloadDisk`
+pub type DownloadStream = Pin<Box<dyn Stream<Item = Result<DownloadChunk, Status>> + Send>>;
+
+/// Chunks of an `UploadDisk` call, possibly on their way to the owning node
+pub type UploadStream = ReceiverStream<UploadChunk>;
+
+/// Streams the file at `path` as `DiskChunk`s, starting with `header` and ending with the
+/// checksum of the data.
+///
+/// Progress is reported on `progress` as chunks are taken off the stream, updates are
+/// dropped if the receiver falls behind. The returned task fails if the file can't be read,
+/// in which case the stream ends without a checksum.
+pub fn send(
+    path: PathBuf,
+    header: TransferHeader,
+    progress: mpsc::Sender<Result<TransferProgress, Status>>,
+) -> (ReceiverStream<DiskChunk>, JoinHandle<Result<u64, Error>>) {
+    let (tx, rx) = mpsc::channel(4);
+
+    let handle = tokio::spawn(async move {
+        let mut file = File::open(&path).await?;
+        let total = header.length;
+        let mut hasher = Sha256::new();
+        let mut sent = 0;
+        let mut header = Some(header);
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        loop {
+            let read = read_chunk(&mut file, &mut buf).await?;
+            hasher.update(&buf[..read]);
+            sent += read as u64;
+
+            // The header goes out even for empty files
+            if read == 0 && header.is_none() {
+                break;
+            }
+
+            let chunk = DiskChunk {
+                header: header.take(),
+                data: buf[..read].to_vec(),
+                checksum: None,
+            };
+            if tx.send(chunk).await.is_err() {
+                return Err(Error::TransferFailed("receiver closed the stream".into()));
+            }
+
+            let _ = progress.try_send(Ok(TransferProgress {
+                bytes_sent: sent,
+                total_bytes: total,
+                disk: None,
+            }));
+
+            if read < buf.len() {
+                break;
+            }
+        }
+
+        let chunk = DiskChunk {
+            header: None,
+            data: vec![],
+            checksum: Some(format!("{:x}", hasher.finalize())),
+        };
+        if tx.send(chunk).await.is_err() {
+            return Err(Error::TransferFailed("receiver closed the stream".into()));
+        }
+
+        Ok(sent)
+    });
+
+    (ReceiverStream::new(rx), handle)
+}
+
+/// Streams the file at `path` from `offset` on as `DownloadChunk`s. The last chunk carries
+/// the checksum of the whole file, so resumed downloads can be verified too.
+///
+/// With `remove` the file is deleted once the stream is done, whether or not it completed.
+pub async fn download(path: PathBuf, offset: u64, remove: bool) -> Result<DownloadStream, Error> {
+    let mut file = File::open(&path).await?;
+    let length = file.metadata().await?.len();
+    if offset > length {
+        return Err(Error::TransferFailed(format!(
+            "offset {} is past the end of the image ({} bytes)",
+            offset, length
+        )));
+    }
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut position = 0;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        loop {
+            let read = match read_chunk(&mut file, &mut buf).await {
+                Ok(read) => read,
+                Err(e) => {
+                    let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                    break;
+                }
+            };
+            hasher.update(&buf[..read]);
+
+            // The part before the offset is only read for the checksum
+            let start = offset.saturating_sub(position).min(read as u64) as usize;
+            if start < read {
+                let chunk = DownloadChunk {
+                    offset: position + start as u64,
+                    data: buf[start..read].to_vec(),
+                    length,
+                    checksum: None,
+                };
+                if tx.send(Ok(chunk)).await.is_err() {
+                    break;
+                }
+            }
+            position += read as u64;
+
+            if read < buf.len() {
+                let chunk = DownloadChunk {
+                    offset: position,
+                    data: vec![],
+                    length,
+                    checksum: Some(format!("{:x}", hasher.finalize())),
+                };
+                let _ = tx.send(Ok(chunk)).await;
+                break;
+            }
+        }
+
+        if remove {
+            let _ = fs::remove_file(&path).await;
+        }
+    });
+
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
+
+/// Hex encoded sha256 of the file at `path`
+pub async fn sha256(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let read = read_chunk(&mut file, &mut buf).await?;
+        hasher.update(&buf[..read]);
+        if read < buf.len() {
+            return Ok(format!("{:x}", hasher.finalize()));
+        }
+    }
+}
+
+/// Fills `buf` unless the end of the file is reached first
+async fn read_chunk(file: &mut File, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(read)
+}
+
+/// A disk image being received from a peer.
+///
+/// Data is written next to the final path and only renamed into place once its length and
+/// checksum have been verified.
+pub struct Incoming {
+    file: File,
+    part: PathBuf,
+    path: PathBuf,
+    hasher: Sha256,
+    received: u64,
+    length: u64,
+}
+
+impl Incoming {
+    pub async fn create(path: &Path, length: u64) -> Result<Self, Error> {
+        if fs::try_exists(path).await? {
+            return Err(Error::TransferFailed(format!(
+                "{} already exists",
+                path.display()
+            )));
+        }
+
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+
+        Ok(Self {
+            file: File::create(&part).await?,
+            part,
+            path: path.to_path_buf(),
+            hasher: Sha256::new(),
+            received: 0,
+            length,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.received += data.len() as u64;
+        if self.received > self.length {
+            return Err(Error::TransferFailed(format!(
+                "received more than the expected {} bytes",
+                self.length
+            )));
+        }
+
+        self.hasher.update(data);
+        self.file.write_all(data).await?;
+        Ok(())
+    }
+
+    /// Verifies the data against `checksum` and moves the image into place. The partial
+    /// image is removed if verification fails.
+    pub async fn finish(mut self, checksum: &str) -> Result<(), Error> {
+        if let Err(e) = self.verify(checksum).await {
+            self.abort().await;
+            return Err(e);
+        }
+
+        fs::rename(&self.part, &self.path).await?;
+        Ok(())
+    }
+
+    async fn verify(&mut self, checksum: &str) -> Result<(), Error> {
+        if self.received != self.length {
+            return Err(Error::TransferFailed(format!(
+                "received {} of {} bytes",
+                self.received, self.length
+            )));
+        }
+
+        let actual = format!("{:x}", self.hasher.clone().finalize());
+        if !checksum.eq_ignore_ascii_case(&actual) {
+            return Err(Error::TransferFailed(format!(
+                "checksum mismatch, expected {} got {}",
+                checksum, actual
+            )));
+        }
+
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Removes the partially received image
+    pub async fn abort(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.part).await;
+    }
+}
+
+/// An upload written to `path`, which is kept between calls until the upload completes so
+/// it can be resumed.
+pub struct Upload {
+    file: File,
+    path: PathBuf,
+    offset: u64,
+}
+
+impl Upload {
+    /// Continues the upload at `path` from `offset`, dropping anything received past it.
+    ///
+    /// Fails if less than `offset` bytes were received before.
+    pub async fn open(path: &Path, offset: u64) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .await?;
+
+        let length = file.metadata().await?.len();
+        if offset > length {
+            return Err(Error::TransferFailed(format!(
+                "can't resume at {}, only {} bytes were uploaded",
+                offset, length
+            )));
+        }
+
+        file.set_len(offset).await?;
+        let mut upload = Self {
+            file,
+            path: path.to_path_buf(),
+            offset,
+        };
+        upload.file.seek(SeekFrom::Start(offset)).await?;
+        Ok(upload)
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.write_all(data).await?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Keeps what was received so far, returning the offset to resume from
+    pub async fn suspend(mut self) -> Result<u64, Error> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(self.offset)
+    }
+
+    /// Verifies the whole upload against `checksum`. A corrupt upload can't be resumed, so
+    /// it is removed.
+    pub async fn finish(mut self, checksum: &str) -> Result<PathBuf, Error> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+
+        let actual = sha256(&self.path).await?;
+        if !checksum.eq_ignore_ascii_case(&actual) {
+            let _ = fs::remove_file(&self.path).await;
+            return Err(Error::TransferFailed(format!(
+                "checksum mismatch, expected {} got {}",
+                checksum, actual
+            )));
+        }
+
+        Ok(self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = Path::new("target/tmp/transfer").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn send_receive() {
+        let dir = dir("send_receive");
+        let source = dir.join("source.img");
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let header = TransferHeader {
+            length: contents.len() as u64,
+            ..Default::default()
+        };
+        let (progress, mut updates) = mpsc::channel(16);
+        let (mut stream, handle) = send(source, header, progress);
+
+        let dest = dir.join("dest.img");
+        let mut incoming = Incoming::create(&dest, contents.len() as u64)
+            .await
+            .unwrap();
+        let mut chunks = 0;
+        let mut checksum = None;
+        while let Some(chunk) = stream.next().await {
+            assert_eq!(chunks == 0, chunk.header.is_some());
+            incoming.write(&chunk.data).await.unwrap();
+            checksum = chunk.checksum;
+            chunks += 1;
+        }
+
+        // Three data chunks and the checksum
+        assert_eq!(4, chunks);
+        assert_eq!(contents.len() as u64, handle.await.unwrap().unwrap());
+        incoming.finish(&checksum.unwrap()).await.unwrap();
+        assert_eq!(contents, std::fs::read(&dest).unwrap());
+        assert!(!dir.join("dest.img.part").exists());
+
+        let mut last = 0;
+        while let Ok(update) = updates.try_recv() {
+            last = update.unwrap().bytes_sent;
+        }
+        assert_eq!(contents.len() as u64, last);
+    }
+
+    #[tokio::test]
+    async fn empty_file() {
+        let dir = dir("empty_file");
+        let source = dir.join("source.img");
+        std::fs::write(&source, []).unwrap();
+
+        let (progress, _updates) = mpsc::channel(16);
+        let (stream, handle) = send(source, TransferHeader::default(), progress);
+        let chunks: Vec<DiskChunk> = stream.collect().await;
+
+        assert_eq!(2, chunks.len());
+        assert!(chunks[0].header.is_some());
+        assert!(chunks[1].checksum.is_some());
+        assert_eq!(0, handle.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_data() {
+        let dir = dir("rejects_bad_data");
+        let dest = dir.join("dest.img");
+
+        let mut incoming = Incoming::create(&dest, 3).await.unwrap();
+        incoming.write(b"abc").await.unwrap();
+        let result = incoming.finish("0000").await;
+        assert!(matches!(result, Err(Error::TransferFailed(_))));
+        assert!(!dest.exists());
+        assert!(!dir.join("dest.img.part").exists());
+
+        let mut incoming = Incoming::create(&dest, 3).await.unwrap();
+        incoming.write(b"ab").await.unwrap();
+        assert!(incoming.finish("").await.is_err());
+
+        let mut incoming = Incoming::create(&dest, 3).await.unwrap();
+        assert!(incoming.write(b"abcd").await.is_err());
+        incoming.abort().await;
+        assert!(!dir.join("dest.img.part").exists());
+
+        std::fs::write(&dest, b"abc").unwrap();
+        assert!(Incoming::create(&dest, 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn download_from_offset() {
+        let dir = dir("download_from_offset");
+        let source = dir.join("source.img");
+        let contents: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| i as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+        let checksum = sha256(&source).await.unwrap();
+
+        for offset in [0, 10, CHUNK_SIZE as u64 + 50] {
+            let chunks: Vec<DownloadChunk> = download(source.clone(), offset, false)
+                .await
+                .unwrap()
+                .map(|chunk| chunk.unwrap())
+                .collect()
+                .await;
+
+            assert_eq!(offset, chunks[0].offset);
+            let data: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.data.clone()).collect();
+            assert_eq!(contents[offset as usize..], data[..]);
+            assert_eq!(Some(&checksum), chunks.last().unwrap().checksum.as_ref());
+        }
+
+        assert!(matches!(
+            download(source.clone(), contents.len() as u64 + 1, false).await,
+            Err(Error::TransferFailed(_))
+        ));
+
+        let _: Vec<_> = download(source.clone(), 0, true)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(!source.exists());
+    }
+
+    #[tokio::test]
+    async fn resume_upload() {
+        let dir = dir("resume_upload");
+        let path = dir.join("disk.upload");
+
+        let mut upload = Upload::open(&path, 0).await.unwrap();
+        upload.write(b"abcxyz").await.unwrap();
+        assert_eq!(6, upload.suspend().await.unwrap());
+
+        // Resuming drops anything past the offset
+        assert!(Upload::open(&path, 7).await.is_err());
+        let mut upload = Upload::open(&path, 3).await.unwrap();
+        upload.write(b"def").await.unwrap();
+        let checksum = format!("{:x}", Sha256::digest(b"abcdef"));
+        assert_eq!(path, upload.finish(&checksum).await.unwrap());
+        assert_eq!(b"abcdef".to_vec(), std::fs::read(&path).unwrap());
+
+        let upload = Upload::open(&path, 6).await.unwrap();
+        assert!(matches!(
+            upload.finish("0000").await,
+            Err(Error::TransferFailed(_))
+        ));
+        assert!(!path.exists());
+    }
+}
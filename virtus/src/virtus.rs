@@ -1,17 +1,38 @@
-use crate::disk::Disk;
+use crate::config::PoolConfig;
+use crate::disk::{Disk, DiskOptions, Resizer};
 use crate::error::Error;
-use crate::node::Node;
-use crate::pool::Pool;
-use skiff::{Client as SkiffClient, ElectionState, Skiff};
+use crate::handle::{self, Handle};
+use crate::heartbeat::{self, Heartbeat, NodeStatus};
+use crate::hypervisor::{DomainState, Hypervisor};
+use crate::image::Image;
+use crate::inventory::Inventory;
+use crate::network::Network;
+use crate::node::{self, Node};
+use crate::pool::{BackendKind, Pool};
+use crate::qemu_img::QemuImg;
+use crate::reconcile::Reconciler;
+use crate::routing::{resolve, Route, Routed, Target, FORWARDED};
+use crate::transfer::{self, DownloadStream, Incoming, TransferStream, Upload, UploadStream};
+use crate::vm::VM;
+use skiff::{Client as SkiffClient, Skiff};
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::fs;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
 use tonic::metadata::MetadataValue;
-use tonic::transport::{Channel, Server};
-use tonic::{Request, Response, Status};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig,
+};
+use tonic::{Request, Response, Status, Streaming};
 use uuid::Uuid;
 use virtus_client::VirtusClient;
 use virtus_proto::virtus_server::VirtusServer;
@@ -21,32 +42,147 @@ pub mod virtus_proto {
     tonic::include_proto!("virtus");
 }
 
+/// Port of the gRPC server unless configured otherwise
+pub const DEFAULT_PORT: u16 = 9400;
+
+/// How often a node publishes its heartbeat unless configured otherwise
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificates securing the gRPC server and the connections to the other nodes
+#[derive(Debug, Clone)]
+pub(crate) struct Tls {
+    identity: Identity,
+    ca: Option<Certificate>,
+}
+
+impl Tls {
+    /// Takes PEM encoded files. Without `ca`, other nodes are verified against the system
+    /// roots and clients don't need a certificate.
+    pub(crate) fn new(cert: &[u8], key: &[u8], ca: Option<&[u8]>) -> Self {
+        Self {
+            identity: Identity::from_pem(cert, key),
+            ca: ca.map(Certificate::from_pem),
+        }
+    }
+
+    fn server(&self) -> ServerTlsConfig {
+        let config = ServerTlsConfig::new().identity(self.identity.clone());
+        match &self.ca {
+            Some(ca) => config.client_ca_root(ca.clone()),
+            None => config,
+        }
+    }
+
+    fn client(&self) -> ClientTlsConfig {
+        let config = ClientTlsConfig::new().identity(self.identity.clone());
+        match &self.ca {
+            Some(ca) => config.ca_certificate(ca.clone()),
+            None => config.with_native_roots(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Virtus {
     id: Uuid,
-    address: Ipv4Addr,
+    // Advertised to the other nodes, together with `port`
+    addresses: Vec<IpAddr>,
+    port: u16,
+    // Where the gRPC server listens
+    bind: SocketAddr,
     skiff: Arc<Skiff>,
     peer_clients: Arc<Mutex<HashMap<Uuid, Arc<Mutex<VirtusClient<Channel>>>>>>,
     client: Arc<Mutex<SkiffClient>>,
+    hypervisor: Arc<dyn Hypervisor>,
+    qemu_img: QemuImg,
+    overcommit: f64,
+    reconciler: Reconciler,
+    default_pools: Vec<PoolConfig>,
+    heartbeat_interval: Duration,
+    // Where /proc, /sys and /dev are found, only changed by tests
+    host_root: PathBuf,
+    // qemu-img and libvirt versions for the inventory, read once at startup
+    versions: Arc<OnceLock<(Option<String>, Option<String>)>>,
+    // Plain text gRPC if unset
+    tls: Option<Tls>,
 }
 
 impl Virtus {
     // Todo: add token gen for authenticated join to cluster
     pub fn new(
         id: Uuid,
-        address: Ipv4Addr,
+        addresses: Vec<IpAddr>,
         data_dir: String,
-        peers: Vec<Ipv4Addr>,
+        peers: Vec<IpAddr>,
+        hypervisor: Arc<dyn Hypervisor>,
+        qemu_img: QemuImg,
+        overcommit: f64,
     ) -> Result<Self, Error> {
+        // Skiff only speaks IPv4, the other addresses are only used for gRPC
+        let address = node::skiff_address(&addresses).ok_or_else(|| {
+            Error::UnsupportedAddress("an IPv4 address is required to join a cluster".to_string())
+        })?;
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                node::skiff_address(&[*peer]).ok_or_else(|| {
+                    Error::UnsupportedAddress(format!("peer {} is not an IPv4 address", peer))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         Ok(Self {
             id,
-            address,
+            bind: SocketAddr::new(addresses[0], DEFAULT_PORT),
+            addresses,
+            port: DEFAULT_PORT,
             skiff: Arc::new(Skiff::new(id, address, data_dir, peers.clone())?),
             client: Arc::new(Mutex::new(SkiffClient::new(vec![address]))),
             peer_clients: Arc::new(Mutex::new(HashMap::new())),
+            hypervisor,
+            qemu_img,
+            overcommit,
+            reconciler: Reconciler::default(),
+            default_pools: vec![],
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            host_root: PathBuf::from("/"),
+            versions: Arc::new(OnceLock::new()),
+            tls: None,
         })
     }
 
+    pub(crate) fn with_reconciler(mut self, reconciler: Reconciler) -> Self {
+        self.reconciler = reconciler;
+        self
+    }
+
+    /// Listen on `bind`, while the other nodes connect to the advertised address on `port`
+    pub(crate) fn with_listener(mut self, bind: SocketAddr, port: u16) -> Self {
+        self.bind = bind;
+        self.port = port;
+        self
+    }
+
+    pub(crate) fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    pub(crate) fn with_host_root(mut self, root: &Path) -> Self {
+        self.host_root = root.to_path_buf();
+        self
+    }
+
+    pub(crate) fn with_default_pools(mut self, pools: Vec<PoolConfig>) -> Self {
+        self.default_pools = pools;
+        self
+    }
+
+    pub(crate) fn with_tls(mut self, tls: Option<Tls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
     pub async fn get_cluster(&self) -> HashMap<Uuid, Node> {
         //self.skiff.get_cluster().await.unwrap()
         Node::list(&self.client)
@@ -78,21 +214,32 @@ impl Virtus {
             return Ok(client.clone());
         }
 
-        match VirtusClient::connect(format!(
-            "http://{}",
-            SocketAddrV4::new(node.get_addr(), 9400)
-        ))
-        .await
-        {
-            Ok(client) => {
-                let arc = Arc::new(Mutex::new(client));
+        // Addresses are tried in the order the node advertises them
+        for address in node.get_addrs() {
+            let endpoint = match self.endpoint(address, node.get_port()) {
+                Ok(endpoint) => endpoint,
+                Err(_) => continue,
+            };
+
+            if let Ok(channel) = endpoint.connect().await {
+                let arc = Arc::new(Mutex::new(VirtusClient::new(channel)));
                 self.peer_clients
                     .lock()
                     .await
                     .insert(peer.to_owned(), arc.clone());
-                Ok(arc)
+                return Ok(arc);
             }
-            Err(_) => Err(Error::PeerConnectFailed),
+        }
+
+        Err(Error::PeerConnectFailed)
+    }
+
+    /// Endpoint of another node's gRPC server, over TLS if we serve it over TLS
+    fn endpoint(&self, address: IpAddr, port: u16) -> Result<Endpoint, tonic::transport::Error> {
+        let endpoint = Endpoint::from_shared(node_uri(address, port, self.tls.is_some()))?;
+        match &self.tls {
+            Some(tls) => endpoint.tls_config(tls.client()),
+            None => Ok(endpoint),
         }
     }
 
@@ -100,20 +247,374 @@ impl Virtus {
         self.peer_clients.lock().await.remove(&id);
     }
 
-    pub async fn start(self) -> Result<(), anyhow::Error> {
+    /// Handles the request locally or forwards it according to `target`.
+    ///
+    /// `forward` is called with a client for the next hop, and should invoke the same RPC
+    /// on it.
+    async fn route<T, R, F, Fut>(
+        &self,
+        mut request: Request<T>,
+        target: Target,
+        forward: F,
+    ) -> Result<Routed<T, R>, Status>
+    where
+        F: FnOnce(VirtusClient<Channel>, Request<T>) -> Fut,
+        Fut: Future<Output = Result<Response<R>, Status>>,
+    {
+        let forwarded = request.metadata().get(FORWARDED).is_some();
+        let state = self.skiff.get_election_state().await;
+
+        match resolve(&state, self.id, target, forwarded) {
+            Ok(Route::Local) => Ok(Routed::Local(request)),
+            Ok(Route::Forward { node, mark }) => {
+                let client = match self.get_peer_client(&node).await {
+                    Ok(client) => client.lock().await.clone(),
+                    Err(_) => return Err(Status::internal("failed to connect to node")),
+                };
+
+                if mark {
+                    request
+                        .metadata_mut()
+                        .insert(FORWARDED, MetadataValue::from_static(""));
+                }
+
+                Ok(Routed::Forwarded(forward(client, request).await))
+            }
+            Err(_) => Err(Status::internal("no skiff leader elected")),
+        }
+    }
+
+    /// Returns the VM using `disk` if its domain is active on this node
+    async fn running_vm(&self, disk: Uuid) -> Result<Option<Uuid>, Status> {
+        let vms = match VM::list(&self.client).await {
+            Ok(vms) => vms,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        match vms.iter().find(|vm| vm.get_disks().contains(&disk)) {
+            Some(vm) => match self.hypervisor.state(vm.get_id()) {
+                // Suspended guests keep their images open too
+                Ok(DomainState::Running | DomainState::Paused | DomainState::Suspended) => {
+                    Ok(Some(vm.get_id()))
+                }
+                Ok(_) => Ok(None),
+                Err(e) => Err(Status::internal(e.to_string())),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if the domain of a VM using `disk` is active on this node
+    async fn ensure_not_running(&self, disk: Uuid) -> Result<(), Status> {
+        match self.running_vm(disk).await? {
+            Some(vm) => Err(Status::failed_precondition(format!(
+                "Disk is in use by running VM {}",
+                vm
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if a disk other than `ids` is layered on any of them
+    async fn ensure_not_backing(&self, ids: &[Uuid]) -> Result<(), Status> {
+        let disks = match Disk::list(&self.client).await {
+            Ok(disks) => disks,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        for disk in disks.iter().filter(|disk| !ids.contains(&disk.get_id())) {
+            if let Some(id) = disk.get_backing_chain().iter().find(|id| ids.contains(id)) {
+                return Err(Status::failed_precondition(format!(
+                    "{} is a backing file of disk {}",
+                    id,
+                    disk.get_id()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `node` accepts connections, without going through the cached client
+    async fn is_reachable(&self, node: Uuid) -> bool {
+        if node == self.id {
+            return true;
+        }
+
+        let record = match Node::get(node, &self.client).await {
+            Ok(Some(node)) => node,
+            _ => return false,
+        };
+
+        for address in record.get_addrs() {
+            let endpoint = match self.endpoint(address, record.get_port()) {
+                Ok(endpoint) => endpoint.connect_timeout(tokio::time::Duration::from_secs(1)),
+                Err(_) => continue,
+            };
+
+            if endpoint.connect().await.is_ok() {
+                return true;
+            }
+        }
+
+        self.drop_client(node).await;
+        false
+    }
+
+    /// Picks the node that handles a request for storage reachable from `nodes`, preferring
+    /// this one. A pool on a single node always goes to that node, shared pools go to the
+    /// first of their nodes that is up.
+    async fn pick_node(&self, nodes: &[Uuid]) -> Result<Uuid, Status> {
+        match nodes {
+            [] => Err(Status::failed_precondition(
+                "No node has access to the pool",
+            )),
+            [node] => Ok(*node),
+            nodes if nodes.contains(&self.id) => Ok(self.id),
+            nodes => {
+                for node in nodes {
+                    if self.is_reachable(*node).await {
+                        return Ok(*node);
+                    }
+                }
+
+                Err(Status::unavailable(
+                    "No node with access to the pool is reachable",
+                ))
+            }
+        }
+    }
+
+    /// The node that handles a request for `disk`. Disks attached to a VM are handled on the
+    /// VM's node if it can reach the pool, since a running domain holds a lock on the image.
+    async fn disk_node(&self, disk: &Disk) -> Result<Uuid, Status> {
+        let pool = match Pool::get(disk.get_pool_id(), &self.client).await {
+            Ok(Some(pool)) => pool,
+            Ok(None) => return Err(Status::internal("Disk pool not found")),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let vms = match VM::list(&self.client).await {
+            Ok(vms) => vms,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        match vms
+            .iter()
+            .find(|vm| vm.get_disks().contains(&disk.get_id()))
+        {
+            Some(vm) if pool.has_node(vm.get_node_id()) => Ok(vm.get_node_id()),
+            _ => self.pick_node(&pool.get_nodes()).await,
+        }
+    }
+
+    /// Streams `disk` into `pool` through the `ReceiveDisk` RPC of the pool's node. Once the
+    /// image is verified there, the records are switched over on the leader with
+    /// `CommitTransfer`. When `id` is the disk's own id the disk is moved, and the image left
+    /// behind is deleted afterwards.
+    ///
+    /// Overlays are flattened into a temporary image first, since their backing files don't
+    /// exist in the other pool.
+    async fn transfer(
+        &self,
+        disk: Disk,
+        pool: Pool,
+        id: Uuid,
+        name: Option<String>,
+    ) -> Result<Response<TransferStream>, Status> {
+        let path = match disk.get_path(&self.client).await {
+            Ok(path) => path,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let flattened = !disk.get_backing_chain().is_empty();
+        let send_path = match flattened {
+            true => {
+                let mut flat = path.as_os_str().to_owned();
+                flat.push(".transfer");
+                let flat = PathBuf::from(flat);
+
+                let format = disk.get_format().as_str();
+                if let Err(e) = self.qemu_img.convert(&path, format, &flat, format, None) {
+                    let _ = fs::remove_file(&flat);
+                    return Err(Status::internal(e.to_string()));
+                }
+                flat
+            }
+            false => path,
+        };
+
+        let length = match fs::metadata(&send_path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let node = self.pick_node(&pool.get_nodes()).await?;
+        let mut peer = match self.get_peer_client(&node).await {
+            Ok(client) => client.lock().await.clone(),
+            Err(_) => return Err(Status::internal("failed to connect to node")),
+        };
+
+        let header = TransferHeader {
+            disk: disk.get_id().to_string(),
+            pool: pool.get_id().to_string(),
+            id: id.to_string(),
+            name,
+            length,
+            flattened,
+        };
+
+        let (progress, updates) = mpsc::channel(16);
+        let virtus = self.clone();
+        tokio::spawn(async move {
+            let (chunks, reader) =
+                transfer::send(send_path.clone(), header.clone(), progress.clone());
+
+            let result = match peer.receive_disk(chunks).await {
+                // A failed read shows up as an incomplete transfer on the receiving side
+                Err(status) => match reader.await {
+                    Ok(Err(e)) => Err(Status::internal(e.to_string())),
+                    _ => Err(status),
+                },
+                Ok(_) => virtus_proto::virtus_server::Virtus::commit_transfer(
+                    &virtus,
+                    Request::new(header),
+                )
+                .await
+                .map(|_| ()),
+            };
+
+            // Only once no record refers to it anymore
+            let result = match result {
+                Ok(_) if id == disk.get_id() => {
+                    match Pool::get(disk.get_pool_id(), &virtus.client).await {
+                        Ok(Some(mut source)) => source
+                            .release_disk(&disk, &virtus.client)
+                            .await
+                            .map_err(|e| Status::internal(e.to_string())),
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(Status::internal(e.to_string())),
+                    }
+                }
+                result => result,
+            };
+
+            if flattened {
+                let _ = fs::remove_file(&send_path);
+            }
+
+            let _ = progress
+                .send(result.map(|_| TransferProgress {
+                    bytes_sent: length,
+                    total_bytes: length,
+                    disk: Some(id.to_string()),
+                }))
+                .await;
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(updates))))
+    }
+
+    /// Starts the gRPC server and registers this node once a leader is elected.
+    ///
+    /// Returns as soon as the server is listening, `Handle::ready` tells when the node is
+    /// usable.
+    pub async fn start(self) -> Result<Handle, anyhow::Error> {
+        let listener = tokio::net::TcpListener::bind(self.bind).await?;
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+
+        let started = tokio::time::Instant::now();
+        let (ready_tx, ready) = watch::channel(None);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        // Skiff keeps its own connections between nodes, only the gRPC server is covered
+        let mut builder = Server::builder();
+        if let Some(tls) = &self.tls {
+            builder = builder.tls_config(tls.server())?;
+        }
+
         let skiff_service = self.skiff.initialize_service();
         let virtus = self.clone();
-        let addr = self.address;
-        let _handle: JoinHandle<Result<(), anyhow::Error>> = tokio::spawn(async move {
-            Server::builder()
+        let stop = handle::requested(shutdown_rx.clone());
+        let server = tokio::spawn(async move {
+            builder
                 .add_service(skiff_service)
                 .add_service(VirtusServer::new(virtus))
-                .serve(SocketAddr::new(addr.into(), 9400))
-                .await?;
+                .serve_with_incoming_shutdown(incoming, stop)
+                .await
+        });
+
+        let background = tokio::spawn(async move {
+            let startup = tokio::select! {
+                result = self.initialize(started) => result,
+                _ = handle::requested(shutdown_rx.clone()) => return,
+            };
+            let failed = startup.is_err();
+            if let Err(e) = &startup {
+                log::error!("Startup failed: {}", e);
+            }
+            let _ = ready_tx.send(Some(startup.map_err(|e| e.to_string())));
+            if failed {
+                return;
+            }
 
-            Ok(())
+            self.run_background(started, shutdown_rx).await;
+            self.peer_clients.lock().await.clear();
         });
 
+        Ok(Handle::new(ready, shutdown, server, background))
+    }
+
+    /// Publishes a heartbeat together with a fresh inventory of the host
+    async fn heartbeat(&self, started: tokio::time::Instant) -> Result<(), Error> {
+        Heartbeat::new(self.id, started.elapsed(), self.heartbeat_interval)
+            .publish(&self.client)
+            .await?;
+
+        // A host that can't be inspected is still usable
+        match Inventory::discover(self.id, &self.host_root) {
+            Ok(inventory) => {
+                let (qemu_img, libvirt) = self.versions.get().cloned().unwrap_or_default();
+                inventory
+                    .with_versions(qemu_img, libvirt)
+                    .publish(&self.client)
+                    .await
+            }
+            Err(e) => {
+                log::warn!("Host inventory failed: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// A node as returned by `GetNode`, with its status
+    async fn describe_node(&self, node: Node) -> virtus_proto::Node {
+        let id = node.get_id();
+        let (status, heartbeat) = heartbeat::status(id, &self.client).await;
+        let mut node: virtus_proto::Node = node.into();
+        node.set_status(status.into());
+        node.heartbeat = heartbeat.map(|heartbeat| heartbeat.into());
+        node.inventory = match Inventory::get(id, &self.client).await {
+            Ok(inventory) => inventory.map(|inventory| inventory.into()),
+            Err(_) => None,
+        };
+        node
+    }
+
+    /// Fails fast if `node` stopped sending heartbeats, instead of waiting for a connection
+    /// to time out. Nodes that never sent one are given the benefit of the doubt.
+    async fn ensure_ready(&self, node: Uuid) -> Result<(), Status> {
+        match heartbeat::status(node, &self.client).await {
+            (NodeStatus::NotReady, _) => {
+                Err(Status::unavailable(format!("Node {} is not ready", node)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for a leader and registers this node and its default pools
+    async fn initialize(&self, started: tokio::time::Instant) -> Result<(), anyhow::Error> {
         while !self.skiff.is_leader_elected().await {
             // todo: ideally skiff implements a better way to notify on ready without polling
             // Wait for one election timeout
@@ -122,43 +623,294 @@ impl Virtus {
 
         let hostname = hostname::get().unwrap().into_string().unwrap();
 
-        // Create a node associated with this server
+        // Create a node associated with this server, unless it is restarting with a persisted id
         // The node's id matches virtus id
-        Node::create(self.id, hostname.as_str(), self.address, &self.client).await?;
+        let mut node = match Node::get(self.id, &self.client).await? {
+            Some(mut node) => {
+                node.advertise(self.addresses.clone(), self.port, &self.client)
+                    .await?;
+                node
+            }
+            None => {
+                Node::create(
+                    self.id,
+                    hostname.as_str(),
+                    self.addresses.clone(),
+                    self.port,
+                    &self.client,
+                )
+                .await?
+            }
+        };
+
+        let pools = node.list_pools(self.client.clone()).await?;
+        for pool in &self.default_pools {
+            if pools
+                .iter()
+                .any(|existing| existing.get_path() == pool.path)
+            {
+                continue;
+            }
+
+            node.create_pool(
+                &pool.path,
+                pool.name.as_deref(),
+                pool.backend(),
+                &[],
+                &self.client,
+            )
+            .await?;
+            log::info!("Created default pool {}", pool.path);
+        }
+
+        // Tools are only upgraded with a restart, so each heartbeat just rereads the host
+        self.versions.get_or_init(|| {
+            (
+                self.qemu_img.version().ok(),
+                self.hypervisor.libvirt_version(),
+            )
+        });
+
+        // Ready as soon as the node is usable, rather than after the first interval
+        self.heartbeat(started).await?;
+
+        log::info!(
+            "Node {} serving on {}, advertised as {}",
+            self.id,
+            self.bind,
+            self.addresses
+                .iter()
+                .map(|address| SocketAddr::new(*address, self.port).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
 
         Ok(())
     }
+
+    /// Runs the periodic tasks of a started node until shutdown is requested
+    async fn run_background(&self, started: tokio::time::Instant, shutdown: watch::Receiver<bool>) {
+        let mut tasks = JoinSet::new();
+
+        let virtus = self.clone();
+        let stop = handle::requested(shutdown.clone());
+        tasks.spawn(async move {
+            tokio::pin!(stop);
+            let start = tokio::time::Instant::now() + virtus.heartbeat_interval;
+            let mut ticker = tokio::time::interval_at(start, virtus.heartbeat_interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = &mut stop => return,
+                }
+                // A node that was removed from the cluster stops reporting
+                if let Ok(Some(_)) = Node::get(virtus.id, &virtus.client).await {
+                    if let Err(e) = virtus.heartbeat(started).await {
+                        log::warn!("Heartbeat failed: {}", e);
+                    }
+                }
+            }
+        });
+
+        if let Some(interval) = self.reconciler.get_interval() {
+            let virtus = self.clone();
+            let stop = handle::requested(shutdown.clone());
+            tasks.spawn(async move {
+                tokio::pin!(stop);
+                let start = tokio::time::Instant::now() + interval;
+                let mut ticker = tokio::time::interval_at(start, interval);
+                loop {
+                    tokio::select! {
+                        _ = ticker.tick() => {}
+                        _ = &mut stop => return,
+                    }
+                    // Failures are retried on the next tick
+                    if let Err(e) = virtus
+                        .reconciler
+                        .reconcile(virtus.id, &virtus.qemu_img, &virtus.client)
+                        .await
+                    {
+                        log::warn!("Drift scan failed: {}", e);
+                    }
+                }
+            });
+        }
+
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+/// gRPC endpoint of a node, with IPv6 addresses in brackets
+fn node_uri(address: IpAddr, port: u16, tls: bool) -> String {
+    let scheme = match tls {
+        true => "https",
+        false => "http",
+    };
+    format!("{}://{}", scheme, SocketAddr::new(address, port))
 }
 
 #[tonic::async_trait]
 impl virtus_proto::virtus_server::Virtus for Virtus {
+    type CopyDiskStream = TransferStream;
+    type MoveDiskStream = TransferStream;
+    type DownloadDiskStream = DownloadStream;
+
     async fn add_node(
         &self,
         request: Request<AddNodeRequest>,
     ) -> Result<Response<AddNodeReply>, Status> {
-        todo!()
+        let addresses = match std::iter::once(&request.get_ref().ip)
+            .chain(&request.get_ref().ips)
+            .map(|ip| IpAddr::from_str(ip))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(addresses) => addresses,
+            Err(_) => return Err(Status::invalid_argument("Invalid node IP")),
+        };
+        // Skiff only speaks IPv4
+        let address = match node::skiff_address(&addresses) {
+            Some(address) => address,
+            None => {
+                return Err(Status::invalid_argument(
+                    "Node needs an IPv4 address to join the cluster",
+                ))
+            }
+        };
+
+        let id = match request.get_ref().id.as_deref().map(Uuid::parse_str) {
+            Some(Ok(id)) => id,
+            Some(Err(_)) => return Err(Status::invalid_argument("Invalid node ID")),
+            None => Uuid::new_v4(),
+        };
+
+        let port = match request.get_ref().port.map(u16::try_from) {
+            Some(Ok(0)) | Some(Err(_)) => return Err(Status::invalid_argument("Invalid port")),
+            Some(Ok(port)) => port,
+            None => DEFAULT_PORT,
+        };
+
+        // Membership changes go through the leader
+        let request = match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.add_node(request).await
+            })
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match Node::list(&self.client).await {
+            Ok(nodes) => {
+                // Several nodes can share a host on different ports
+                if nodes.iter().any(|n| {
+                    n.get_id() == id
+                        || (n.get_port() == port
+                            && n.get_addrs().iter().any(|a| addresses.contains(a)))
+                }) {
+                    return Err(Status::already_exists("Node already exists"));
+                }
+            }
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+
+        if let Err(e) = self.skiff.add_server(id, address).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        match Node::create(id, inner.hostname.as_str(), addresses, port, &self.client).await {
+            Ok(node) => Ok(Response::new(AddNodeReply {
+                success: true,
+                id: Some(node.get_id().to_string()),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
     }
 
     async fn remove_node(
         &self,
         request: Request<RemoveNodeRequest>,
     ) -> Result<Response<RemoveNodeReply>, Status> {
-        todo!()
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
+        };
+
+        let node = match Node::get(id, &self.client).await.unwrap() {
+            Some(node) => node,
+            None => return Err(Status::not_found("Node not found")),
+        };
+
+        if node.has_resources() {
+            return Err(Status::failed_precondition("Node still owns pools or VMs"));
+        }
+
+        // Membership changes go through the leader
+        match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.remove_node(request).await
+            })
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => {
+                if reply.is_ok() {
+                    self.drop_client(id).await;
+                }
+
+                return reply;
+            }
+        }
+
+        if id == self.id {
+            return Err(Status::failed_precondition("Cannot remove the leader"));
+        }
+
+        if let Err(e) = self.skiff.remove_server(id).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        if let Err(e) = node.delete(&self.client).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        if let Err(e) = Heartbeat::delete(id, &self.client).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        if let Err(e) = Inventory::delete(id, &self.client).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        self.drop_client(id).await;
+        Ok(Response::new(RemoveNodeReply { success: true }))
     }
 
     async fn get_node(
         &self,
         request: Request<GetNodeRequest>,
     ) -> Result<Response<GetNodeReply>, Status> {
-        let id = match Uuid::from_str(&request.into_inner().id) {
+        let id = match Uuid::from_str(&request.get_ref().id) {
             Ok(id) => id,
             Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
         };
 
+        // The leader derives the status, so every node reports the same
+        if let Routed::Forwarded(reply) = self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.get_node(request).await
+            })
+            .await?
+        {
+            return reply;
+        }
+
         match Node::get(id, &self.client).await {
-            Ok(node) => Ok(Response::new(GetNodeReply {
-                node: node.map(|n| n.into()),
+            Ok(Some(node)) => Ok(Response::new(GetNodeReply {
+                node: Some(self.describe_node(node).await),
             })),
+            Ok(None) => Ok(Response::new(GetNodeReply { node: None })),
             Err(e) => return Err(Status::internal(e.to_string())),
         }
     }
@@ -167,73 +919,94 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
         &self,
         request: Request<Empty>,
     ) -> Result<Response<ListNodesReply>, Status> {
-        match Node::list(&self.client).await {
-            Ok(nodes) => Ok(Response::new(ListNodesReply {
-                nodes: nodes.into_iter().map(|n| n.get_id().to_string()).collect(),
-            })),
+        if let Routed::Forwarded(reply) = self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.list_nodes(request).await
+            })
+            .await?
+        {
+            return reply;
+        }
+
+        let nodes = match Node::list(&self.client).await {
+            Ok(nodes) => nodes,
             Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let mut statuses = HashMap::new();
+        for node in &nodes {
+            let (status, _) = heartbeat::status(node.get_id(), &self.client).await;
+            statuses.insert(
+                node.get_id().to_string(),
+                virtus_proto::NodeStatus::from(status).into(),
+            );
         }
+
+        Ok(Response::new(ListNodesReply {
+            nodes: nodes.into_iter().map(|n| n.get_id().to_string()).collect(),
+            statuses,
+        }))
     }
 
     async fn add_pool(
         &self,
         request: Request<AddPoolRequest>,
     ) -> Result<Response<AddPoolReply>, Status> {
-        let (mut metadata, extensions, inner) = request.into_parts();
-
-        let node_id = match Uuid::parse_str(&inner.node) {
+        let node_id = match Uuid::parse_str(&request.get_ref().node) {
             Ok(id) => id,
             Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
         };
 
-        if Node::get(node_id, &self.client).await.unwrap().is_none() {
-            return Err(Status::invalid_argument("Node not found"));
-        }
+        let mut node = match Node::get(node_id, &self.client).await.unwrap() {
+            Some(node) => node,
+            None => return Err(Status::invalid_argument("Node not found")),
+        };
 
-        match self.skiff.get_election_state().await {
-            ElectionState::Leader => {
-                if inner.node != self.id.to_string() {
-                    let client = self.get_peer_client(&node_id).await;
-                    if let Ok(client_inner) = client {
-                        // Indicate that this is forwarded from leader
-                        // Todo: more rigorous way to indicate forwarded request
-                        metadata.append("forwarded", MetadataValue::from_static(""));
-                        return client_inner
-                            .lock()
-                            .await
-                            .add_pool(Request::from_parts(metadata, extensions, inner))
-                            .await;
-                    }
+        let backend = BackendKind::from(request.get_ref().backend());
 
-                    return Err(Status::internal("failed to connect to node"));
-                }
-            }
-            ElectionState::Follower(leader) => {
-                // Check if the request is from the leader
-                if metadata.get("forwarded").is_none() {
-                    // Forward to leader
-                    let client = self.get_peer_client(&leader).await;
-                    if let Ok(client_inner) = client {
-                        return client_inner
-                            .lock()
-                            .await
-                            .add_pool(Request::from_parts(metadata, extensions, inner))
-                            .await;
-                    }
+        let mut others = Vec::new();
+        for other in &request.get_ref().nodes {
+            let other = match Uuid::parse_str(other) {
+                Ok(id) => id,
+                Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
+            };
 
-                    return Err(Status::internal("failed to forward request to leader"));
-                }
+            if Node::get(other, &self.client).await.unwrap().is_none() {
+                return Err(Status::invalid_argument("Node not found"));
             }
-            ElectionState::Candidate => return Err(Status::internal("no skiff leader elected")),
+            others.push(other);
         }
 
-        match Pool::create(
-            self.id,
-            inner.path.as_str(),
-            inner.name.as_deref(),
-            &self.client,
-        )
-        .await
+        if !others.is_empty() && !backend.backend().is_shared() {
+            return Err(Status::invalid_argument(
+                "Only shared pools can have more than one node",
+            ));
+        }
+
+        self.ensure_ready(node_id).await?;
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.add_pool(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match node
+            .create_pool(
+                inner.path.as_str(),
+                inner.name.as_deref(),
+                backend,
+                &others,
+                &self.client,
+            )
+            .await
         {
             Ok(pool) => {
                 return Ok(Response::new(AddPoolReply {
@@ -241,6 +1014,11 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
                     id: Some(pool.get_id().to_string()),
                 }))
             }
+            Err(Error::PoolUnavailable(path)) => Err(Status::failed_precondition(format!(
+                "Pool storage is not mounted at {}",
+                path
+            ))),
+            Err(Error::InvalidPool(e)) => Err(Status::invalid_argument(e)),
             Err(e) => return Err(Status::internal(e.to_string())),
         }
     }
@@ -249,7 +1027,70 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
         &self,
         request: Request<RemovePoolRequest>,
     ) -> Result<Response<RemovePoolReply>, Status> {
-        todo!()
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid pool ID")),
+        };
+
+        let pool = match Pool::get(id, &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::not_found("Pool not found")),
+        };
+
+        if !pool.get_disks().is_empty() {
+            if !request.get_ref().cascade {
+                return Err(Status::failed_precondition("Pool still contains disks"));
+            }
+
+            match VM::list(&self.client).await {
+                Ok(vms) => {
+                    let disks = pool.get_disks();
+                    if let Some(vm) = vms
+                        .iter()
+                        .find(|vm| vm.get_disks().iter().any(|d| disks.contains(d)))
+                    {
+                        return Err(Status::failed_precondition(format!(
+                            "Pool has disks attached to VM {}",
+                            vm.get_id()
+                        )));
+                    }
+                }
+                Err(e) => return Err(Status::internal(e.to_string())),
+            }
+
+            self.ensure_not_backing(&pool.get_disks()).await?;
+        }
+
+        let node_id = self.pick_node(&pool.get_nodes()).await?;
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.remove_pool(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        let mut node = match Node::get(node_id, &self.client).await.unwrap() {
+            Some(node) => node,
+            None => return Err(Status::internal("Pool node not found")),
+        };
+
+        match node
+            .remove_pool(pool, inner.cascade, inner.remove_dir, &self.client)
+            .await
+        {
+            Ok(_) => Ok(Response::new(RemovePoolReply { success: true })),
+            Err(Error::PoolNotEmpty) => {
+                Err(Status::failed_precondition("Pool still contains disks"))
+            }
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
     }
 
     async fn get_pool(
@@ -285,64 +1126,105 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
         &self,
         request: Request<AddDiskRequest>,
     ) -> Result<Response<AddDiskReply>, Status> {
-        let (mut metadata, extensions, inner) = request.into_parts();
-
-        let pool_id = match Uuid::parse_str(&inner.pool) {
+        let pool_id = match Uuid::parse_str(&request.get_ref().pool) {
             Ok(id) => id,
             Err(_) => return Err(Status::invalid_argument("Invalid pool ID")),
         };
 
-        let pool = match Pool::get(pool_id, &self.client).await.unwrap() {
+        let mut pool = match Pool::get(pool_id, &self.client).await.unwrap() {
             Some(pool) => pool,
             None => return Err(Status::invalid_argument("Pool not found")),
         };
 
-        let node_id = pool.get_node_id();
+        // Nodes that can handle the request, narrowed down to those that can also read the
+        // source
+        let mut nodes = pool.get_nodes();
 
-        match self.skiff.get_election_state().await {
-            ElectionState::Leader => {
-                if node_id != self.id {
-                    let client = self.get_peer_client(&node_id).await;
-                    if let Ok(client_inner) = client {
-                        // Indicate that this is forwarded from leader
-                        // Todo: more rigorous way to indicate forwarded request
-                        metadata.append("forwarded", MetadataValue::from_static(""));
-                        return client_inner
-                            .lock()
-                            .await
-                            .add_disk(Request::from_parts(metadata, extensions, inner))
-                            .await;
+        let source = match request.get_ref().source.as_deref().map(Uuid::parse_str) {
+            Some(Ok(id)) => Some(id),
+            Some(Err(_)) => return Err(Status::invalid_argument("Invalid source ID")),
+            None => None,
+        };
+
+        if let Some(source) = source {
+            match Disk::get(source, &self.client).await.unwrap() {
+                Some(source_disk) => {
+                    match Pool::get(source_disk.get_pool_id(), &self.client)
+                        .await
+                        .unwrap()
+                    {
+                        Some(source_pool) => nodes.retain(|node| source_pool.has_node(*node)),
+                        None => return Err(Status::internal("Source disk pool not found")),
                     }
 
-                    return Err(Status::internal("failed to connect to node"));
-                }
-            }
-            ElectionState::Follower(leader) => {
-                // Check if the request is from the leader
-                if metadata.get("forwarded").is_none() {
-                    // Forward to leader
-                    let client = self.get_peer_client(&leader).await;
-                    if let Ok(client_inner) = client {
-                        return client_inner
-                            .lock()
-                            .await
-                            .add_disk(Request::from_parts(metadata, extensions, inner))
-                            .await;
+                    if nodes.is_empty() {
+                        return Err(Status::failed_precondition(
+                            "Source disk is on a different node",
+                        ));
                     }
 
-                    return Err(Status::internal("failed to forward request to leader"));
+                    // Overlays and copies of a disk that is being written to would be
+                    // inconsistent
+                    match VM::list(&self.client).await {
+                        Ok(vms) => {
+                            if let Some(vm) = vms.iter().find(|vm| vm.get_disks().contains(&source))
+                            {
+                                return Err(Status::failed_precondition(format!(
+                                    "Source disk is attached to VM {}",
+                                    vm.get_id()
+                                )));
+                            }
+                        }
+                        Err(e) => return Err(Status::internal(e.to_string())),
+                    }
                 }
+                None => match Image::get(source, &self.client).await.unwrap() {
+                    Some(image) => {
+                        nodes.retain(|node| image.get_path(*node).is_some());
+                        if nodes.is_empty() {
+                            return Err(Status::failed_precondition(
+                                "Source image has no copy on the pool's node",
+                            ));
+                        }
+                    }
+                    None => return Err(Status::not_found("Source disk or image not found")),
+                },
             }
-            ElectionState::Candidate => return Err(Status::internal("no skiff leader elected")),
         }
 
-        match Disk::create(
-            pool_id,
-            inner.size_gb as usize,
-            inner.name.as_deref(),
-            &self.client,
-        )
-        .await
+        let node_id = self.pick_node(&nodes).await?;
+        self.ensure_ready(node_id).await?;
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.add_disk(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match pool
+            .create_disk(
+                inner.size_gb as usize,
+                inner.name.as_deref(),
+                &DiskOptions {
+                    format: inner.format().into(),
+                    preallocation: inner.preallocation().into(),
+                    cluster_size: inner.cluster_size,
+                    lazy_refcounts: inner.lazy_refcounts,
+                    source,
+                    full_copy: inner.full_copy,
+                },
+                self.overcommit,
+                &self.qemu_img,
+                &self.client,
+            )
+            .await
         {
             Ok(disk) => {
                 return Ok(Response::new(AddDiskReply {
@@ -350,6 +1232,8 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
                     id: Some(disk.get_id().to_string()),
                 }))
             }
+            Err(Error::InvalidDisk(e)) => return Err(Status::invalid_argument(e)),
+            Err(Error::PoolFull) => return Err(Status::resource_exhausted("Pool is full")),
             Err(e) => return Err(Status::internal(e.to_string())),
         }
     }
@@ -358,293 +1242,3512 @@ impl virtus_proto::virtus_server::Virtus for Virtus {
         &self,
         request: Request<RemoveDiskRequest>,
     ) -> Result<Response<RemoveDiskReply>, Status> {
-        todo!()
-    }
-
-    async fn get_disk(
-        &self,
-        request: Request<GetDiskRequest>,
-    ) -> Result<Response<GetDiskReply>, Status> {
-        let id = match Uuid::from_str(&request.into_inner().id) {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
             Ok(id) => id,
             Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
         };
 
-        match Disk::get(id, &self.client).await {
-            Ok(disk) => Ok(Response::new(GetDiskReply {
-                disk: disk.map(|d| d.into()),
-            })),
-            Err(e) => return Err(Status::internal(e.to_string())),
-        }
-    }
+        let disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
 
-    async fn list_disks(
-        &self,
-        request: Request<Empty>,
-    ) -> Result<Response<ListDisksReply>, Status> {
-        match Disk::list(&self.client).await {
-            Ok(disks) => Ok(Response::new(ListDisksReply {
-                disks: disks.into_iter().map(|d| d.get_id().to_string()).collect(),
-            })),
+        let mut pool = match Pool::get(disk.get_pool_id(), &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::internal("Disk pool not found")),
+        };
+
+        match VM::list(&self.client).await {
+            Ok(vms) => {
+                if let Some(vm) = vms.iter().find(|vm| vm.get_disks().contains(&id)) {
+                    return Err(Status::failed_precondition(format!(
+                        "Disk is attached to VM {}",
+                        vm.get_id()
+                    )));
+                }
+            }
             Err(e) => return Err(Status::internal(e.to_string())),
         }
-    }
 
-    async fn add_network(
-        &self,
-        request: Request<AddNetworkRequest>,
-    ) -> Result<Response<AddNetworkReply>, Status> {
-        todo!()
-    }
+        self.ensure_not_backing(&[id]).await?;
 
-    async fn remove_network(
-        &self,
-        request: Request<RemoveNetworkRequest>,
-    ) -> Result<Response<RemoveNetworkReply>, Status> {
-        todo!()
-    }
+        let node_id = self.disk_node(&disk).await?;
 
-    async fn get_network(
-        &self,
-        request: Request<GetNetworkRequest>,
-    ) -> Result<Response<GetNetworkReply>, Status> {
-        todo!()
+        match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.remove_disk(request).await },
+            )
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        match pool.remove_disk(disk, &self.client).await {
+            Ok(_) => Ok(Response::new(RemoveDiskReply { success: true })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
     }
 
-    async fn list_networks(
+    async fn resize_disk(
         &self,
-        request: Request<Empty>,
-    ) -> Result<Response<ListNetworksReply>, Status> {
-        todo!()
-    }
-}
+        request: Request<ResizeDiskRequest>,
+    ) -> Result<Response<ResizeDiskReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
 
-#[cfg(test)]
-mod tests {
-    use std::fs;
-    use std::path::Path;
+        let mut disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
 
-    use super::*;
-    use crate::pool::Pool;
-    use crate::Builder;
-    use serial_test::serial;
+        let mut pool = match Pool::get(disk.get_pool_id(), &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::internal("Disk pool not found")),
+        };
 
-    fn get_virtus() -> Result<Virtus, anyhow::Error> {
-        let dir = String::from("target/tmp/test/127.0.0.1");
-        if Path::exists(Path::new("target/tmp/test")) {
-            fs::remove_dir_all("target/tmp/test")?;
+        // Overlays rely on the contents of their backing file staying the same
+        if (request.get_ref().size_gb as usize) < disk.get_size_gb() {
+            self.ensure_not_backing(&[id]).await?;
         }
 
-        Ok(Builder::new()
-            .bind("127.0.0.1".parse().unwrap())
-            .set_dir(&dir)
-            .build()
-            .unwrap())
-    }
+        let node_id = self.disk_node(&disk).await?;
 
-    fn get_follower(address: &str) -> Result<Virtus, anyhow::Error> {
-        let dir = format!("target/tmp/test/{}", &address);
-        if Path::exists(Path::new(&dir)) {
-            fs::remove_dir_all(&dir)?;
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.resize_disk(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        // A running domain holds the image open, so it has to be resized through the hypervisor
+        let running = self.running_vm(id).await?;
+
+        if running.is_some() && (inner.size_gb as usize) < disk.get_size_gb() {
+            return Err(Status::failed_precondition(
+                "Can't shrink the disk of a running VM",
+            ));
         }
 
-        Ok(Builder::new()
-            .bind(address.parse().unwrap())
-            .set_dir(&dir)
-            .join_cluster(vec!["127.0.0.1".parse().unwrap()])
-            .build()
-            .unwrap())
+        match pool
+            .resize_disk(
+                &mut disk,
+                inner.size_gb as usize,
+                inner.force,
+                self.overcommit,
+                match running {
+                    Some(vm) => Resizer::Live {
+                        vm,
+                        hypervisor: self.hypervisor.as_ref(),
+                    },
+                    None => Resizer::Offline(&self.qemu_img),
+                },
+                &self.client,
+            )
+            .await
+        {
+            Ok(_) => Ok(Response::new(ResizeDiskReply { success: true })),
+            Err(Error::InvalidDisk(e)) => Err(Status::invalid_argument(e)),
+            Err(Error::PoolFull) => Err(Status::resource_exhausted("Pool is full")),
+            Err(Error::DiskLocked(e)) => Err(Status::failed_precondition(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
     }
 
-    async fn get_client(address: &str) -> Result<VirtusClient<Channel>, anyhow::Error> {
-        Ok(VirtusClient::connect(format!(
-            "http://{}",
-            SocketAddrV4::new(address.parse().unwrap(), 9400)
-        ))
-        .await?)
-    }
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CreateSnapshotReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().disk) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
 
-    #[tokio::test]
-    #[serial]
-    async fn start_server() {
-        let virtus = get_virtus().unwrap();
+        let mut disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
 
-        let virtus_clone = virtus.clone();
-        let handle = tokio::spawn(async move {
-            let _ = virtus_clone.start().await;
-        });
+        let node_id = self.disk_node(&disk).await?;
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.create_snapshot(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
 
-        assert_eq!(
-            1,
-            virtus
-                .client
-                .lock()
-                .await
-                .list_keys("nodes/")
-                .await
-                .unwrap()
-                .len()
-        );
+        // qemu-img can't open images that a running domain has locked
+        self.ensure_not_running(id).await?;
+
+        match disk
+            .create_snapshot(&inner.name, &self.qemu_img, &self.client)
+            .await
+        {
+            Ok(snapshot) => Ok(Response::new(CreateSnapshotReply {
+                success: true,
+                snapshot: Some(snapshot.into()),
+            })),
+            Err(Error::SnapshotExists) => Err(Status::already_exists("Snapshot already exists")),
+            Err(Error::DiskLocked(e)) => Err(Status::failed_precondition(e)),
+            Err(Error::InvalidDisk(e)) => Err(Status::invalid_argument(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
     }
 
-    #[tokio::test]
-    #[serial]
-    async fn list_nodes() {
-        let virtus = get_virtus().unwrap();
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsReply>, Status> {
+        let id = match Uuid::from_str(&request.into_inner().disk) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
 
-        let virtus_clone = virtus.clone();
-        let handle = tokio::spawn(async move {
-            let _ = virtus_clone.start().await;
-        });
+        match Disk::get(id, &self.client).await {
+            Ok(Some(disk)) => Ok(Response::new(ListSnapshotsReply {
+                snapshots: disk
+                    .get_snapshots()
+                    .into_iter()
+                    .map(|snapshot| snapshot.into())
+                    .collect(),
+            })),
+            Ok(None) => Err(Status::not_found("Disk not found")),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    async fn revert_snapshot(
+        &self,
+        request: Request<RevertSnapshotRequest>,
+    ) -> Result<Response<RevertSnapshotReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().disk) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
 
-        assert_eq!(1, Node::list(&virtus.client).await.unwrap().len());
-    }
+        let mut disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
 
-    #[tokio::test]
-    #[serial]
-    async fn create_pool() {
-        let virtus = get_virtus().unwrap();
+        let mut pool = match Pool::get(disk.get_pool_id(), &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::internal("Disk pool not found")),
+        };
 
-        let virtus_clone = virtus.clone();
-        let handle = tokio::spawn(async move {
-            let _ = virtus_clone.start().await;
-        });
+        // Overlays rely on the contents of their backing file staying the same
+        self.ensure_not_backing(&[id]).await?;
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        let node_id = self.disk_node(&disk).await?;
 
-        assert_eq!(
-            Vec::<Pool>::new(),
-            Pool::list(&virtus.client).await.unwrap()
-        );
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.revert_snapshot(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
 
-        let mut node = Node::list(&virtus.client)
-            .await
-            .unwrap()
-            .first()
-            .unwrap()
-            .clone();
+        self.ensure_not_running(id).await?;
 
-        let pool = node
-            .create_pool("target/tmp/test/pool1", None, &virtus.client)
+        match pool
+            .revert_disk_snapshot(&mut disk, &inner.name, &self.qemu_img, &self.client)
             .await
-            .unwrap();
+        {
+            Ok(_) => Ok(Response::new(RevertSnapshotReply { success: true })),
+            Err(Error::SnapshotNotFound) => Err(Status::not_found("Snapshot not found")),
+            Err(Error::DiskLocked(e)) => Err(Status::failed_precondition(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
 
-        assert_eq!(1, Pool::list(&virtus.client).await.unwrap().len());
+    async fn delete_snapshot(
+        &self,
+        request: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<DeleteSnapshotReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().disk) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
 
-        let node = Node::list(&virtus.client)
-            .await
-            .unwrap()
-            .first()
-            .unwrap()
-            .clone();
+        let mut disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
 
-        assert_eq!(pool.get_node_id(), node.get_id());
+        let node_id = self.disk_node(&disk).await?;
 
-        let node_pools: Vec<Uuid> = node
-            .list_pools(virtus.client.clone())
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|p| p.get_id())
-            .collect();
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.delete_snapshot(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
 
-        assert_eq!(vec![pool.get_id()], node_pools);
+        self.ensure_not_running(id).await?;
+
+        match disk
+            .delete_snapshot(&inner.name, &self.qemu_img, &self.client)
+            .await
+        {
+            Ok(_) => Ok(Response::new(DeleteSnapshotReply { success: true })),
+            Err(Error::SnapshotNotFound) => Err(Status::not_found("Snapshot not found")),
+            Err(Error::DiskLocked(e)) => Err(Status::failed_precondition(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
     }
 
-    #[tokio::test]
+    async fn copy_disk(
+        &self,
+        request: Request<CopyDiskRequest>,
+    ) -> Result<Response<Self::CopyDiskStream>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
+
+        let pool_id = match Uuid::parse_str(&request.get_ref().pool) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid pool ID")),
+        };
+
+        let disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let pool = match Pool::get(pool_id, &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::not_found("Pool not found")),
+        };
+
+        let node_id = self.disk_node(&disk).await?;
+
+        // The image is read on the node that has it
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move {
+                    client
+                        .copy_disk(request)
+                        .await
+                        .map(|reply| reply.map(|stream| Box::pin(stream) as TransferStream))
+                },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        // A running guest would change the image while it is being read
+        self.ensure_not_running(id).await?;
+
+        self.transfer(disk, pool, Uuid::new_v4(), inner.name).await
+    }
+
+    async fn move_disk(
+        &self,
+        request: Request<MoveDiskRequest>,
+    ) -> Result<Response<Self::MoveDiskStream>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
+
+        let pool_id = match Uuid::parse_str(&request.get_ref().pool) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid pool ID")),
+        };
+
+        let disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let pool = match Pool::get(pool_id, &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::not_found("Pool not found")),
+        };
+
+        if disk.get_pool_id() == pool_id {
+            return Err(Status::invalid_argument("Disk is already in that pool"));
+        }
+
+        // The domain definition refers to the image where it is now
+        match VM::list(&self.client).await {
+            Ok(vms) => {
+                if let Some(vm) = vms.iter().find(|vm| vm.get_disks().contains(&id)) {
+                    return Err(Status::failed_precondition(format!(
+                        "Disk is attached to VM {}",
+                        vm.get_id()
+                    )));
+                }
+            }
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+
+        // Overlays refer to their backing files by path
+        self.ensure_not_backing(&[id]).await?;
+
+        let node_id = self.disk_node(&disk).await?;
+
+        match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move {
+                    client
+                        .move_disk(request)
+                        .await
+                        .map(|reply| reply.map(|stream| Box::pin(stream) as TransferStream))
+                },
+            )
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        self.transfer(disk, pool, id, None).await
+    }
+
+    async fn receive_disk(
+        &self,
+        request: Request<Streaming<DiskChunk>>,
+    ) -> Result<Response<ReceiveDiskReply>, Status> {
+        let mut stream = request.into_inner();
+
+        let first = match stream.message().await? {
+            Some(chunk) => chunk,
+            None => return Err(Status::invalid_argument("Empty transfer")),
+        };
+
+        let header = match &first.header {
+            Some(header) => header.clone(),
+            None => return Err(Status::invalid_argument("Transfer is missing its header")),
+        };
+
+        let (disk_id, pool_id, id) = match (
+            Uuid::parse_str(&header.disk),
+            Uuid::parse_str(&header.pool),
+            Uuid::parse_str(&header.id),
+        ) {
+            (Ok(disk_id), Ok(pool_id), Ok(id)) => (disk_id, pool_id, id),
+            _ => return Err(Status::invalid_argument("Invalid transfer header")),
+        };
+
+        let source = match Disk::get(disk_id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let mut pool = match Pool::get(pool_id, &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::not_found("Pool not found")),
+        };
+
+        // Sent straight from the node with the image, without going through the leader
+        if !pool.has_node(self.id) {
+            return Err(Status::failed_precondition("Pool is on another node"));
+        }
+
+        if let Err(e) = pool.refresh(&self.client).await {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        if pool
+            .check_capacity(source.get_size(), self.overcommit)
+            .is_err()
+        {
+            return Err(Status::resource_exhausted("Pool is full"));
+        }
+
+        let path = pool.disk_path(id, source.get_format());
+        let mut incoming = match Incoming::create(&path, header.length).await {
+            Ok(incoming) => incoming,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let mut chunk = first;
+        let checksum = loop {
+            if let Err(e) = incoming.write(&chunk.data).await {
+                incoming.abort().await;
+                return Err(Status::invalid_argument(e.to_string()));
+            }
+
+            if let Some(checksum) = chunk.checksum {
+                break checksum;
+            }
+
+            chunk = match stream.message().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    incoming.abort().await;
+                    return Err(Status::data_loss("Transfer ended before its checksum"));
+                }
+                Err(status) => {
+                    incoming.abort().await;
+                    return Err(status);
+                }
+            };
+        };
+
+        // The sending node commits the records once we're done
+        match incoming.finish(&checksum).await {
+            Ok(_) => Ok(Response::new(ReceiveDiskReply { success: true })),
+            Err(e) => Err(Status::data_loss(e.to_string())),
+        }
+    }
+
+    async fn commit_transfer(
+        &self,
+        request: Request<TransferHeader>,
+    ) -> Result<Response<CommitTransferReply>, Status> {
+        let header = request.get_ref();
+        let (disk_id, pool_id, id) = match (
+            Uuid::parse_str(&header.disk),
+            Uuid::parse_str(&header.pool),
+            Uuid::parse_str(&header.id),
+        ) {
+            (Ok(disk_id), Ok(pool_id), Ok(id)) => (disk_id, pool_id, id),
+            _ => return Err(Status::invalid_argument("Invalid transfer header")),
+        };
+
+        // Both pools and the disk change together, in one place
+        let request = match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.commit_transfer(request).await
+            })
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let header = request.into_inner();
+
+        let source = match Disk::get(disk_id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let mut pool = match Pool::get(pool_id, &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::not_found("Pool not found")),
+        };
+
+        match pool
+            .receive_disk(
+                &source,
+                id,
+                header.name.as_deref(),
+                header.flattened,
+                &self.client,
+            )
+            .await
+        {
+            Ok(_) => Ok(Response::new(CommitTransferReply { success: true })),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn get_drift(
+        &self,
+        request: Request<GetDriftRequest>,
+    ) -> Result<Response<GetDriftReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().node) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
+        };
+
+        if Node::get(id, &self.client).await.unwrap().is_none() {
+            return Err(Status::not_found("Node not found"));
+        }
+
+        // Each node checks its own pools
+        let request = match self
+            .route(
+                request,
+                Target::Node(id),
+                |mut client, request| async move { client.get_drift(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+
+        let report = match request.into_inner().scan {
+            true => match self
+                .reconciler
+                .reconcile(self.id, &self.qemu_img, &self.client)
+                .await
+            {
+                Ok(report) => report,
+                Err(e) => return Err(Status::internal(e.to_string())),
+            },
+            false => self.reconciler.last().await,
+        };
+
+        Ok(Response::new(report.into()))
+    }
+
+    async fn upload_disk(
+        &self,
+        request: Request<Streaming<UploadChunk>>,
+    ) -> Result<Response<UploadDiskReply>, Status> {
+        let (metadata, extensions, mut stream) = request.into_parts();
+
+        let first = match stream.message().await? {
+            Some(chunk) => chunk,
+            None => return Err(Status::invalid_argument("Empty upload")),
+        };
+
+        let header = match &first.header {
+            Some(header) => header.clone(),
+            None => return Err(Status::invalid_argument("Upload is missing its header")),
+        };
+
+        let id = match Uuid::parse_str(&header.disk) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
+
+        let mut disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let mut pool = match Pool::get(disk.get_pool_id(), &self.client).await.unwrap() {
+            Some(pool) => pool,
+            None => return Err(Status::internal("Disk pool not found")),
+        };
+
+        // Overlays rely on the contents of their backing file staying the same
+        self.ensure_not_backing(&[id]).await?;
+
+        let node_id = self.disk_node(&disk).await?;
+
+        // The first chunk was read to find the disk, so it has to be passed on again. An
+        // interrupted stream ends the upload like a client that stops early.
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut chunk = Some(first);
+            while let Some(next) = chunk {
+                if tx.send(next).await.is_err() {
+                    break;
+                }
+                chunk = stream.message().await.ok().flatten();
+            }
+        });
+        let chunks: UploadStream = ReceiverStream::new(rx);
+
+        let request = match self
+            .route(
+                Request::from_parts(metadata, extensions, chunks),
+                Target::Node(node_id),
+                |mut client, request| async move { client.upload_disk(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let mut chunks = request.into_inner();
+
+        self.ensure_not_running(id).await?;
+
+        let mut upload = match Upload::open(&pool.upload_path(id), header.offset).await {
+            Ok(upload) => upload,
+            Err(Error::TransferFailed(e)) => return Err(Status::out_of_range(e)),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let mut checksum = None;
+        while let Some(chunk) = chunks.next().await {
+            if let Err(e) = upload.write(&chunk.data).await {
+                return Err(Status::internal(e.to_string()));
+            }
+
+            if chunk.checksum.is_some() {
+                checksum = chunk.checksum;
+                break;
+            }
+        }
+
+        // Without a checksum the client either went away or only asked where to resume
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => {
+                return match upload.suspend().await {
+                    Ok(offset) => Ok(Response::new(UploadDiskReply {
+                        complete: false,
+                        offset,
+                    })),
+                    Err(e) => Err(Status::internal(e.to_string())),
+                }
+            }
+        };
+
+        let image = match upload.finish(&checksum).await {
+            Ok(image) => image,
+            Err(Error::TransferFailed(e)) => return Err(Status::data_loss(e)),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+        let offset = fs::metadata(&image)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        let result = pool
+            .replace_disk_image(
+                &mut disk,
+                &image,
+                header.format().into(),
+                self.overcommit,
+                &self.qemu_img,
+                &self.client,
+            )
+            .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&image);
+        }
+
+        match result {
+            Ok(_) => Ok(Response::new(UploadDiskReply {
+                complete: true,
+                offset,
+            })),
+            Err(Error::InvalidDisk(e)) => Err(Status::invalid_argument(e)),
+            Err(Error::PoolFull) => Err(Status::resource_exhausted("Pool is full")),
+            Err(Error::DiskLocked(e)) => Err(Status::failed_precondition(e)),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn download_disk(
+        &self,
+        request: Request<DownloadDiskRequest>,
+    ) -> Result<Response<Self::DownloadDiskStream>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
+
+        let disk = match Disk::get(id, &self.client).await.unwrap() {
+            Some(disk) => disk,
+            None => return Err(Status::not_found("Disk not found")),
+        };
+
+        let node_id = self.disk_node(&disk).await?;
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move {
+                    client
+                        .download_disk(request)
+                        .await
+                        .map(|reply| reply.map(|stream| Box::pin(stream) as DownloadStream))
+                },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        // A running guest would change the image while it is being read
+        self.ensure_not_running(id).await?;
+
+        let path = match disk.get_path(&self.client).await {
+            Ok(path) => path,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        // Overlays are downloaded as standalone images. Each download gets its own copy, which
+        // is deleted once the stream ends.
+        let flattened = !disk.get_backing_chain().is_empty();
+        let path = match flattened {
+            true => {
+                let mut flat = path.as_os_str().to_owned();
+                flat.push(format!(".{}.download", Uuid::new_v4()));
+                let flat = PathBuf::from(flat);
+
+                let format = disk.get_format().as_str();
+                if let Err(e) = self.qemu_img.convert(&path, format, &flat, format, None) {
+                    let _ = fs::remove_file(&flat);
+                    return Err(Status::internal(e.to_string()));
+                }
+                flat
+            }
+            false => path,
+        };
+
+        match transfer::download(path.clone(), inner.offset, flattened).await {
+            Ok(stream) => Ok(Response::new(stream)),
+            Err(e) => {
+                if flattened {
+                    let _ = fs::remove_file(&path);
+                }
+
+                match e {
+                    Error::TransferFailed(e) => Err(Status::out_of_range(e)),
+                    e => Err(Status::internal(e.to_string())),
+                }
+            }
+        }
+    }
+
+    async fn get_disk(
+        &self,
+        request: Request<GetDiskRequest>,
+    ) -> Result<Response<GetDiskReply>, Status> {
+        let id = match Uuid::from_str(&request.into_inner().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+        };
+
+        match Disk::get(id, &self.client).await {
+            Ok(disk) => Ok(Response::new(GetDiskReply {
+                disk: disk.map(|d| d.into()),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn list_disks(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListDisksReply>, Status> {
+        match Disk::list(&self.client).await {
+            Ok(disks) => Ok(Response::new(ListDisksReply {
+                disks: disks.into_iter().map(|d| d.get_id().to_string()).collect(),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn register_image(
+        &self,
+        request: Request<RegisterImageRequest>,
+    ) -> Result<Response<RegisterImageReply>, Status> {
+        let node_id = match Uuid::parse_str(&request.get_ref().node) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
+        };
+
+        if Node::get(node_id, &self.client).await.unwrap().is_none() {
+            return Err(Status::invalid_argument("Node not found"));
+        }
+
+        // The image file has to be inspected on the node that holds it
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.register_image(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match Image::register(
+            node_id,
+            &inner.path,
+            inner.name.as_deref(),
+            inner.os.as_deref(),
+            inner.installer,
+            inner.checksum.as_deref(),
+            &self.qemu_img,
+            &self.client,
+        )
+        .await
+        {
+            Ok(image) => Ok(Response::new(RegisterImageReply {
+                success: true,
+                id: Some(image.get_id().to_string()),
+            })),
+            Err(Error::ImageExists) => Err(Status::already_exists("Image already exists")),
+            Err(Error::InvalidImage(e)) => Err(Status::invalid_argument(e)),
+            Err(Error::IOError(e)) => Err(Status::invalid_argument(e.to_string())),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn remove_image(
+        &self,
+        request: Request<RemoveImageRequest>,
+    ) -> Result<Response<RemoveImageReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid image ID")),
+        };
+
+        // Only the catalog entry is removed, so there is nothing to do on the nodes
+        match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.remove_image(request).await
+            })
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        let image = match Image::get(id, &self.client).await.unwrap() {
+            Some(image) => image,
+            None => return Err(Status::not_found("Image not found")),
+        };
+
+        self.ensure_not_backing(&[id]).await?;
+
+        match VM::list(&self.client).await {
+            Ok(vms) => {
+                if let Some(vm) = vms.iter().find(|vm| vm.get_disks().contains(&id)) {
+                    return Err(Status::failed_precondition(format!(
+                        "Image is attached to VM {}",
+                        vm.get_id()
+                    )));
+                }
+            }
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+
+        match image.delete(&self.client).await {
+            Ok(_) => Ok(Response::new(RemoveImageReply { success: true })),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn get_image(
+        &self,
+        request: Request<GetImageRequest>,
+    ) -> Result<Response<GetImageReply>, Status> {
+        let id = match Uuid::from_str(&request.into_inner().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid image ID")),
+        };
+
+        match Image::get(id, &self.client).await {
+            Ok(image) => Ok(Response::new(GetImageReply {
+                image: image.map(|i| i.into()),
+            })),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn list_images(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<ListImagesReply>, Status> {
+        match Image::list(&self.client).await {
+            Ok(images) => Ok(Response::new(ListImagesReply {
+                images: images.into_iter().map(|i| i.get_id().to_string()).collect(),
+            })),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn add_vm(&self, request: Request<AddVmRequest>) -> Result<Response<AddVmReply>, Status> {
+        let node_id = match Uuid::parse_str(&request.get_ref().node) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid node ID")),
+        };
+
+        let mut node = match Node::get(node_id, &self.client).await.unwrap() {
+            Some(node) => node,
+            None => return Err(Status::invalid_argument("Node not found")),
+        };
+
+        let mut disks = Vec::new();
+        for disk in &request.get_ref().disks {
+            let disk_id = match Uuid::parse_str(disk) {
+                Ok(id) => id,
+                Err(_) => return Err(Status::invalid_argument("Invalid disk ID")),
+            };
+
+            let disk = match Disk::get(disk_id, &self.client).await.unwrap() {
+                Some(disk) => disk,
+                None => match Image::get(disk_id, &self.client).await.unwrap() {
+                    // Images are attached read-only, so only ISOs make sense
+                    Some(image) if image.get_format() != "iso" => {
+                        return Err(Status::invalid_argument(
+                            "Only ISO images can be attached to VMs",
+                        ))
+                    }
+                    Some(image) if image.get_path(node_id).is_none() => {
+                        return Err(Status::invalid_argument(
+                            "Image has no copy on the VM's node",
+                        ))
+                    }
+                    Some(_) => {
+                        disks.push(disk_id);
+                        continue;
+                    }
+                    None => return Err(Status::invalid_argument("Disk not found")),
+                },
+            };
+
+            let pool = match Pool::get(disk.get_pool_id(), &self.client).await.unwrap() {
+                Some(pool) => pool,
+                None => return Err(Status::internal("Disk pool not found")),
+            };
+
+            // Disks are files, so they must live in a pool the VM's node can access
+            if !pool.has_node(node_id) {
+                return Err(Status::invalid_argument("Disk is not on the VM's node"));
+            }
+
+            disks.push(disk_id);
+        }
+
+        // Interfaces are named by the network they attach to
+        let mut interfaces = Vec::new();
+        for interface in &request.get_ref().interfaces {
+            let id = match Uuid::parse_str(interface) {
+                Ok(id) => id,
+                Err(_) => return Err(Status::invalid_argument("Invalid interface ID")),
+            };
+
+            if Network::get(id, &self.client).await.unwrap().is_none() {
+                return Err(Status::invalid_argument("Interface network not found"));
+            }
+
+            interfaces.push(id);
+        }
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.add_vm(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        let vm = match node
+            .create_vm(
+                inner.name.as_str(),
+                inner.cpus,
+                inner.memory,
+                disks,
+                interfaces,
+                &self.client,
+            )
+            .await
+        {
+            Ok(vm) => vm,
+            Err(Error::VMExists) => return Err(Status::already_exists("VM already exists")),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let defined = match vm.to_spec(&self.client).await {
+            Ok(spec) => spec
+                .to_xml()
+                .and_then(|xml| self.hypervisor.define(vm.get_id(), &xml)),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = defined {
+            // Don't leave a record behind for a domain that doesn't exist
+            let _ = node.remove_vm(vm, &self.client).await;
+            return Err(Status::internal(e.to_string()));
+        }
+
+        Ok(Response::new(AddVmReply {
+            success: true,
+            id: Some(vm.get_id().to_string()),
+        }))
+    }
+
+    async fn remove_vm(
+        &self,
+        request: Request<RemoveVmRequest>,
+    ) -> Result<Response<RemoveVmReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid VM ID")),
+        };
+
+        let vm = match VM::get(id, &self.client).await.unwrap() {
+            Some(vm) => vm,
+            None => return Err(Status::not_found("VM not found")),
+        };
+
+        let node_id = vm.get_node_id();
+
+        match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.remove_vm(request).await },
+            )
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        let mut node = match Node::get(node_id, &self.client).await.unwrap() {
+            Some(node) => node,
+            None => return Err(Status::internal("VM node not found")),
+        };
+
+        let removed = match self.hypervisor.state(id) {
+            Ok(DomainState::Undefined) => Ok(()),
+            Ok(DomainState::Stopped) => self.hypervisor.undefine(id),
+            Ok(_) => self
+                .hypervisor
+                .destroy(id)
+                .and_then(|_| self.hypervisor.undefine(id)),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = removed {
+            return Err(Status::internal(e.to_string()));
+        }
+
+        match node.remove_vm(vm, &self.client).await {
+            Ok(_) => Ok(Response::new(RemoveVmReply { success: true })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn get_vm(&self, request: Request<GetVmRequest>) -> Result<Response<GetVmReply>, Status> {
+        let (mut metadata, extensions, inner) = request.into_parts();
+
+        let id = match Uuid::from_str(&inner.id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid VM ID")),
+        };
+
+        let vm = match VM::get(id, &self.client).await {
+            Ok(Some(vm)) => vm,
+            Ok(None) => return Ok(Response::new(GetVmReply { vm: None })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        // Domain state lives on the owning node, so ask it directly
+        if vm.get_node_id() != self.id && metadata.get(FORWARDED).is_none() {
+            if let Ok(client) = self.get_peer_client(&vm.get_node_id()).await {
+                metadata.insert(FORWARDED, MetadataValue::from_static(""));
+                return client
+                    .lock()
+                    .await
+                    .get_vm(Request::from_parts(metadata, extensions, inner))
+                    .await;
+            }
+
+            // Owner is unreachable, return the record with an undefined state
+            return Ok(Response::new(GetVmReply {
+                vm: Some(vm.into()),
+            }));
+        }
+
+        let state = match self.hypervisor.state(id) {
+            Ok(state) => state,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+
+        let mut vm: virtus_proto::Vm = vm.into();
+        vm.set_state(state.into());
+        Ok(Response::new(GetVmReply { vm: Some(vm) }))
+    }
+
+    async fn list_v_ms(&self, _request: Request<Empty>) -> Result<Response<ListVMsReply>, Status> {
+        match VM::list(&self.client).await {
+            Ok(vms) => Ok(Response::new(ListVMsReply {
+                vms: vms.into_iter().map(|v| v.get_id().to_string()).collect(),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn start_vm(
+        &self,
+        request: Request<StartVmRequest>,
+    ) -> Result<Response<StartVmReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid VM ID")),
+        };
+
+        let vm = match VM::get(id, &self.client).await.unwrap() {
+            Some(vm) => vm,
+            None => return Err(Status::not_found("VM not found")),
+        };
+
+        for disk in vm.get_disks() {
+            if let Ok(Some(disk)) = Disk::get(disk, &self.client).await {
+                if disk.is_broken() {
+                    return Err(Status::failed_precondition(format!(
+                        "Disk {} is broken",
+                        disk.get_id()
+                    )));
+                }
+            }
+        }
+
+        let node_id = vm.get_node_id();
+
+        match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.start_vm(request).await },
+            )
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        match self
+            .hypervisor
+            .start(id)
+            .and_then(|_| self.hypervisor.state(id))
+        {
+            Ok(state) => {
+                let mut reply = StartVmReply {
+                    success: true,
+                    ..Default::default()
+                };
+                reply.set_state(state.into());
+                Ok(Response::new(reply))
+            }
+            Err(Error::DomainNotFound) => Err(Status::failed_precondition("VM is not defined")),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn stop_vm(
+        &self,
+        request: Request<StopVmRequest>,
+    ) -> Result<Response<StopVmReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid VM ID")),
+        };
+
+        let vm = match VM::get(id, &self.client).await.unwrap() {
+            Some(vm) => vm,
+            None => return Err(Status::not_found("VM not found")),
+        };
+
+        let node_id = vm.get_node_id();
+
+        let request = match self
+            .route(
+                request,
+                Target::Node(node_id),
+                |mut client, request| async move { client.stop_vm(request).await },
+            )
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match match inner.force {
+            true => self.hypervisor.destroy(id),
+            false => self.hypervisor.stop(id),
+        }
+        .and_then(|_| self.hypervisor.state(id))
+        {
+            Ok(state) => {
+                let mut reply = StopVmReply {
+                    success: true,
+                    ..Default::default()
+                };
+                reply.set_state(state.into());
+                Ok(Response::new(reply))
+            }
+            Err(Error::DomainNotFound) => Err(Status::failed_precondition("VM is not defined")),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn add_network(
+        &self,
+        request: Request<AddNetworkRequest>,
+    ) -> Result<Response<AddNetworkReply>, Status> {
+        // Networks are cluster objects with no local side effects, so only the leader
+        // needs to handle them
+        let request = match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.add_network(request).await
+            })
+            .await?
+        {
+            Routed::Local(request) => request,
+            Routed::Forwarded(reply) => return reply,
+        };
+        let inner = request.into_inner();
+
+        match Network::create(
+            inner.name.as_deref(),
+            inner.vlan,
+            inner.cidr4.as_deref(),
+            inner.uplink.as_deref(),
+            &self.client,
+        )
+        .await
+        {
+            Ok(network) => Ok(Response::new(AddNetworkReply {
+                success: true,
+                id: Some(network.get_id().to_string()),
+            })),
+            Err(Error::NetworkExists) | Err(Error::PhysicalNetworkExists) => {
+                Err(Status::already_exists("Network already exists"))
+            }
+            Err(Error::InvalidNetwork(e)) => Err(Status::invalid_argument(e)),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn remove_network(
+        &self,
+        request: Request<RemoveNetworkRequest>,
+    ) -> Result<Response<RemoveNetworkReply>, Status> {
+        let id = match Uuid::parse_str(&request.get_ref().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid network ID")),
+        };
+
+        match self
+            .route(request, Target::Leader, |mut client, request| async move {
+                client.remove_network(request).await
+            })
+            .await?
+        {
+            Routed::Local(_) => {}
+            Routed::Forwarded(reply) => return reply,
+        }
+
+        let network = match Network::get(id, &self.client).await.unwrap() {
+            Some(network) => network,
+            None => return Err(Status::not_found("Network not found")),
+        };
+
+        match network.delete(&self.client).await {
+            Ok(_) => Ok(Response::new(RemoveNetworkReply { success: true })),
+            Err(Error::NetworkInUse) => Err(Status::failed_precondition(
+                "Network still has interfaces attached",
+            )),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn get_network(
+        &self,
+        request: Request<GetNetworkRequest>,
+    ) -> Result<Response<GetNetworkReply>, Status> {
+        let id = match Uuid::from_str(&request.into_inner().id) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::invalid_argument("Invalid network ID")),
+        };
+
+        match Network::get(id, &self.client).await {
+            Ok(network) => Ok(Response::new(GetNetworkReply {
+                network: network.map(|n| n.into()),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn list_networks(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<ListNetworksReply>, Status> {
+        match Network::list(&self.client).await {
+            Ok(networks) => Ok(Response::new(ListNetworksReply {
+                networks: networks
+                    .into_iter()
+                    .map(|n| n.get_id().to_string())
+                    .collect(),
+            })),
+            Err(e) => return Err(Status::internal(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::domain::DomainSpec;
+    use crate::hypervisor::FakeHypervisor;
+    use crate::pool::Pool;
+    use crate::qemu_img::FakeQemuImgRunner;
+    use crate::reconcile::Reconciler;
+    use crate::Builder;
+    use serial_test::serial;
+    use sha2::{Digest, Sha256};
+
+    fn get_virtus() -> Result<Virtus, anyhow::Error> {
+        let dir = String::from("target/tmp/test/127.0.0.1");
+        if Path::exists(Path::new("target/tmp/test")) {
+            fs::remove_dir_all("target/tmp/test")?;
+        }
+
+        Ok(Builder::new()
+            .bind("127.0.0.1".parse().unwrap())
+            .set_dir(&dir)
+            .hypervisor(Arc::new(FakeHypervisor::new()))
+            .qemu_img(Arc::new(FakeQemuImgRunner::new()))
+            .build()
+            .unwrap())
+    }
+
+    fn get_follower(address: &str) -> Result<Virtus, anyhow::Error> {
+        let dir = format!("target/tmp/test/{}", &address);
+        if Path::exists(Path::new(&dir)) {
+            fs::remove_dir_all(&dir)?;
+        }
+
+        Ok(Builder::new()
+            .bind(address.parse().unwrap())
+            .set_dir(&dir)
+            .join_cluster(vec!["127.0.0.1".parse().unwrap()])
+            .hypervisor(Arc::new(FakeHypervisor::new()))
+            .qemu_img(Arc::new(FakeQemuImgRunner::new()))
+            .build()
+            .unwrap())
+    }
+
+    async fn get_client(address: &str) -> Result<VirtusClient<Channel>, anyhow::Error> {
+        Ok(VirtusClient::connect(format!(
+            "http://{}",
+            SocketAddr::new(address.parse().unwrap(), DEFAULT_PORT)
+        ))
+        .await?)
+    }
+
+    #[test]
+    fn node_uri_brackets_ipv6() {
+        assert_eq!(
+            "http://10.0.0.2:9400",
+            node_uri("10.0.0.2".parse().unwrap(), 9400, false)
+        );
+        assert_eq!(
+            "http://[fd00::2]:9401",
+            node_uri("fd00::2".parse().unwrap(), 9401, false)
+        );
+        assert_eq!(
+            "https://[fd00::2]:9401",
+            node_uri("fd00::2".parse().unwrap(), 9401, true)
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn start_server() {
+        let virtus = get_virtus().unwrap();
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        assert_eq!(
+            1,
+            virtus
+                .client
+                .lock()
+                .await
+                .list_keys("nodes/")
+                .await
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn shutdown_and_restart() {
+        let virtus = get_virtus().unwrap();
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+        handle.shutdown().await.unwrap();
+
+        // Nothing listens on the port anymore
+        assert!(get_client("127.0.0.1").await.is_err());
+
+        // Restarting with the same id reuses the node record
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+        assert_eq!(1, Node::list(&virtus.client).await.unwrap().len());
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn list_nodes() {
+        let virtus = get_virtus().unwrap();
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        assert_eq!(1, Node::list(&virtus.client).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn create_pool() {
+        let virtus = get_virtus().unwrap();
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        assert_eq!(
+            Vec::<Pool>::new(),
+            Pool::list(&virtus.client).await.unwrap()
+        );
+
+        let mut node = Node::list(&virtus.client)
+            .await
+            .unwrap()
+            .first()
+            .unwrap()
+            .clone();
+
+        let pool = node
+            .create_pool(
+                "target/tmp/test/pool1",
+                None,
+                BackendKind::Directory,
+                &[],
+                &virtus.client,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(1, Pool::list(&virtus.client).await.unwrap().len());
+
+        let node = Node::list(&virtus.client)
+            .await
+            .unwrap()
+            .first()
+            .unwrap()
+            .clone();
+
+        assert_eq!(pool.get_node_id(), node.get_id());
+
+        let node_pools: Vec<Uuid> = node
+            .list_pools(virtus.client.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| p.get_id())
+            .collect();
+
+        assert_eq!(vec![pool.get_id()], node_pools);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn legacy_pool_allocation() {
+        let virtus = get_virtus().unwrap();
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let mut client = get_client("127.0.0.1").await.unwrap();
+        let pool = client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/pool1".to_string(),
+                node: virtus.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+        let pool_id = Uuid::parse_str(&pool).unwrap();
+
+        let disk = client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // Write the pool back the way it was stored before allocation tracking
+        let found = Pool::get(pool_id, &virtus.client).await.unwrap().unwrap();
+        let mut legacy = serde_json::to_value(&found).unwrap();
+        legacy.as_object_mut().unwrap().remove("allocated_bytes");
+        virtus
+            .client
+            .lock()
+            .await
+            .insert(format!("pools/{}", pool_id).as_str(), legacy)
+            .await
+            .unwrap();
+
+        let found = Pool::get(pool_id, &virtus.client).await.unwrap().unwrap();
+        assert_eq!(0, virtus_proto::Pool::from(found).allocated_bytes);
+
+        client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk,
+                size_gb: 2,
+                force: false,
+            }))
+            .await
+            .unwrap();
+
+        let found = Pool::get(pool_id, &virtus.client).await.unwrap().unwrap();
+        assert_eq!(
+            2 * 1024 * 1024 * 1024,
+            virtus_proto::Pool::from(found).allocated_bytes
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn start_from_config() {
+        if Path::exists(Path::new("target/tmp/test")) {
+            fs::remove_dir_all("target/tmp/test").unwrap();
+        }
+
+        let config = Config::parse(
+            r#"
+            data_dir = "target/tmp/test/127.0.0.1"
+            bind = "127.0.0.1"
+            advertise_port = 19400
+            reconcile_interval = 0
+
+            [[pools]]
+            path = "target/tmp/test/default_pool"
+            name = "default"
+            "#,
+        )
+        .unwrap();
+
+        let virtus = Builder::from_config(&config)
+            .unwrap()
+            .hypervisor(Arc::new(FakeHypervisor::new()))
+            .qemu_img(Arc::new(FakeQemuImgRunner::new()))
+            .build()
+            .unwrap();
+
+        // The generated id is kept for the next start
+        assert_eq!(virtus.id, config.node_id().unwrap());
+        assert_eq!(9400, virtus.bind.port());
+        assert_eq!(19400, virtus.port);
+
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let pools = Pool::list(&virtus.client).await.unwrap();
+        assert_eq!(1, pools.len());
+        assert_eq!("target/tmp/test/default_pool", pools[0].get_path());
+        assert_eq!(virtus.id, pools[0].get_node_id());
+        assert!(Path::new("target/tmp/test/default_pool").is_dir());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn two_node_cluster() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let leader_cluster = leader.skiff.get_cluster().await.unwrap();
+        let follower_cluster = follower.skiff.get_cluster().await.unwrap();
+
+        assert_eq!(leader_cluster, follower_cluster);
+        assert_eq!(2, leader_cluster.len());
+        assert_eq!(2, Node::list(&leader.client).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_pool_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut follower_client = get_client("127.0.0.2").await.unwrap();
+        let pool = follower_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await;
+
+        assert_eq!(1, Pool::list(&leader.client).await.unwrap().len());
+        assert!(Path::exists(Path::new("target/tmp/test/follower_pool")))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_disk_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut follower_client = get_client("127.0.0.2").await.unwrap();
+        let pool = follower_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = follower_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        assert_eq!(1, Disk::list(&leader.client).await.unwrap().len());
+        let filename = format!("target/tmp/test/follower_pool/{}.qcow2", disk);
+        assert!(Path::exists(Path::new(&filename)));
+
+        let found = follower_client
+            .get_pool(Request::new(GetPoolRequest { id: pool.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .pool
+            .unwrap();
+        assert_eq!(1024 * 1024 * 1024, found.allocated_bytes);
+        assert!(found.total_bytes > 0);
+
+        let raw = follower_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("database".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                format: DiskFormat::Raw.into(),
+                preallocation: Preallocation::Falloc.into(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let filename = format!("target/tmp/test/follower_pool/{}.img", raw);
+        assert_eq!(
+            1024 * 1024 * 1024,
+            fs::metadata(Path::new(&filename)).unwrap().len()
+        );
+
+        let found = follower_client
+            .get_disk(Request::new(GetDiskRequest { id: raw.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .disk
+            .unwrap();
+        assert_eq!(DiskFormat::Raw, found.format());
+
+        // Raw images can't have a backing file
+        let status = follower_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: None,
+                pool: pool.clone(),
+                source: Some(raw),
+                format: DiskFormat::Raw.into(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        // Far beyond the capacity of the test filesystem
+        let status = follower_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: None,
+                pool,
+                size_gb: 1024 * 1024 * 1024,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::ResourceExhausted, status.code());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_remove_vm_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool,
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "test_vm".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![disk.clone()],
+                interfaces: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let found = leader_client
+            .get_vm(Request::new(GetVmRequest { id: vm.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .vm
+            .unwrap();
+
+        assert_eq!(follower.id.to_string(), found.node);
+        assert_eq!(vec![disk], found.disks);
+
+        let node = Node::get(follower.id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, node.list_vms(&leader.client).await.unwrap().len());
+
+        // Names are unique across the cluster
+        assert!(leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "test_vm".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![],
+                interfaces: vec![],
+            }))
+            .await
+            .is_err());
+
+        // Interfaces must name an existing network
+        let status = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "no_network".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![],
+                interfaces: vec![Uuid::new_v4().to_string()],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        leader_client
+            .remove_vm(Request::new(RemoveVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        assert_eq!(0, VM::list(&leader.client).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn vm_lifecycle_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "lifecycle_vm".to_string(),
+                node: follower.id.to_string(),
+                cpus: 2,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![],
+                interfaces: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // The domain is defined on the owning node only
+        let vm_id = Uuid::parse_str(&vm).unwrap();
+        assert_eq!(
+            DomainState::Stopped,
+            follower.hypervisor.state(vm_id).unwrap()
+        );
+        assert_eq!(
+            DomainState::Undefined,
+            leader.hypervisor.state(vm_id).unwrap()
+        );
+
+        // What the node defined is exactly what the record describes
+        let spec = VM::get(vm_id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap()
+            .to_spec(&leader.client)
+            .await
+            .unwrap();
+        let defined = DomainSpec::from_xml(&follower.hypervisor.xml(vm_id).unwrap()).unwrap();
+        assert_eq!(spec, defined);
+
+        let state = leader_client
+            .start_vm(Request::new(StartVmRequest { id: vm.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .state();
+        assert_eq!(VmState::Running, state);
+
+        let found = leader_client
+            .get_vm(Request::new(GetVmRequest { id: vm.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .vm
+            .unwrap();
+        assert_eq!(VmState::Running, found.state());
+
+        let state = leader_client
+            .stop_vm(Request::new(StopVmRequest {
+                id: vm.clone(),
+                force: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .state();
+        assert_eq!(VmState::Stopped, state);
+
+        leader_client
+            .start_vm(Request::new(StartVmRequest { id: vm.clone() }))
+            .await
+            .unwrap();
+
+        // Removing a running VM tears the domain down first
+        leader_client
+            .remove_vm(Request::new(RemoveVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        assert!(follower.hypervisor.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn remove_disk_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let pool_id = Uuid::parse_str(&pool).unwrap();
+        let disk_id = Uuid::parse_str(&disk).unwrap();
+        let found = Pool::get(pool_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(vec![disk_id], found.get_disks());
+
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "disk_user".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![disk.clone()],
+                interfaces: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // Disks can't be removed out from under a VM
+        let status = leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: disk.clone() }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        leader_client
+            .remove_vm(Request::new(RemoveVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: disk.clone() }))
+            .await
+            .unwrap();
+
+        let filename = format!("target/tmp/test/follower_pool/{}.qcow2", disk);
+        assert!(!Path::exists(Path::new(&filename)));
+        assert_eq!(0, Disk::list(&leader.client).await.unwrap().len());
+
+        let found = Pool::get(pool_id, &leader.client).await.unwrap().unwrap();
+        assert!(found.get_disks().is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_disk_from_source_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let base = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("base".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let overlay = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("overlay".into()),
+                pool: pool.clone(),
+                size_gb: 0,
+                source: Some(base.clone()),
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let copy = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("copy".into()),
+                pool: pool.clone(),
+                size_gb: 2,
+                source: Some(overlay.clone()),
+                full_copy: true,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let base_id = Uuid::parse_str(&base).unwrap();
+        let overlay_id = Uuid::parse_str(&overlay).unwrap();
+        let found = Disk::get(overlay_id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(base_id), found.get_source());
+        assert_eq!(vec![base_id], found.get_backing_chain());
+
+        let found = leader_client
+            .get_disk(Request::new(GetDiskRequest { id: copy.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .disk
+            .unwrap();
+        assert_eq!(Some(overlay.clone()), found.source);
+        assert!(found.backing_chain.is_empty());
+        assert_eq!(2, found.size_gb);
+
+        // Sources can't be shrunk
+        let status = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: None,
+                pool: pool.clone(),
+                size_gb: 1,
+                source: Some(copy.clone()),
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        // The base is still in use by the overlay
+        let status = leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: base.clone() }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: overlay }))
+            .await
+            .unwrap();
+        leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: base }))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn register_remove_image() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        fs::create_dir_all("target/tmp/test/images").unwrap();
+        fs::write("target/tmp/test/images/base.img", vec![0u8; 1024 * 1024]).unwrap();
+        fs::copy(
+            "target/tmp/test/images/base.img",
+            "target/tmp/test/images/base_copy.img",
+        )
+        .unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let image = leader_client
+            .register_image(Request::new(RegisterImageRequest {
+                node: follower.id.to_string(),
+                path: "target/tmp/test/images/base.img".to_string(),
+                name: Some("base".to_string()),
+                os: Some("debian12".to_string()),
+                installer: false,
+                checksum: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // The same file on another node is another copy of the same image
+        let copy = leader_client
+            .register_image(Request::new(RegisterImageRequest {
+                node: leader.id.to_string(),
+                path: "target/tmp/test/images/base_copy.img".to_string(),
+                name: None,
+                os: None,
+                installer: false,
+                checksum: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+        assert_eq!(image, copy);
+
+        // Registering the same file again can't rename the image
+        let status = leader_client
+            .register_image(Request::new(RegisterImageRequest {
+                node: leader.id.to_string(),
+                path: "target/tmp/test/images/base_copy.img".to_string(),
+                name: Some("renamed".to_string()),
+                os: None,
+                installer: false,
+                checksum: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::AlreadyExists, status.code());
+
+        let again = leader_client
+            .register_image(Request::new(RegisterImageRequest {
+                node: leader.id.to_string(),
+                path: "target/tmp/test/images/base_copy.img".to_string(),
+                name: Some("base".to_string()),
+                os: Some("debian12".to_string()),
+                installer: false,
+                checksum: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+        assert_eq!(image, again);
+
+        let status = leader_client
+            .register_image(Request::new(RegisterImageRequest {
+                node: follower.id.to_string(),
+                path: "target/tmp/test/images/base.img".to_string(),
+                name: None,
+                os: None,
+                installer: false,
+                checksum: Some("0".repeat(64)),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        let found = leader_client
+            .get_image(Request::new(GetImageRequest { id: image.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .image
+            .unwrap();
+        assert_eq!("raw", found.format);
+        assert_eq!(1024 * 1024, found.size);
+        assert_eq!(Some("debian12".to_string()), found.os);
+        assert_eq!(2, found.copies.len());
+
+        let images = leader_client
+            .list_images(Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .images;
+        assert_eq!(vec![image.clone()], images);
+
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: None,
+                pool,
+                size_gb: 0,
+                source: Some(image.clone()),
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let status = leader_client
+            .remove_image(Request::new(RemoveImageRequest { id: image.clone() }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: disk }))
+            .await
+            .unwrap();
+        leader_client
+            .remove_image(Request::new(RemoveImageRequest { id: image }))
+            .await
+            .unwrap();
+
+        assert!(Image::list(&leader.client).await.unwrap().is_empty());
+        assert!(Path::exists(Path::new("target/tmp/test/images/base.img")));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn resize_disk_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 3,
+                force: false,
+            }))
+            .await
+            .unwrap();
+
+        let disk_id = Uuid::parse_str(&disk).unwrap();
+        let found = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(3, found.get_size_gb());
+
+        let found = Pool::get(Uuid::parse_str(&pool).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            3 * 1024 * 1024 * 1024,
+            virtus_proto::Pool::from(found).allocated_bytes
+        );
+
+        // Shrinking needs to be forced
+        let status = leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 2,
+                force: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 2,
+                force: true,
+            }))
+            .await
+            .unwrap();
+
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "resized".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![disk.clone()],
+                interfaces: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        leader_client
+            .start_vm(Request::new(StartVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        // Running VMs can grow through the hypervisor, but not shrink
+        leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 4,
+                force: false,
+            }))
+            .await
+            .unwrap();
+
+        let status = leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 1,
+                force: true,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        let found = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(4, found.get_size_gb());
+
+        // The live resize is charged to the pool like an offline one, 2G to 4G
+        let found = Pool::get(Uuid::parse_str(&pool).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            4 * 1024 * 1024 * 1024,
+            virtus_proto::Pool::from(found).allocated_bytes
+        );
+    }
+
+    #[tokio::test]
     #[serial]
-    async fn two_node_cluster() {
+    async fn copy_move_disk_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let mut pools = vec![];
+        for (path, node) in [
+            ("target/tmp/test/leader_pool", leader.id),
+            ("target/tmp/test/follower_pool", follower.id),
+        ] {
+            pools.push(
+                leader_client
+                    .add_pool(Request::new(AddPoolRequest {
+                        name: None,
+                        path: path.to_string(),
+                        node: node.to_string(),
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .id
+                    .unwrap(),
+            );
+        }
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pools[0].clone(),
+                size_gb: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk_id = Uuid::parse_str(&disk).unwrap();
+        let path = Disk::get(disk_id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap()
+            .get_path(&leader.client)
+            .await
+            .unwrap();
+        let contents = vec![7u8; 3 * 1024 * 1024];
+        fs::write(&path, &contents).unwrap();
+
+        let progress: Vec<TransferProgress> = leader_client
+            .copy_disk(Request::new(CopyDiskRequest {
+                id: disk.clone(),
+                pool: pools[1].clone(),
+                name: Some("copy".into()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+
+        let last = progress.last().unwrap();
+        assert_eq!(contents.len() as u64, last.bytes_sent);
+        let copy_id = Uuid::parse_str(last.disk.as_ref().unwrap()).unwrap();
+        assert_ne!(disk_id, copy_id);
+
+        let copy = Disk::get(copy_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(Uuid::parse_str(&pools[1]).unwrap(), copy.get_pool_id());
+        assert_eq!(Some(disk_id), copy.get_source());
+        assert_eq!(
+            contents,
+            fs::read(copy.get_path(&leader.client).await.unwrap()).unwrap()
+        );
+
+        // Moving keeps the disk id and cleans up the old image
+        let progress: Vec<TransferProgress> = leader_client
+            .move_disk(Request::new(MoveDiskRequest {
+                id: disk.clone(),
+                pool: pools[1].clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+        assert_eq!(Some(disk.clone()), progress.last().unwrap().disk);
+
+        let moved = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(Uuid::parse_str(&pools[1]).unwrap(), moved.get_pool_id());
+        assert_eq!(
+            contents,
+            fs::read(moved.get_path(&leader.client).await.unwrap()).unwrap()
+        );
+        assert!(!path.exists());
+
+        let source_pool = Pool::get(Uuid::parse_str(&pools[0]).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(source_pool.get_disks().is_empty());
+        let dest_pool = Pool::get(Uuid::parse_str(&pools[1]).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, dest_pool.get_disks().len());
+
+        // Committing the move again, e.g. after a lost reply, changes nothing
+        let mut follower_client = get_client("127.0.0.2").await.unwrap();
+        follower_client
+            .commit_transfer(Request::new(TransferHeader {
+                disk: disk.clone(),
+                pool: pools[1].clone(),
+                id: disk.clone(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let source_pool = Pool::get(Uuid::parse_str(&pools[0]).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(source_pool.get_disks().is_empty());
+        let found = Pool::get(Uuid::parse_str(&pools[1]).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dest_pool, found);
+
+        let status = leader_client
+            .move_disk(Request::new(MoveDiskRequest {
+                id: disk.clone(),
+                pool: pools[1].clone(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn upload_download_disk_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: None,
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                format: virtus_proto::DiskFormat::Raw.into(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let contents: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| i as u8).collect();
+        let (first, rest) = contents.split_at(1024 * 1024);
+        let header = UploadHeader {
+            disk: disk.clone(),
+            format: virtus_proto::DiskFormat::Raw.into(),
+            offset: 0,
+        };
+
+        // Stopping without a checksum leaves the upload incomplete
+        let reply = leader_client
+            .upload_disk(tokio_stream::iter([UploadChunk {
+                header: Some(header.clone()),
+                data: first.to_vec(),
+                checksum: None,
+            }]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!reply.complete);
+        assert_eq!(first.len() as u64, reply.offset);
+
+        let status = leader_client
+            .upload_disk(tokio_stream::iter([UploadChunk {
+                header: Some(UploadHeader {
+                    offset: contents.len() as u64,
+                    ..header.clone()
+                }),
+                data: vec![],
+                checksum: None,
+            }]))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::OutOfRange, status.code());
+
+        let checksum = format!("{:x}", Sha256::digest(&contents));
+        let reply = leader_client
+            .upload_disk(tokio_stream::iter([
+                UploadChunk {
+                    header: Some(UploadHeader {
+                        offset: reply.offset,
+                        ..header.clone()
+                    }),
+                    data: rest.to_vec(),
+                    checksum: None,
+                },
+                UploadChunk {
+                    header: None,
+                    data: vec![],
+                    checksum: Some(checksum.clone()),
+                },
+            ]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.complete);
+        assert_eq!(contents.len() as u64, reply.offset);
+
+        let disk_id = Uuid::parse_str(&disk).unwrap();
+        let found = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(1, found.get_size_gb());
+        let path = found.get_path(&leader.client).await.unwrap();
+        let image = fs::read(&path).unwrap();
+        assert_eq!(contents[..], image[..contents.len()]);
+
+        let chunks: Vec<DownloadChunk> = leader_client
+            .download_disk(Request::new(DownloadDiskRequest {
+                id: disk.clone(),
+                offset: 100,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Result<_, _>>()
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.data.clone()).collect();
+        assert_eq!(image[100..], data[..]);
+        assert_eq!(
+            Some(format!("{:x}", Sha256::digest(&image))),
+            chunks.last().unwrap().checksum
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn get_drift_two_nodes() {
         let leader = get_virtus().unwrap();
-        let leader_clone = leader.clone();
-        let handle = tokio::spawn(async move {
-            let _ = leader_clone.start().await;
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: None,
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let mut disks = vec![];
+        for _ in 0..2 {
+            disks.push(
+                leader_client
+                    .add_disk(Request::new(AddDiskRequest {
+                        name: None,
+                        pool: pool.clone(),
+                        size_gb: 1,
+                        format: virtus_proto::DiskFormat::Raw.into(),
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .id
+                    .unwrap(),
+            );
+        }
+
+        let missing = Uuid::parse_str(&disks[1]).unwrap();
+        let disk = Disk::get(missing, &leader.client).await.unwrap().unwrap();
+        fs::remove_file(disk.get_path(&leader.client).await.unwrap()).unwrap();
+        fs::write("target/tmp/test/follower_pool/stray.img", vec![1u8; 512]).unwrap();
+        // Old enough not to be mistaken for a disk being created
+        fs::File::options()
+            .write(true)
+            .open("target/tmp/test/follower_pool/stray.img")
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        fs::write(
+            "target/tmp/test/follower_pool/stray.img.part",
+            vec![1u8; 512],
+        )
+        .unwrap();
+
+        let reply = leader_client
+            .get_drift(Request::new(GetDriftRequest {
+                node: follower.id.to_string(),
+                scan: true,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(reply.scanned_at > 0);
+        assert_eq!(2, reply.drift.len());
+        assert!(reply.drift.iter().all(|drift| !drift.fixed));
+        assert!(reply.drift.iter().any(|drift| {
+            drift.kind() == virtus_proto::DriftKind::MissingFile
+                && drift.disk == Some(disks[1].clone())
+        }));
+        assert!(reply
+            .drift
+            .iter()
+            .any(|drift| drift.kind() == virtus_proto::DriftKind::OrphanFile));
+
+        // Reporting doesn't change anything
+        assert_eq!(reply, {
+            let reply = leader_client
+                .get_drift(Request::new(GetDriftRequest {
+                    node: follower.id.to_string(),
+                    scan: false,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            reply
         });
+        assert!(!Disk::get(missing, &leader.client)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_broken());
+
+        let report = Reconciler::new(None, true)
+            .reconcile(follower.id, &follower.qemu_img, &follower.client)
+            .await
+            .unwrap();
+        let reply = virtus_proto::GetDriftReply::from(report);
+        assert!(reply.drift.iter().all(|drift| drift.fixed));
+        assert!(Disk::get(missing, &leader.client)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_broken());
+
+        let adopted = reply
+            .drift
+            .iter()
+            .find(|drift| drift.kind() == virtus_proto::DriftKind::OrphanFile)
+            .and_then(|drift| drift.disk.clone())
+            .unwrap();
+        let adopted = Disk::get(Uuid::parse_str(&adopted).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, adopted.get_size_gb());
+        assert!(adopted.get_path(&leader.client).await.unwrap().exists());
+
+        let found = Pool::get(Uuid::parse_str(&pool).unwrap(), &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(3, found.get_disks().len());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn disk_snapshots_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool,
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let snapshot = leader_client
+            .create_snapshot(Request::new(CreateSnapshotRequest {
+                disk: disk.clone(),
+                name: "before_upgrade".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .snapshot
+            .unwrap();
+        assert_eq!("before_upgrade", snapshot.name);
+        assert_eq!(1, snapshot.size_gb);
+        assert!(snapshot.created > 0);
+
+        let status = leader_client
+            .create_snapshot(Request::new(CreateSnapshotRequest {
+                disk: disk.clone(),
+                name: "before_upgrade".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::AlreadyExists, status.code());
+
+        leader_client
+            .resize_disk(Request::new(ResizeDiskRequest {
+                id: disk.clone(),
+                size_gb: 2,
+                force: false,
+            }))
+            .await
+            .unwrap();
+
+        // Reverting also restores the size at the time of the snapshot
+        leader_client
+            .revert_snapshot(Request::new(RevertSnapshotRequest {
+                disk: disk.clone(),
+                name: "before_upgrade".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let disk_id = Uuid::parse_str(&disk).unwrap();
+        let found = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert_eq!(1, found.get_size_gb());
+
+        let snapshots = leader_client
+            .list_snapshots(Request::new(ListSnapshotsRequest { disk: disk.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .snapshots;
+        assert_eq!(vec![snapshot], snapshots);
+
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "snapshotted".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![disk.clone()],
+                interfaces: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        leader_client
+            .start_vm(Request::new(StartVmRequest { id: vm.clone() }))
+            .await
+            .unwrap();
+
+        let status = leader_client
+            .create_snapshot(Request::new(CreateSnapshotRequest {
+                disk: disk.clone(),
+                name: "live".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        leader_client
+            .stop_vm(Request::new(StopVmRequest {
+                id: vm,
+                force: true,
+            }))
+            .await
+            .unwrap();
+
+        leader_client
+            .delete_snapshot(Request::new(DeleteSnapshotRequest {
+                disk: disk.clone(),
+                name: "before_upgrade".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let status = leader_client
+            .delete_snapshot(Request::new(DeleteSnapshotRequest {
+                disk: disk.clone(),
+                name: "before_upgrade".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+
+        let found = Disk::get(disk_id, &leader.client).await.unwrap().unwrap();
+        assert!(found.get_snapshots().is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn remove_pool_two_nodes() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
+            .add_pool(Request::new(AddPoolRequest {
+                name: Some("test".to_string()),
+                path: "target/tmp/test/follower_pool".to_string(),
+                node: follower.id.to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
 
-        // Give leader time to elect itself
-        // Todo: again, need more reliable method for determining when servers are ready
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let node = Node::get(follower.id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            1,
+            node.list_pools(leader.client.clone()).await.unwrap().len()
+        );
 
-        let follower = get_follower("127.0.0.2").unwrap();
-        let follower_clone = follower.clone();
-        let _ = tokio::spawn(async move {
-            let _ = follower_clone.start().await;
-        });
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: Some("test_disk".into()),
+                pool: pool.clone(),
+                size_gb: 1,
+                source: None,
+                full_copy: false,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let status = leader_client
+            .remove_pool(Request::new(RemovePoolRequest {
+                id: pool.clone(),
+                cascade: false,
+                remove_dir: true,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
 
-        let leader_cluster = leader.skiff.get_cluster().await.unwrap();
-        let follower_cluster = follower.skiff.get_cluster().await.unwrap();
+        leader_client
+            .remove_pool(Request::new(RemovePoolRequest {
+                id: pool,
+                cascade: true,
+                remove_dir: true,
+            }))
+            .await
+            .unwrap();
 
-        assert_eq!(leader_cluster, follower_cluster);
-        assert_eq!(2, leader_cluster.len());
-        assert_eq!(2, Node::list(&leader.client).await.unwrap().len());
+        let filename = format!("target/tmp/test/follower_pool/{}.qcow2", disk);
+        assert!(!Path::exists(Path::new(&filename)));
+        assert!(!Path::exists(Path::new("target/tmp/test/follower_pool")));
+        assert_eq!(0, Disk::list(&leader.client).await.unwrap().len());
+        assert_eq!(0, Pool::list(&leader.client).await.unwrap().len());
+
+        let node = Node::get(follower.id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(node
+            .list_pools(leader.client.clone())
+            .await
+            .unwrap()
+            .is_empty());
     }
 
     #[tokio::test]
     #[serial]
-    async fn add_pool_two_nodes() {
+    async fn shared_pool_two_nodes() {
         let leader = get_virtus().unwrap();
-        let leader_clone = leader.clone();
-        let handle = tokio::spawn(async move {
-            let _ = leader_clone.start().await;
-        });
-
-        // Give leader time to elect itself
-        // Todo: again, need more reliable method for determining when servers are ready
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
 
         let follower = get_follower("127.0.0.2").unwrap();
-        let follower_clone = follower.clone();
-        let _ = tokio::spawn(async move {
-            let _ = follower_clone.start().await;
-        });
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
 
-        let mut follower_client = get_client("127.0.0.2").await.unwrap();
-        let pool = follower_client
+        // Only shared pools can span nodes, and their storage has to be mounted already
+        assert_eq!(
+            tonic::Code::InvalidArgument,
+            leader_client
+                .add_pool(Request::new(AddPoolRequest {
+                    path: "target/tmp/test/shared_pool".to_string(),
+                    node: leader.id.to_string(),
+                    nodes: vec![follower.id.to_string()],
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err()
+                .code()
+        );
+
+        let _ = fs::remove_dir_all("target/tmp/test/shared_pool");
+        assert_eq!(
+            tonic::Code::FailedPrecondition,
+            leader_client
+                .add_pool(Request::new(AddPoolRequest {
+                    path: "target/tmp/test/shared_pool".to_string(),
+                    node: leader.id.to_string(),
+                    backend: virtus_proto::PoolBackend::Shared.into(),
+                    nodes: vec![follower.id.to_string()],
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err()
+                .code()
+        );
+
+        fs::create_dir_all("target/tmp/test/shared_pool").unwrap();
+        let pool = leader_client
             .add_pool(Request::new(AddPoolRequest {
-                name: Some("test".to_string()),
-                path: "target/tmp/test/follower_pool".to_string(),
+                path: "target/tmp/test/shared_pool".to_string(),
+                node: leader.id.to_string(),
+                backend: virtus_proto::PoolBackend::Shared.into(),
+                nodes: vec![follower.id.to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        let found = leader_client
+            .get_pool(Request::new(GetPoolRequest { id: pool.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .pool
+            .unwrap();
+        assert_eq!(virtus_proto::PoolBackend::Shared, found.backend());
+        assert_eq!(leader.id.to_string(), found.node);
+        assert_eq!(
+            vec![leader.id.to_string(), follower.id.to_string()],
+            found.nodes
+        );
+
+        for id in [leader.id, follower.id] {
+            let node = Node::get(id, &leader.client).await.unwrap().unwrap();
+            assert_eq!(
+                1,
+                node.list_pools(leader.client.clone()).await.unwrap().len()
+            );
+        }
+
+        let disk = leader_client
+            .add_disk(Request::new(AddDiskRequest {
+                name: None,
+                pool: pool.clone(),
+                size_gb: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // Disks in a shared pool can be attached on any of its nodes
+        let vm = leader_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "shared_vm".to_string(),
                 node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![disk.clone()],
+                interfaces: vec![],
             }))
-            .await;
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
 
-        assert_eq!(1, Pool::list(&leader.client).await.unwrap().len());
-        assert!(Path::exists(Path::new("target/tmp/test/follower_pool")))
+        leader_client
+            .remove_vm(Request::new(RemoveVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        leader_client
+            .remove_disk(Request::new(RemoveDiskRequest { id: disk }))
+            .await
+            .unwrap();
+
+        leader_client
+            .remove_pool(Request::new(RemovePoolRequest {
+                id: pool,
+                cascade: false,
+                remove_dir: true,
+            }))
+            .await
+            .unwrap();
+
+        // The mount point is left for the other nodes
+        assert!(Path::new("target/tmp/test/shared_pool").is_dir());
+        for id in [leader.id, follower.id] {
+            let node = Node::get(id, &leader.client).await.unwrap().unwrap();
+            assert!(!node.has_resources());
+        }
     }
 
     #[tokio::test]
     #[serial]
-    async fn add_disk_two_nodes() {
+    async fn node_heartbeats() {
         let leader = get_virtus().unwrap();
-        let leader_clone = leader.clone();
-        let handle = tokio::spawn(async move {
-            let _ = leader_clone.start().await;
-        });
-
-        // Give leader time to elect itself
-        // Todo: again, need more reliable method for determining when servers are ready
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
 
         let follower = get_follower("127.0.0.2").unwrap();
-        let follower_clone = follower.clone();
-        let _ = tokio::spawn(async move {
-            let _ = follower_clone.start().await;
-        });
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        // A registered node that never started
+        let mut follower_client = get_client("127.0.0.2").await.unwrap();
+        let absent = follower_client
+            .add_node(Request::new(AddNodeRequest {
+                ip: "127.0.0.3".to_string(),
+                hostname: "node3".to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // Status is derived by the leader, whichever node is asked
+        let node = follower_client
+            .get_node(Request::new(GetNodeRequest {
+                id: follower.id.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .node
+            .unwrap();
+        assert_eq!(virtus_proto::NodeStatus::Ready, node.status());
+        let heartbeat = node.heartbeat.unwrap();
+        assert_eq!(heartbeat::VERSION, heartbeat.version);
+        assert!(heartbeat.last_seen > 0);
+
+        let statuses = follower_client
+            .list_nodes(Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .statuses;
+        assert_eq!(3, statuses.len());
+        assert_eq!(
+            Some(&(virtus_proto::NodeStatus::Ready as i32)),
+            statuses.get(&leader.id.to_string())
+        );
+        assert_eq!(
+            Some(&(virtus_proto::NodeStatus::Unknown as i32)),
+            statuses.get(&absent)
+        );
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        // One heartbeat, then silence
+        let id = Uuid::parse_str(&absent).unwrap();
+        Heartbeat::new(id, Duration::ZERO, Duration::from_secs(1))
+            .publish(&leader.client)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(4100)).await;
+
+        let node = follower_client
+            .get_node(Request::new(GetNodeRequest { id: absent.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .node
+            .unwrap();
+        assert_eq!(virtus_proto::NodeStatus::NotReady, node.status());
+
+        let status = follower_client
+            .add_pool(Request::new(AddPoolRequest {
+                path: "target/tmp/test/absent_pool".to_string(),
+                node: absent.clone(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unavailable, status.code());
+
+        follower_client
+            .remove_node(Request::new(RemoveNodeRequest { id: absent }))
+            .await
+            .unwrap();
+        assert!(Heartbeat::get(id, &leader.client).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn node_inventory() {
+        if Path::exists(Path::new("target/tmp/test")) {
+            fs::remove_dir_all("target/tmp/test").unwrap();
+        }
+
+        let virtus = Builder::new()
+            .bind("127.0.0.1".parse().unwrap())
+            .set_dir("target/tmp/test/127.0.0.1")
+            .host_root("testdata/host/numa")
+            .hypervisor(Arc::new(FakeHypervisor::new()))
+            .qemu_img(Arc::new(FakeQemuImgRunner::new()))
+            .build()
+            .unwrap();
+        let handle = virtus.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let mut client = get_client("127.0.0.1").await.unwrap();
+        let inventory = client
+            .get_node(Request::new(GetNodeRequest {
+                id: virtus.id.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .node
+            .unwrap()
+            .inventory
+            .unwrap();
+
+        assert_eq!(4, inventory.cpus);
+        assert_eq!(2, inventory.sockets);
+        assert_eq!(2, inventory.numa_nodes.len());
+        assert_eq!(2, inventory.hugepages.len());
+        assert!(inventory.kvm);
+        assert_eq!("6.8.0-45-generic", inventory.kernel_version);
+        assert_eq!(Some("8.2.2".to_string()), inventory.qemu_img_version);
+        assert_eq!(None, inventory.libvirt_version);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_remove_node() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
 
+        // Register a third node through the follower, which forwards to the leader
         let mut follower_client = get_client("127.0.0.2").await.unwrap();
-        let pool = follower_client
+        let id = Uuid::new_v4();
+        let added = follower_client
+            .add_node(Request::new(AddNodeRequest {
+                ip: "127.0.0.3".to_string(),
+                hostname: "node3".to_string(),
+                id: Some(id.to_string()),
+                port: Some(9403),
+                ips: vec!["::1".to_string()],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        assert_eq!(id.to_string(), added);
+        assert_eq!(3, Node::list(&leader.client).await.unwrap().len());
+
+        let node = follower_client
+            .get_node(Request::new(GetNodeRequest { id: added.clone() }))
+            .await
+            .unwrap()
+            .into_inner()
+            .node
+            .unwrap();
+        assert_eq!("127.0.0.3", node.ip);
+        assert_eq!(vec!["127.0.0.3", "::1"], node.ips);
+        assert_eq!(9403, node.port);
+
+        let node = Node::get(follower.id, &leader.client)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(DEFAULT_PORT, node.get_port());
+
+        // Sharing any address on the same port is a duplicate
+        let status = follower_client
+            .add_node(Request::new(AddNodeRequest {
+                ip: "::1".to_string(),
+                hostname: "node3".to_string(),
+                id: None,
+                port: Some(9403),
+                ips: vec!["127.0.0.4".to_string()],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::AlreadyExists, status.code());
+
+        // Skiff needs an IPv4 address
+        let status = follower_client
+            .add_node(Request::new(AddNodeRequest {
+                ip: "::2".to_string(),
+                hostname: "node4".to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        follower_client
+            .remove_node(Request::new(RemoveNodeRequest { id: added }))
+            .await
+            .unwrap();
+        assert_eq!(2, Node::list(&leader.client).await.unwrap().len());
+
+        // Nodes that still own resources can't be removed
+        let mut leader_client = get_client("127.0.0.1").await.unwrap();
+        let pool = leader_client
             .add_pool(Request::new(AddPoolRequest {
                 name: Some("test".to_string()),
                 path: "target/tmp/test/follower_pool".to_string(),
                 node: follower.id.to_string(),
+                ..Default::default()
             }))
             .await
             .unwrap()
@@ -652,11 +4755,52 @@ mod tests {
             .id
             .unwrap();
 
-        let disk = follower_client
-            .add_disk(Request::new(AddDiskRequest {
-                name: Some("test_disk".into()),
-                pool,
-                size_gb: 1,
+        let status = leader_client
+            .remove_node(Request::new(RemoveNodeRequest {
+                id: follower.id.to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        leader_client
+            .remove_pool(Request::new(RemovePoolRequest {
+                id: pool,
+                cascade: false,
+                remove_dir: false,
+            }))
+            .await
+            .unwrap();
+
+        leader_client
+            .remove_node(Request::new(RemoveNodeRequest {
+                id: follower.id.to_string(),
+            }))
+            .await
+            .unwrap();
+
+        assert_eq!(1, Node::list(&leader.client).await.unwrap().len());
+        assert!(!leader.peer_clients.lock().await.contains_key(&follower.id));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn add_remove_network() {
+        let leader = get_virtus().unwrap();
+        let handle = leader.clone().start().await.unwrap();
+        handle.ready().await.unwrap();
+
+        let follower = get_follower("127.0.0.2").unwrap();
+        let follower_handle = follower.clone().start().await.unwrap();
+        follower_handle.ready().await.unwrap();
+
+        let mut follower_client = get_client("127.0.0.2").await.unwrap();
+        let network = follower_client
+            .add_network(Request::new(AddNetworkRequest {
+                name: Some("external".to_string()),
+                vlan: Some(100),
+                cidr4: Some("10.0.0.0/24".to_string()),
+                uplink: Some("eth0".to_string()),
             }))
             .await
             .unwrap()
@@ -664,8 +4808,84 @@ mod tests {
             .id
             .unwrap();
 
-        assert_eq!(1, Disk::list(&leader.client).await.unwrap().len());
-        let filename = format!("target/tmp/test/follower_pool/{}.qcow2", disk);
-        assert!(Path::exists(Path::new(&filename)));
+        let found = follower_client
+            .get_network(Request::new(GetNetworkRequest {
+                id: network.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .network
+            .unwrap();
+
+        assert_eq!(100, found.vlan);
+        assert!(found.external);
+        assert_eq!("eth0", found.bridge_name);
+        assert_eq!(1, Network::list(&leader.client).await.unwrap().len());
+
+        // Only one network per physical uplink
+        let status = follower_client
+            .add_network(Request::new(AddNetworkRequest {
+                name: Some("other".to_string()),
+                vlan: None,
+                cidr4: None,
+                uplink: Some("eth0".to_string()),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::AlreadyExists, status.code());
+
+        let status = follower_client
+            .add_network(Request::new(AddNetworkRequest {
+                name: Some("bad".to_string()),
+                vlan: None,
+                cidr4: Some("10.0.0.0/33".to_string()),
+                uplink: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+
+        let vm = follower_client
+            .add_vm(Request::new(AddVmRequest {
+                name: "attached".to_string(),
+                node: follower.id.to_string(),
+                cpus: 1,
+                memory: 1024 * 1024 * 1024,
+                disks: vec![],
+                interfaces: vec![network.clone()],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .id
+            .unwrap();
+
+        // Not while a VM is still attached
+        let status = follower_client
+            .remove_network(Request::new(RemoveNetworkRequest {
+                id: network.clone(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        follower_client
+            .remove_vm(Request::new(RemoveVmRequest { id: vm }))
+            .await
+            .unwrap();
+
+        follower_client
+            .remove_network(Request::new(RemoveNetworkRequest { id: network }))
+            .await
+            .unwrap();
+
+        assert!(follower_client
+            .list_networks(Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .networks
+            .is_empty());
     }
 }
@@ -0,0 +1,219 @@
+use crate::disk::Disk;
+use crate::domain::{
+    Console, DomainBuilder, DomainDisk, DomainInterface, DomainSpec, InterfaceSource,
+};
+use crate::error::Error;
+use crate::image::Image;
+use crate::network::Network;
+use crate::virtus::virtus_proto;
+use serde::{Deserialize, Serialize};
+use skiff::Client as SkiffClient;
+use std::fs;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VM {
+    id: Uuid,
+    node_id: Uuid,
+    name: String,
+    cpus: u32,
+    memory: u64,
+    disks: Vec<Uuid>,
+    interfaces: Vec<Uuid>,
+}
+
+impl VM {
+    pub async fn create(
+        node_id: Uuid,
+        name: &str,
+        cpus: u32,
+        memory: u64,
+        disks: Vec<Uuid>,
+        interfaces: Vec<Uuid>,
+        client: &Arc<Mutex<SkiffClient>>,
+    ) -> Result<Self, Error> {
+        if VM::find(name, client).await?.is_some() {
+            return Err(Error::VMExists);
+        }
+
+        let vm = Self {
+            id: Uuid::new_v4(),
+            node_id,
+            name: name.to_string(),
+            cpus,
+            memory,
+            disks,
+            interfaces,
+        };
+
+        vm.commit(client).await?;
+        Ok(vm)
+    }
+
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn get_node_id(&self) -> Uuid {
+        self.node_id
+    }
+
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_disks(&self) -> Vec<Uuid> {
+        self.disks.clone()
+    }
+
+    pub fn get_interfaces(&self) -> Vec<Uuid> {
+        self.interfaces.clone()
+    }
+
+    pub async fn to_spec(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<DomainSpec, Error> {
+        let mut builder = DomainBuilder::new(self.id, &self.name)
+            .memory(self.memory)
+            .vcpus(self.cpus)
+            .console(Console::Pty);
+
+        let mut disks = Vec::new();
+        let mut cdroms = Vec::new();
+        let mut installer = None;
+        for id in &self.disks {
+            if let Some(image) = Image::get(*id, client).await? {
+                let path = match image.get_path(self.node_id) {
+                    Some(path) => path,
+                    None => return Err(Error::ImageNotFound),
+                };
+
+                if image.is_installer() && installer.is_none() {
+                    installer = Some(cdroms.len());
+                }
+
+                cdroms.push(DomainDisk::cdrom(&path.to_string_lossy()));
+                continue;
+            }
+
+            let disk = match Disk::get(*id, client).await? {
+                Some(disk) => disk,
+                None => return Err(Error::DiskNotFound),
+            };
+
+            // libvirt requires absolute paths
+            let path = disk.get_path(client).await?;
+            let path = fs::canonicalize(&path).unwrap_or(path);
+            disks.push(DomainDisk::new(
+                &path.to_string_lossy(),
+                disk.get_format().as_str(),
+            ));
+        }
+
+        // Boot the installer first, then the disk it installs onto
+        if let Some(index) = installer {
+            cdroms[index] = cdroms[index].clone().boot_order(1);
+            if let Some(disk) = disks.first_mut() {
+                *disk = disk.clone().boot_order(2);
+            }
+        }
+
+        for disk in disks.into_iter().chain(cdroms) {
+            builder = builder.disk(disk);
+        }
+
+        // Each interface is a port on the bridge of its network
+        for id in &self.interfaces {
+            let network = match Network::get(*id, client).await? {
+                Some(network) => network,
+                None => return Err(Error::NetworkNotFound),
+            };
+
+            let mut interface =
+                DomainInterface::new(InterfaceSource::Bridge(network.get_bridge_name()));
+            if network.get_vlan() != 0 {
+                interface = interface.openvswitch().vlan(network.get_vlan());
+            }
+            builder = builder.interface(interface);
+        }
+
+        builder.build()
+    }
+
+    async fn commit(&self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .insert(format!("vms/{}", self.id).as_str(), self.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(self, client: &Arc<Mutex<SkiffClient>>) -> Result<(), Error> {
+        client
+            .lock()
+            .await
+            .remove(format!("vms/{}", self.id).as_str())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(id: Uuid, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<VM>, Error> {
+        let vm = client
+            .lock()
+            .await
+            .get::<VM>(format!("vms/{}", id).as_str())
+            .await?;
+
+        Ok(vm)
+    }
+
+    pub async fn find(name: &str, client: &Arc<Mutex<SkiffClient>>) -> Result<Option<VM>, Error> {
+        // Todo: index vms by name so we don't need to load each vm
+        Ok(VM::list(client)
+            .await?
+            .into_iter()
+            .find(|vm| vm.name == name))
+    }
+
+    pub async fn list(client: &Arc<Mutex<SkiffClient>>) -> Result<Vec<VM>, Error> {
+        let vm_ids = client.lock().await.list_keys("vms/").await?;
+
+        let mut vms = Vec::new();
+        for vm in vm_ids {
+            vms.push(
+                client
+                    .lock()
+                    .await
+                    .get::<VM>(vm.as_str())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        Ok(vms)
+    }
+}
+
+impl From<VM> for virtus_proto::Vm {
+    fn from(val: VM) -> Self {
+        virtus_proto::Vm {
+            id: val.id.to_string(),
+            node: val.node_id.to_string(),
+            name: val.name,
+            cpus: val.cpus,
+            memory: val.memory,
+            disks: val.disks.into_iter().map(|id| id.to_string()).collect(),
+            interfaces: val
+                .interfaces
+                .into_iter()
+                .map(|id| id.to_string())
+                .collect(),
+            // Only the owning node knows the domain state
+            state: virtus_proto::VmState::Undefined.into(),
+        }
+    }
+}